@@ -0,0 +1,691 @@
+use crate::config::{Config, Settings};
+use crate::fetcher::assets::AssetIndex;
+use crate::fetcher::client::ClientOptions;
+use crate::fetcher::cookies::{DomainHeaders, SessionJar};
+use crate::fetcher::downloader::{download_and_save_post, DownloadOptions, MediaOptions, PostOutcome};
+use crate::fetcher::events::{Events, ProgressEvent};
+use crate::fetcher::http::HttpSession;
+use crate::fetcher::links::{link_posts, link_targets};
+use crate::fetcher::processor::{Processor, ProcessorRegistry};
+use crate::fetcher::render::{renderer_by_name, Renderer, RendererSelection};
+use crate::fetcher::retry::{FetchError, RetryPolicy};
+use crate::fetcher::robots::{robots_agent, RobotsCache};
+use crate::fetcher::store::AssetStore;
+use crate::fetcher::scheduler::{interleave_by_host, HostLimits, HostScheduler};
+use crate::manifest::{FetchStatus, ForcePolicy, Manifest, STATE_DIR};
+use crate::model::Post;
+use crate::parser::csv_parser::parse_posts;
+use crate::parser::index_builder::{build_index_tree, write_index_html};
+use crate::report::{PostReport, ReportStatus, RunReport};
+use crate::shutdown::Shutdown;
+use crate::utils::check_and_install_tools;
+use anyhow::{Context, Result};
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Number of finished posts between manifest flushes
+const MANIFEST_FLUSH_INTERVAL: usize = 20;
+/// Session cookies kept between runs
+const COOKIE_JAR_FILE: &str = "cookies.json";
+/// Netscape copy of the jar for yt-dlp
+const COOKIES_TXT_FILE: &str = "cookies.txt";
+
+/// Builder for an [`Archive`]
+///
+/// Settings are layered like on the command line: the config defaults, then the selected
+/// profile, then everything set on the builder.
+#[derive(Debug, Default)]
+pub struct ArchiveBuilder {
+    config: Config,
+    profile: Option<String>,
+    settings: Settings,
+    posts: Vec<Post>,
+    events: Option<Events>,
+    shutdown: Option<Shutdown>,
+    processors: ProcessorRegistry,
+    renderers: Vec<Arc<dyn Renderer>>,
+}
+
+impl ArchiveBuilder {
+    /// Read posts from the CSV files under this directory
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.settings.data_dir = Some(dir.into());
+        self
+    }
+
+    /// Fetch these posts; the data directory is then only read if set explicitly
+    pub fn posts(mut self, posts: impl IntoIterator<Item = Post>) -> Self {
+        self.posts.extend(posts);
+        self
+    }
+
+    pub fn outputs_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.settings.outputs_dir = Some(dir.into());
+        self
+    }
+
+    /// Posts downloaded at the same time
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.settings.concurrency = Some(concurrency);
+        self
+    }
+
+    /// Config file contents: defaults, profiles and per-category/per-domain overrides
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Config profile applied on top of the config defaults
+    ///
+    /// ```
+    /// use hyfetcher::config::Config;
+    /// use hyfetcher::Archive;
+    ///
+    /// let config = Config::parse("profiles:\n  slow:\n    concurrency: 1\n").unwrap();
+    /// let archive = Archive::builder().config(config.clone()).profile("slow").build().unwrap();
+    /// assert_eq!(archive.settings().concurrency(), 1);
+    ///
+    /// let error = Archive::builder().config(config).profile("fast").build().unwrap_err();
+    /// assert!(error.to_string().contains("unknown profile `fast`"));
+    /// ```
+    pub fn profile(mut self, profile: impl Into<String>) -> Self {
+        self.profile = Some(profile.into());
+        self
+    }
+
+    /// Any other settings (client, retries, renderers, ...); fields left `None` keep their value
+    pub fn settings(mut self, settings: Settings) -> Self {
+        self.settings.merge(settings);
+        self
+    }
+
+    /// Processing steps run on each page, by name
+    pub fn pipeline<S: Into<String>>(mut self, steps: impl IntoIterator<Item = S>) -> Self {
+        self.settings.pipeline = Some(steps.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Make a custom processor available to pipelines under its name
+    ///
+    /// ```
+    /// use futures::future::BoxFuture;
+    /// use hyfetcher::fetcher::processor::{PageContext, Processor};
+    /// use hyfetcher::Archive;
+    /// use scraper::Html;
+    ///
+    /// #[derive(Debug)]
+    /// struct WordCount;
+    ///
+    /// impl Processor for WordCount {
+    ///     fn name(&self) -> &'static str {
+    ///         "word-count"
+    ///     }
+    ///
+    ///     fn process<'a>(&'a self, document: &'a mut Html, page: &'a mut PageContext) -> BoxFuture<'a, anyhow::Result<()>> {
+    ///         let words = document.root_element().text().flat_map(str::split_whitespace).count();
+    ///         page.metadata.insert("words".to_string(), words.to_string());
+    ///         Box::pin(async { Ok(()) })
+    ///     }
+    /// }
+    ///
+    /// let archive = Archive::builder()
+    ///     .processor(WordCount)
+    ///     .pipeline(["cleanup", "images", "word-count"])
+    ///     .build()
+    ///     .unwrap();
+    /// # drop(archive);
+    ///
+    /// let error = Archive::builder().pipeline(["images", "thumbnails"]).build().unwrap_err();
+    /// assert!(format!("{:#}", error).contains("unknown processor `thumbnails`"));
+    /// ```
+    pub fn processor(mut self, processor: impl Processor + 'static) -> Self {
+        self.processors.register(Arc::new(processor));
+        self
+    }
+
+    /// Make a custom renderer available under its name, for `renderer`, `render-domain` and
+    /// `render-category`; see [`Renderer`] for an example
+    pub fn renderer(mut self, renderer: impl Renderer + 'static) -> Self {
+        self.renderers.push(Arc::new(renderer));
+        self
+    }
+
+    /// Report progress on this channel instead of a new one
+    pub fn events(mut self, events: Events) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Stop the run when this is triggered
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Resolve the settings and set up the HTTP session and renderers
+    pub fn build(self) -> Result<Archive> {
+        let ArchiveBuilder { config, profile, settings, posts, events, shutdown, processors, renderers } = self;
+        let explicit_data_dir = settings.data_dir.is_some();
+        let settings = config.resolve(profile.as_deref(), settings)?;
+        let events = events.unwrap_or_default();
+        let state_dir = settings.outputs_dir().join(STATE_DIR);
+        let session_dir = settings.session_dir();
+
+        // Cookies persist between runs; configured ones are added on top. Jars saved by older
+        // versions inside the outputs directory are picked up once and moved on the next save.
+        let jar_path = session_dir.join(COOKIE_JAR_FILE);
+        let legacy_jar_path = state_dir.join(COOKIE_JAR_FILE);
+        let jar = if !jar_path.exists() && legacy_jar_path.exists() {
+            SessionJar::load(&legacy_jar_path)?
+        } else {
+            SessionJar::load(&jar_path)?
+        };
+        let jar = Arc::new(jar);
+        let mut domain_headers = DomainHeaders::default();
+        for (domain, overrides) in &config.domains {
+            for (name, value) in &overrides.cookies {
+                jar.add_domain_cookie(domain, name, value)?;
+            }
+            domain_headers.insert(domain, &overrides.headers)?;
+        }
+
+        let mut proxies: Vec<(String, String)> = config
+            .domains
+            .iter()
+            .filter_map(|(domain, overrides)| Some((domain.clone(), overrides.proxy.clone()?)))
+            .collect();
+        proxies.extend(settings.proxy_domain.iter().flatten().cloned());
+        let mut insecure_hosts: Vec<String> = config
+            .domains
+            .iter()
+            .filter(|(_, overrides)| overrides.insecure == Some(true))
+            .map(|(domain, _)| domain.clone())
+            .collect();
+        insecure_hosts.extend(settings.insecure_host.iter().flatten().cloned());
+        let client = ClientOptions {
+            user_agent: settings.user_agent().to_string(),
+            proxy: settings.proxy.clone(),
+            domain_proxies: proxies,
+            connect_timeout: settings.connect_timeout(),
+            read_timeout: settings.read_timeout(),
+            timeout: settings.timeout(),
+            ca_certs: settings.ca_cert.clone().unwrap_or_default(),
+            insecure_hosts,
+            cookies: Some(jar.clone()),
+        }
+        .build()?;
+
+        let default_limits = HostLimits {
+            max_in_flight: settings.per_host_concurrency(),
+            requests_per_second: settings.per_host_rps,
+        };
+        let mut host_limits: HashMap<String, HostLimits> = config
+            .domains
+            .iter()
+            .filter_map(|(domain, overrides)| Some((domain.to_lowercase(), overrides.host_limits(default_limits)?)))
+            .collect();
+        host_limits.extend(settings.host_limit.iter().flatten().cloned());
+        let scheduler = Arc::new(HostScheduler::new(default_limits, host_limits));
+        let mut http = HttpSession::new(
+            client,
+            RetryPolicy {
+                max_retries: settings.retries(),
+                base_delay: Duration::from_millis(settings.retry_delay_ms()),
+                ..RetryPolicy::default()
+            },
+            scheduler.clone(),
+        )
+        .with_events(events.clone())
+        .with_domain_headers(domain_headers)
+        .with_cookies_file(session_dir.join(COOKIES_TXT_FILE))
+        .with_cookie_jar(jar.clone());
+        if settings.respect_robots.unwrap_or(false) {
+            let robots_dir = state_dir.join("robots");
+            http = http.with_robots(Arc::new(RobotsCache::new(&robots_agent(settings.user_agent()), Some(robots_dir))));
+        }
+        if let Some(limit) = settings.max_body_size {
+            http = http.with_max_body_size(limit);
+        }
+
+        // One instance per renderer name, so all chromium pages share a browser
+        let wait = settings.wait();
+        let mut by_name: HashMap<String, Arc<dyn Renderer>> =
+            renderers.into_iter().map(|renderer| (renderer.name().to_string(), renderer)).collect();
+        let mut renderer = |name: &str| -> Result<Arc<dyn Renderer>> {
+            if !by_name.contains_key(name) {
+                by_name.insert(name.to_string(), renderer_by_name(name, &wait)?);
+            }
+            Ok(by_name[name].clone())
+        };
+        let mut renderers = RendererSelection::new(renderer(settings.renderer())?);
+        for (category, overrides) in &config.categories {
+            if let Some(name) = &overrides.renderer {
+                let selected = renderer(name).with_context(|| format!("Invalid config key categories.{}.renderer", category))?;
+                renderers = renderers.with_category(category, selected);
+            }
+        }
+        for (domain, overrides) in &config.domains {
+            if let Some(name) = &overrides.renderer {
+                let selected = renderer(name).with_context(|| format!("Invalid config key domains.{}.renderer", domain))?;
+                renderers = renderers.with_domain(domain, selected);
+            }
+        }
+        for (domain, name) in settings.render_domain.iter().flatten() {
+            renderers = renderers.with_domain(domain, renderer(name)?);
+        }
+        for (category, name) in settings.render_category.iter().flatten() {
+            renderers = renderers.with_category(category, renderer(name)?);
+        }
+        // Check every pipeline now rather than on the first post using it
+        let pipeline = processors.pipeline(&settings.pipeline()).context("Invalid pipeline")?;
+        for (category, overrides) in &config.categories {
+            if let Some(steps) = &overrides.pipeline {
+                processors
+                    .pipeline(steps)
+                    .with_context(|| format!("Invalid config key categories.{}.pipeline", category))?;
+            }
+        }
+        for (domain, overrides) in &config.domains {
+            if let Some(steps) = &overrides.pipeline {
+                processors
+                    .pipeline(steps)
+                    .with_context(|| format!("Invalid config key domains.{}.pipeline", domain))?;
+            }
+        }
+        let asset_index = Arc::new(AssetIndex::load(&settings.outputs_dir())?);
+        let store = if settings.asset_store.unwrap_or(false) {
+            Some(Arc::new(AssetStore::new(&settings.outputs_dir(), asset_index.clone())))
+        } else {
+            None
+        };
+        let options = DownloadOptions {
+            detect_soft_404: settings.detect_soft_404.unwrap_or(false),
+            fallback_encoding: settings.fallback_charset(),
+            renderers,
+            media: MediaOptions {
+                images_dir: settings.images_dir().to_string(),
+                videos_dir: settings.videos_dir().to_string(),
+                styles_dir: settings.styles_dir().to_string(),
+                scripts_dir: settings.scripts_dir().to_string(),
+                yt_dlp_format: settings.yt_dlp_format().to_string(),
+                asset_concurrency: settings.asset_concurrency(),
+            },
+            scripts: settings.scripts.unwrap_or_default(),
+            pipeline,
+            single_file: settings.single_file.unwrap_or(false),
+            inline_max_size: settings.inline_max_size(),
+            store,
+            asset_index,
+            processors: Arc::new(processors),
+            overrides: Arc::new(config.post_overrides()),
+        };
+
+        Ok(Archive {
+            read_data_dir: posts.is_empty() || explicit_data_dir,
+            settings,
+            posts,
+            jar,
+            http,
+            options,
+            events,
+            shutdown: shutdown.unwrap_or_default(),
+        })
+    }
+}
+
+/// An offline archive of posts: fetches them with their assets into the outputs directory,
+/// keeps the manifest and cookie jar up to date and writes the index and run report.
+///
+/// Posts already completed in an earlier run are skipped, so `run` can simply be repeated.
+///
+/// ```
+/// use hyfetcher::config::Settings;
+/// use hyfetcher::fetcher::events::ProgressEvent;
+/// use hyfetcher::model::Post;
+/// use hyfetcher::Archive;
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> anyhow::Result<()> {
+/// # let outputs = tempfile::tempdir()?;
+/// // Nothing listens on port 9, so the post fails without network access
+/// let post = Post::new(
+///     "http://127.0.0.1:9/hello.html".to_string(),
+///     "Hello".to_string(),
+///     "notes".to_string(),
+///     String::new(),
+///     "links".to_string(),
+/// );
+/// let archive = Archive::builder()
+///     .posts([post])
+///     .outputs_dir(outputs.path())
+///     .concurrency(4)
+///     .settings(Settings { retries: Some(0), ..Settings::default() })
+///     .build()?;
+///
+/// let mut events = archive.events().subscribe();
+/// let report = archive.run().await?;
+/// assert_eq!(report.totals.failed, 1);
+/// assert!(report.posts[0].errors[0].contains("127.0.0.1:9"));
+/// assert!(outputs.path().join("index.html").exists());
+/// assert!(matches!(events.recv().await?, ProgressEvent::RunStarted { total: 1 }));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Archive {
+    settings: Settings,
+    posts: Vec<Post>,
+    read_data_dir: bool,
+    jar: Arc<SessionJar>,
+    http: HttpSession,
+    options: DownloadOptions,
+    events: Events,
+    shutdown: Shutdown,
+}
+
+impl Archive {
+    pub fn builder() -> ArchiveBuilder {
+        ArchiveBuilder::default()
+    }
+
+    /// Settings after applying the config, profile and builder values
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Progress events of every run; subscribe before calling `run`
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    /// Per-host limits, with a snapshot of the requests in flight
+    pub fn scheduler(&self) -> &Arc<HostScheduler> {
+        self.http.scheduler()
+    }
+
+    /// Trigger it to stop starting posts; downloads in progress get the grace period to finish
+    ///
+    /// ```
+    /// use hyfetcher::model::Post;
+    /// use hyfetcher::report::ReportStatus;
+    /// use hyfetcher::Archive;
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() -> anyhow::Result<()> {
+    /// # let outputs = tempfile::tempdir()?;
+    /// let post = Post::new(
+    ///     "http://127.0.0.1:9/".to_string(),
+    ///     "Never fetched".to_string(),
+    ///     "notes".to_string(),
+    ///     String::new(),
+    ///     String::new(),
+    /// );
+    /// let archive = Archive::builder().posts([post]).outputs_dir(outputs.path()).build()?;
+    /// archive.shutdown().trigger();
+    /// let report = archive.run().await?;
+    /// assert!(report.interrupted);
+    /// assert_eq!(report.posts[0].status, ReportStatus::NotAttempted);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    /// Make sure yt-dlp is available, installing it if needed
+    pub async fn check_tools(&self) -> Result<()> {
+        check_and_install_tools(&self.http).await
+    }
+
+    /// Fetch every post that is not up to date, then save the state, report and index
+    pub async fn run(&self) -> Result<RunReport> {
+        let result = self.fetch_all().await;
+        self.events.emit(ProgressEvent::RunFinished);
+        result
+    }
+
+    fn collect_posts(&self) -> Vec<Post> {
+        let mut posts = Vec::new();
+        if self.read_data_dir {
+            let data_dir = self.settings.data_dir();
+            self.events.info(format!("Parsing posts from {} ...", data_dir.display()));
+            posts = parse_posts(&data_dir);
+            self.events.info(format!("Found {} posts.", posts.len()));
+        }
+        posts.extend(self.posts.iter().cloned());
+        posts
+    }
+
+    async fn fetch_all(&self) -> Result<RunReport> {
+        let settings = &self.settings;
+        let outputs_dir = settings.outputs_dir();
+        let posts = self.collect_posts();
+
+        // Skip posts already completed in a previous run
+        let mut manifest = Manifest::load(&outputs_dir)?;
+        let targets_before = link_targets(&outputs_dir, &manifest);
+        let force = ForcePolicy {
+            all: settings.force.unwrap_or(false),
+            urls: settings.force_post.clone().unwrap_or_default(),
+            categories: settings.force_category.clone().unwrap_or_default(),
+        };
+        let retry_failed = settings.retry_failed.unwrap_or(false);
+        let mut report = RunReport::new();
+        let mut pending: Vec<Post> = Vec::new();
+        for post in &posts {
+            let failed_before = manifest.get(post).is_some_and(|e| e.status == FetchStatus::Failed);
+            if manifest.needs_fetch(post, &outputs_dir, &force) && (!retry_failed || failed_before) {
+                pending.push(post.clone());
+            } else {
+                report.push(PostReport::not_fetched(post, manifest.get(post)));
+            }
+        }
+        if pending.len() < posts.len() {
+            if retry_failed {
+                self.events.info(format!("Retrying {} posts that failed before.", pending.len()));
+            } else {
+                self.events.info(format!("Skipping {} posts already completed.", posts.len() - pending.len()));
+            }
+        }
+
+        // Cookies exported from a browser are imported on every run, on top of the saved jar
+        let state_dir = outputs_dir.join(STATE_DIR);
+        let session_dir = settings.session_dir();
+        for path in settings.cookies.iter().flatten() {
+            let count = self.jar.import_netscape(path)?;
+            self.events.info(format!("Imported {} cookies from {}", count, path.display()));
+        }
+        if !self.jar.is_empty() {
+            self.jar.export_netscape(&session_dir.join(COOKIES_TXT_FILE)).await?;
+        }
+
+        self.events.emit(ProgressEvent::RunStarted { total: pending.len() });
+        let grace_period = settings.grace_period();
+        let pending = interleave_by_host(pending);
+        let mut recorded = vec![false; pending.len()];
+        let mut queue = pending.iter().cloned().enumerate();
+        let mut futures = FuturesUnordered::new();
+        let mut grace_deadline: Option<tokio::time::Instant> = None;
+        let mut finished = 0;
+        loop {
+            while futures.len() < settings.concurrency()
+                && !self.shutdown.is_triggered()
+                && let Some((index, post)) = queue.next()
+            {
+                let http = self.http.clone();
+                let outputs_dir = outputs_dir.clone();
+                let options = self.options.clone();
+                futures.push(async move {
+                    let started = Instant::now();
+                    let result = download_and_save_post(&post, &outputs_dir, &http, &options).await;
+                    (index, result, started.elapsed())
+                });
+            }
+            let grace_over = async {
+                match grace_deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                next = futures.next() => {
+                    let Some((index, result, elapsed)) = next else { break };
+                    record_result(&mut manifest, &mut report, &pending[index], result, elapsed, &outputs_dir);
+                    recorded[index] = true;
+                    finished += 1;
+                    if finished % MANIFEST_FLUSH_INTERVAL == 0 {
+                        manifest.save()?;
+                        self.options.asset_index.save()?;
+                    }
+                }
+                _ = self.shutdown.triggered(), if grace_deadline.is_none() => {
+                    grace_deadline = Some(tokio::time::Instant::now() + grace_period);
+                }
+                _ = grace_over => break,
+            }
+        }
+        // Dropping unfinished downloads removes their partial files
+        drop(futures);
+        for (post, _) in pending.iter().zip(&recorded).filter(|(_, recorded)| !**recorded) {
+            report.push(PostReport::not_fetched(post, manifest.get(post)));
+        }
+        manifest.save()?;
+        self.options.asset_index.save()?;
+        if !self.jar.is_empty() || session_dir.join(COOKIE_JAR_FILE).exists() {
+            self.jar.save(&session_dir.join(COOKIE_JAR_FILE)).await?;
+        }
+        // Login cookies do not belong in the archive people share
+        for name in [COOKIE_JAR_FILE, COOKIES_TXT_FILE] {
+            let legacy = state_dir.join(name);
+            if legacy.exists() {
+                fs::remove_file(&legacy)?;
+            }
+        }
+
+        // The run's state is saved first: linking only rewrites pages already on disk
+        if !self.shutdown.is_triggered() {
+            let mark_external = settings.mark_external_links.unwrap_or(false);
+            let written: HashSet<String> = report
+                .posts
+                .iter()
+                .filter(|post| post.status == ReportStatus::Completed)
+                .filter_map(|post| post.save_path.clone())
+                .collect();
+            let linked =
+                link_posts(&outputs_dir, &manifest, &targets_before, &written, mark_external, &self.events).await;
+            if !linked.is_empty() {
+                for save_path in &linked {
+                    manifest.rehash(&outputs_dir, save_path);
+                }
+                manifest.save()?;
+                self.events.info(format!("Updated links in {} pages.", linked.len()));
+            }
+        }
+
+        report.interrupted = self.shutdown.is_triggered();
+        report.finish();
+        report.save(&outputs_dir)?;
+
+        // Generate index.html
+        let tree = build_index_tree(&posts);
+        write_index_html(&tree, &outputs_dir, &manifest)?;
+        Ok(report)
+    }
+}
+
+/// Update the manifest and the run report with the result of one post
+fn record_result(
+    manifest: &mut Manifest,
+    report: &mut RunReport,
+    post: &Post,
+    result: Result<PostOutcome>,
+    elapsed: Duration,
+    outputs_dir: &Path,
+) {
+    let status = match &result {
+        Ok(outcome) => {
+            manifest.record_success(post, outputs_dir, outcome);
+            FetchStatus::Completed
+        }
+        Err(e) => match e.downcast_ref::<FetchError>() {
+            Some(reason @ FetchError::RobotsDisallowed { .. }) => {
+                manifest.record_skipped(post, &reason.to_string());
+                FetchStatus::Skipped
+            }
+            _ => {
+                manifest.record_failure(post, e);
+                FetchStatus::Failed
+            }
+        },
+    };
+    report.push(PostReport::new(post, status, &result, elapsed));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{post, Route, TestServer};
+
+    #[tokio::test]
+    async fn run_saves_pages_manifest_and_report() {
+        let page = format!(
+            r#"<html><head><title>Hello</title></head><body><p>{}</p><img src="/cat.png"></body></html>"#,
+            "Archived text. ".repeat(20)
+        );
+        let server = TestServer::start(vec![
+            Route::ok("/hello.html", "text/html; charset=utf-8", page),
+            Route::ok("/cat.png", "image/png", b"\x89PNG\r\n\x1a\nfake".to_vec()),
+            Route::ok("/paper", "application/pdf", b"%PDF-1.4 fake".to_vec()),
+        ])
+        .await;
+        let outputs = tempfile::tempdir().unwrap();
+        let build = || {
+            Archive::builder()
+                .posts([
+                    post(&server.url("/hello.html"), "Hello"),
+                    post(&server.url("/paper"), "Paper"),
+                    post(&server.url("/missing.html"), "Missing"),
+                ])
+                .outputs_dir(outputs.path())
+                .settings(Settings { retries: Some(0), ..Settings::default() })
+                .build()
+                .unwrap()
+        };
+
+        let report = build().run().await.unwrap();
+        assert_eq!((report.totals.completed, report.totals.failed), (2, 1));
+
+        // The page is saved with its image next to it, the document as-is
+        let saved = fs::read_to_string(outputs.path().join("notes/Hello.html")).unwrap();
+        assert!(saved.contains("Archived text."));
+        let image = saved.split(r#"<img src=""#).nth(1).and_then(|rest| rest.split('"').next()).unwrap();
+        assert!(image.starts_with("images/"));
+        assert!(outputs.path().join("notes").join(image).is_file());
+        assert_eq!(fs::read(outputs.path().join("notes/Paper.pdf")).unwrap(), b"%PDF-1.4 fake");
+        assert!(outputs.path().join("index.html").is_file());
+
+        let manifest = Manifest::load(outputs.path()).unwrap();
+        let status = |save_path: &str| manifest.entries().find(|entry| entry.save_path == save_path).map(|entry| entry.status);
+        assert_eq!(status("notes/Hello.html"), Some(FetchStatus::Completed));
+        assert_eq!(status("notes/Paper.pdf"), Some(FetchStatus::Completed));
+        assert_eq!(status("notes/Missing.html"), Some(FetchStatus::Failed));
+
+        let saved_report: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(outputs.path().join(STATE_DIR).join("report.json")).unwrap())
+                .unwrap();
+        assert_eq!(saved_report["totals"]["completed"], 2);
+        assert_eq!(saved_report["totals"]["failed"], 1);
+
+        // A second run only retries the failure
+        let report = build().run().await.unwrap();
+        assert_eq!((report.totals.up_to_date, report.totals.failed), (2, 1));
+        let missing = report.posts.iter().find(|post| post.title == "Missing").unwrap();
+        assert_eq!(missing.http_status, Some(404));
+        assert_eq!(missing.status, ReportStatus::Failed);
+    }
+}
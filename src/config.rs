@@ -0,0 +1,734 @@
+use crate::fetcher::processor::DEFAULT_PIPELINE;
+use crate::fetcher::render::WaitCondition;
+use crate::fetcher::scheduler::{host_of, parse_host_limit, HostLimits};
+use crate::fetcher::scripts::ScriptPolicy;
+use crate::manifest::STATE_DIR;
+use crate::model::Post;
+use anyhow::{Context, Result};
+use encoding_rs::{Encoding, GB18030};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Config file looked up in the data directory when `--config` is not given
+pub const CONFIG_FILE_NAME: &str = "hyfetcher.yaml";
+
+pub const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (compatible; hyfetcher/0.1; +https://github.com/hyperplasma/hyfetcher)";
+pub const DEFAULT_YT_DLP_FORMAT: &str =
+    "bv*[height=720][ext=mp4]+ba[ext=m4a]/bv*[height=720]+ba/best[height=720]/best";
+pub const DEFAULT_ASSET_CONCURRENCY: usize = 4;
+/// Largest file embedded in single-file pages
+pub const DEFAULT_INLINE_MAX_SIZE: u64 = 1 << 20;
+
+/// Run settings, set on the command line or in the `defaults`/`profiles` sections of the config.
+///
+/// Every field is optional so layers can be merged: built-in defaults, then the config's
+/// `defaults`, then the selected profile, then command-line flags.
+#[derive(clap::Args, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    /// Data input directory [default: data]
+    #[arg(short = 'd', long)]
+    pub data_dir: Option<PathBuf>,
+    /// Output directory [default: outputs]
+    #[arg(short = 'o', long)]
+    pub outputs_dir: Option<PathBuf>,
+    /// Directory for the session cookie jar, kept out of the outputs you share [default: <data-dir>/.hyfetcher]
+    #[arg(long)]
+    pub session_dir: Option<PathBuf>,
+    /// Number of concurrent tasks [default: 8]
+    #[arg(short = 'c', long)]
+    pub concurrency: Option<usize>,
+    /// Images and videos of one page downloaded at the same time [default: 4]
+    #[arg(long, value_name = "N")]
+    pub asset_concurrency: Option<usize>,
+    /// Skip tool check
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub skip_tool_check: Option<bool>,
+    /// Re-download all posts, ignoring the manifest
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub force: Option<bool>,
+    /// Re-download the post with this URL (repeatable)
+    #[arg(long, value_name = "URL")]
+    pub force_post: Option<Vec<String>>,
+    /// Re-download all posts of this category (repeatable)
+    #[arg(long, value_name = "CATEGORY")]
+    pub force_category: Option<Vec<String>>,
+    /// Only fetch posts that failed in an earlier run
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub retry_failed: Option<bool>,
+    /// Number of retries for transient network errors [default: 3]
+    #[arg(long)]
+    pub retries: Option<u32>,
+    /// Initial retry backoff in milliseconds, doubled on each attempt [default: 500]
+    #[arg(long)]
+    pub retry_delay_ms: Option<u64>,
+    /// Maximum concurrent requests per host (pages and assets) [default: 2]
+    #[arg(long)]
+    pub per_host_concurrency: Option<usize>,
+    /// Maximum requests per second per host
+    #[arg(long)]
+    pub per_host_rps: Option<f64>,
+    /// Per-domain override as DOMAIN=CONCURRENCY[/RPS] (repeatable)
+    #[arg(long, value_name = "DOMAIN=N[/RPS]", value_parser = parse_host_limit)]
+    #[serde(deserialize_with = "de_host_limits")]
+    pub host_limit: Option<Vec<(String, HostLimits)>>,
+    /// Honor robots.txt (including Crawl-delay) for pages and assets
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub respect_robots: Option<bool>,
+    /// Treat tiny bodies and known challenge/404 pages served with 2xx as failures
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub detect_soft_404: Option<bool>,
+    /// Charset assumed for pages that declare none and are not valid UTF-8 [default: gb18030]
+    #[arg(long, value_parser = parse_encoding)]
+    #[serde(deserialize_with = "de_encoding")]
+    pub fallback_charset: Option<&'static Encoding>,
+    /// Default page renderer: http, or chromium (needs the js_render feature) [default: http]
+    #[arg(long)]
+    pub renderer: Option<String>,
+    /// Use a renderer for a domain and its subdomains, as DOMAIN=RENDERER (repeatable)
+    #[arg(long, value_name = "DOMAIN=RENDERER", value_parser = parse_key_value)]
+    #[serde(deserialize_with = "de_key_values")]
+    pub render_domain: Option<Vec<(String, String)>>,
+    /// Use a renderer for a category, as CATEGORY=RENDERER (repeatable)
+    #[arg(long, value_name = "CATEGORY=RENDERER", value_parser = parse_key_value)]
+    #[serde(deserialize_with = "de_key_values")]
+    pub render_category: Option<Vec<(String, String)>>,
+    /// What the chromium renderer waits for: load, network-idle, selector:<CSS> or delay:<MS> [default: load]
+    #[arg(long)]
+    #[serde(deserialize_with = "de_from_str")]
+    pub wait: Option<WaitCondition>,
+    /// Import cookies from a Netscape cookies.txt file exported from a browser (repeatable)
+    #[arg(long, value_name = "FILE")]
+    pub cookies: Option<Vec<PathBuf>>,
+    /// User-Agent header sent with every request
+    #[arg(long)]
+    pub user_agent: Option<String>,
+    /// Directory next to each page where its images are stored [default: images]
+    #[arg(long, value_name = "DIR")]
+    pub images_dir: Option<String>,
+    /// Directory next to each page where its videos are stored [default: videos]
+    #[arg(long, value_name = "DIR")]
+    pub videos_dir: Option<String>,
+    /// Directory next to each page where its stylesheets, fonts and CSS images are stored [default: styles]
+    #[arg(long, value_name = "DIR")]
+    pub styles_dir: Option<String>,
+    /// Directory next to each page where its scripts are stored with `--scripts localize` [default: scripts]
+    #[arg(long, value_name = "DIR")]
+    pub scripts_dir: Option<String>,
+    /// What to do with the JavaScript of saved pages: strip, neutralize or localize [default: strip]
+    #[arg(long, value_name = "POLICY")]
+    #[serde(deserialize_with = "de_from_str")]
+    pub scripts: Option<ScriptPolicy>,
+    /// yt-dlp format selection for sites downloaded with yt-dlp
+    #[arg(long, value_name = "FORMAT")]
+    pub yt_dlp_format: Option<String>,
+    /// Store images and videos once under <outputs>/assets, shared by all pages, instead of next to each page
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub asset_store: Option<bool>,
+    /// Save each page as one self-contained HTML file, with its assets embedded as data: URIs
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub single_file: Option<bool>,
+    /// Largest asset embedded in single-file pages; bigger ones stay in files next to the page [default: 1M]
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    #[serde(deserialize_with = "de_size")]
+    pub inline_max_size: Option<u64>,
+    /// Mark links to pages outside the archive with an arrow; links between archived posts always point to the local copies
+    #[arg(long, num_args = 0..=1, default_missing_value = "true", value_name = "BOOL")]
+    pub mark_external_links: Option<bool>,
+    /// Processing steps run on each page, in order, e.g. scripts,cleanup,styles,images,videos,metadata; the script policy runs last when `scripts` is left out [default: scripts,styles,images,videos]
+    #[arg(long, value_name = "STEPS", value_delimiter = ',')]
+    pub pipeline: Option<Vec<String>>,
+    /// Proxy for all requests: http://, https://, socks5:// or socks5h:// URL
+    #[arg(long, value_name = "URL")]
+    pub proxy: Option<String>,
+    /// Proxy for a domain and its subdomains as DOMAIN=URL, or DOMAIN=direct to bypass --proxy (repeatable)
+    #[arg(long, value_name = "DOMAIN=URL", value_parser = parse_key_value)]
+    #[serde(deserialize_with = "de_key_values")]
+    pub proxy_domain: Option<Vec<(String, String)>>,
+    /// Seconds to wait for a connection, 0 for no limit [default: 30]
+    #[arg(long, value_name = "SECS")]
+    pub connect_timeout: Option<u64>,
+    /// Seconds to wait for the next piece of a response, 0 for no limit [default: 60]
+    #[arg(long, value_name = "SECS")]
+    pub read_timeout: Option<u64>,
+    /// Seconds allowed for a whole request including its body, 0 for no limit [default: 0]
+    #[arg(long, value_name = "SECS")]
+    pub timeout: Option<u64>,
+    /// Trust the root certificates in this PEM file (repeatable)
+    #[arg(long, value_name = "FILE")]
+    pub ca_cert: Option<Vec<PathBuf>>,
+    /// Do not verify TLS certificates of this host and its subdomains (repeatable)
+    #[arg(long, value_name = "HOST")]
+    pub insecure_host: Option<Vec<String>>,
+    /// Reject responses larger than this, e.g. 500K, 20M or 1G
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    #[serde(deserialize_with = "de_size")]
+    pub max_body_size: Option<u64>,
+    /// Seconds in-flight downloads get to finish after Ctrl-C before they are aborted [default: 10]
+    #[arg(long, value_name = "SECS")]
+    pub grace_period: Option<u64>,
+}
+
+impl Settings {
+    /// Override the fields set in `other`; lists are replaced, not appended
+    pub fn merge(&mut self, other: Settings) {
+        let Settings {
+            data_dir,
+            outputs_dir,
+            session_dir,
+            concurrency,
+            asset_concurrency,
+            skip_tool_check,
+            force,
+            force_post,
+            force_category,
+            retry_failed,
+            retries,
+            retry_delay_ms,
+            per_host_concurrency,
+            per_host_rps,
+            host_limit,
+            respect_robots,
+            detect_soft_404,
+            fallback_charset,
+            renderer,
+            render_domain,
+            render_category,
+            wait,
+            cookies,
+            user_agent,
+            images_dir,
+            videos_dir,
+            styles_dir,
+            scripts_dir,
+            scripts,
+            yt_dlp_format,
+            asset_store,
+            single_file,
+            inline_max_size,
+            mark_external_links,
+            pipeline,
+            proxy,
+            proxy_domain,
+            connect_timeout,
+            read_timeout,
+            timeout,
+            ca_cert,
+            insecure_host,
+            max_body_size,
+            grace_period,
+        } = other;
+        macro_rules! take {
+            ($($field:ident),*) => {
+                $(if $field.is_some() { self.$field = $field; })*
+            };
+        }
+        take!(
+            data_dir,
+            outputs_dir,
+            session_dir,
+            concurrency,
+            asset_concurrency,
+            skip_tool_check,
+            force,
+            force_post,
+            force_category,
+            retry_failed,
+            retries,
+            retry_delay_ms,
+            per_host_concurrency,
+            per_host_rps,
+            host_limit,
+            respect_robots,
+            detect_soft_404,
+            fallback_charset,
+            renderer,
+            render_domain,
+            render_category,
+            wait,
+            cookies,
+            user_agent,
+            images_dir,
+            videos_dir,
+            styles_dir,
+            scripts_dir,
+            scripts,
+            yt_dlp_format,
+            asset_store,
+            single_file,
+            inline_max_size,
+            mark_external_links,
+            pipeline,
+            proxy,
+            proxy_domain,
+            connect_timeout,
+            read_timeout,
+            timeout,
+            ca_cert,
+            insecure_host,
+            max_body_size,
+            grace_period
+        );
+    }
+
+    pub fn data_dir(&self) -> PathBuf {
+        self.data_dir.clone().unwrap_or_else(|| PathBuf::from("data"))
+    }
+
+    pub fn outputs_dir(&self) -> PathBuf {
+        self.outputs_dir.clone().unwrap_or_else(|| PathBuf::from("outputs"))
+    }
+
+    pub fn session_dir(&self) -> PathBuf {
+        self.session_dir.clone().unwrap_or_else(|| self.data_dir().join(STATE_DIR))
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(8).max(1)
+    }
+
+    pub fn asset_concurrency(&self) -> usize {
+        self.asset_concurrency.unwrap_or(DEFAULT_ASSET_CONCURRENCY).max(1)
+    }
+
+    pub fn inline_max_size(&self) -> u64 {
+        self.inline_max_size.unwrap_or(DEFAULT_INLINE_MAX_SIZE)
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(3)
+    }
+
+    pub fn retry_delay_ms(&self) -> u64 {
+        self.retry_delay_ms.unwrap_or(500)
+    }
+
+    pub fn per_host_concurrency(&self) -> usize {
+        self.per_host_concurrency.unwrap_or(2)
+    }
+
+    pub fn fallback_charset(&self) -> &'static Encoding {
+        self.fallback_charset.unwrap_or(GB18030)
+    }
+
+    pub fn renderer(&self) -> &str {
+        self.renderer.as_deref().unwrap_or("http")
+    }
+
+    pub fn wait(&self) -> WaitCondition {
+        self.wait.clone().unwrap_or(WaitCondition::Load)
+    }
+
+    pub fn user_agent(&self) -> &str {
+        self.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT)
+    }
+
+    pub fn images_dir(&self) -> &str {
+        self.images_dir.as_deref().unwrap_or("images")
+    }
+
+    pub fn videos_dir(&self) -> &str {
+        self.videos_dir.as_deref().unwrap_or("videos")
+    }
+
+    pub fn styles_dir(&self) -> &str {
+        self.styles_dir.as_deref().unwrap_or("styles")
+    }
+
+    pub fn scripts_dir(&self) -> &str {
+        self.scripts_dir.as_deref().unwrap_or("scripts")
+    }
+
+    pub fn yt_dlp_format(&self) -> &str {
+        self.yt_dlp_format.as_deref().unwrap_or(DEFAULT_YT_DLP_FORMAT)
+    }
+
+    pub fn pipeline(&self) -> Vec<String> {
+        self.pipeline
+            .clone()
+            .unwrap_or_else(|| DEFAULT_PIPELINE.iter().map(|step| step.to_string()).collect())
+    }
+
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        seconds(self.connect_timeout.unwrap_or(30))
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        seconds(self.read_timeout.unwrap_or(60))
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        seconds(self.timeout.unwrap_or(0))
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period.unwrap_or(10))
+    }
+}
+
+/// A timeout in seconds, where 0 means none
+fn seconds(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Settings for the posts of one category or one domain (and its subdomains)
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Overrides {
+    pub renderer: Option<String>,
+    pub detect_soft_404: Option<bool>,
+    #[serde(deserialize_with = "de_encoding")]
+    pub fallback_charset: Option<&'static Encoding>,
+    pub images_dir: Option<String>,
+    pub videos_dir: Option<String>,
+    pub styles_dir: Option<String>,
+    pub scripts_dir: Option<String>,
+    #[serde(deserialize_with = "de_from_str")]
+    pub scripts: Option<ScriptPolicy>,
+    pub yt_dlp_format: Option<String>,
+    /// Processing steps replacing the run's pipeline
+    pub pipeline: Option<Vec<String>>,
+    /// Only under `domains`
+    pub per_host_concurrency: Option<usize>,
+    /// Only under `domains`
+    pub per_host_rps: Option<f64>,
+    /// Only under `domains`: cookies added to the jar
+    pub cookies: HashMap<String, String>,
+    /// Only under `domains`: extra request headers
+    pub headers: HashMap<String, String>,
+    /// Only under `domains`: proxy URL, or `direct`
+    pub proxy: Option<String>,
+    /// Only under `domains`: skip TLS certificate verification
+    pub insecure: Option<bool>,
+}
+
+impl Overrides {
+    fn merge(&mut self, other: &Overrides) {
+        macro_rules! take {
+            ($($field:ident),*) => {
+                $(if other.$field.is_some() { self.$field = other.$field.clone(); })*
+            };
+        }
+        take!(
+            renderer,
+            detect_soft_404,
+            fallback_charset,
+            images_dir,
+            videos_dir,
+            styles_dir,
+            scripts_dir,
+            scripts,
+            yt_dlp_format,
+            pipeline,
+            per_host_concurrency,
+            per_host_rps,
+            proxy,
+            insecure
+        );
+        self.cookies.extend(other.cookies.clone());
+        self.headers.extend(other.headers.clone());
+    }
+
+    /// Host limits for a domain, if the override sets any
+    pub fn host_limits(&self, default: HostLimits) -> Option<HostLimits> {
+        if self.per_host_concurrency.is_none() && self.per_host_rps.is_none() {
+            return None;
+        }
+        Some(HostLimits {
+            max_in_flight: self.per_host_concurrency.unwrap_or(default.max_in_flight),
+            requests_per_second: self.per_host_rps.or(default.requests_per_second),
+        })
+    }
+}
+
+/// Per-post settings from the `categories` and `domains` sections; a domain wins over a category
+#[derive(Debug, Clone, Default)]
+pub struct PostOverrides {
+    categories: HashMap<String, Overrides>,
+    domains: HashMap<String, Overrides>,
+}
+
+impl PostOverrides {
+    pub fn new(categories: HashMap<String, Overrides>, domains: HashMap<String, Overrides>) -> Self {
+        let domains = domains.into_iter().map(|(d, o)| (d.to_lowercase(), o)).collect();
+        Self { categories, domains }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.categories.is_empty() && self.domains.is_empty()
+    }
+
+    /// Merged overrides for a post; more specific domains win over parent domains
+    pub fn for_post(&self, post: &Post) -> Overrides {
+        let mut merged = Overrides::default();
+        if let Some(category) = self.categories.get(&post.category) {
+            merged.merge(category);
+        }
+        let host = host_of(&post.url);
+        let mut domains: Vec<_> = self
+            .domains
+            .iter()
+            .filter(|(domain, _)| host == domain.as_str() || host.ends_with(&format!(".{}", domain)))
+            .collect();
+        domains.sort_by_key(|(domain, _)| domain.len());
+        for (_, overrides) in domains {
+            merged.merge(overrides);
+        }
+        merged
+    }
+}
+
+/// Contents of a `hyfetcher.yaml` config file
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Settings applied to every run
+    pub defaults: Settings,
+    /// Named settings selected with `--profile`
+    pub profiles: HashMap<String, Settings>,
+    pub categories: HashMap<String, Overrides>,
+    pub domains: HashMap<String, Overrides>,
+}
+
+impl Config {
+    /// Parse a config file; errors name the file and the offending key
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        Self::parse(&content).with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        // serde_yaml errors already start with the path of the offending key
+        let config: Config = serde_yaml::from_str(content)?;
+        for (category, overrides) in &config.categories {
+            let domain_only = [
+                ("per-host-concurrency", overrides.per_host_concurrency.is_some()),
+                ("per-host-rps", overrides.per_host_rps.is_some()),
+                ("cookies", !overrides.cookies.is_empty()),
+                ("headers", !overrides.headers.is_empty()),
+                ("proxy", overrides.proxy.is_some()),
+                ("insecure", overrides.insecure.is_some()),
+            ];
+            if let Some((key, _)) = domain_only.iter().find(|(_, set)| *set) {
+                anyhow::bail!("categories.{}.{}: only allowed under `domains`", category, key);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Load the config given with `--config`, or `hyfetcher.yaml` in the data directory if there is one
+    pub fn find(explicit: Option<&Path>, data_dir: &Path) -> Result<Option<(PathBuf, Self)>> {
+        let path = match explicit {
+            Some(path) => path.to_path_buf(),
+            None => {
+                let path = data_dir.join(CONFIG_FILE_NAME);
+                if !path.exists() {
+                    return Ok(None);
+                }
+                path
+            }
+        };
+        let config = Self::load(&path)?;
+        Ok(Some((path, config)))
+    }
+
+    /// Layer the config's defaults, the selected profile and the command-line settings
+    pub fn resolve(&self, profile: Option<&str>, cli: Settings) -> Result<Settings> {
+        let mut settings = self.defaults.clone();
+        if let Some(name) = profile {
+            let selected = self.profiles.get(name).with_context(|| {
+                let mut known: Vec<_> = self.profiles.keys().map(String::as_str).collect();
+                known.sort();
+                format!("unknown profile `{}` (available: {})", name, known.join(", "))
+            })?;
+            settings.merge(selected.clone());
+        }
+        settings.merge(cli);
+        Ok(settings)
+    }
+
+    pub fn post_overrides(&self) -> PostOverrides {
+        PostOverrides::new(self.categories.clone(), self.domains.clone())
+    }
+}
+
+pub fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got `{}`", s))
+}
+
+pub fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| format!("unknown charset `{}`", label))
+}
+
+/// Parse a byte size with an optional K, M or G suffix (powers of 1024)
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let trimmed = s.trim().trim_end_matches(['B', 'b']);
+    let (digits, multiplier) = match trimmed.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&trimmed[..trimmed.len() - 1], 1u64 << 10),
+        Some('M') => (&trimmed[..trimmed.len() - 1], 1 << 20),
+        Some('G') => (&trimmed[..trimmed.len() - 1], 1 << 30),
+        _ => (trimmed, 1),
+    };
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size `{}` (expected e.g. 500K, 20M or 1G)", s))
+}
+
+fn de_with<'de, D, T>(deserializer: D, parse: fn(&str) -> Result<T, String>) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| parse(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn de_list_with<'de, D, T>(
+    deserializer: D,
+    parse: fn(&str) -> Result<T, String>,
+) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<Vec<String>>::deserialize(deserializer)?
+        .map(|items| items.iter().map(|s| parse(s).map_err(serde::de::Error::custom)).collect())
+        .transpose()
+}
+
+fn de_from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    de_with(deserializer, |s| T::from_str(s).map_err(|e| e.to_string()))
+}
+
+fn de_encoding<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<&'static Encoding>, D::Error> {
+    de_with(deserializer, parse_encoding)
+}
+
+fn de_host_limits<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<(String, HostLimits)>>, D::Error> {
+    de_list_with(deserializer, parse_host_limit)
+}
+
+fn de_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    // Plain YAML numbers are byte counts
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    match Option::<Size>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Size::Bytes(n)) => Ok(Some(n)),
+        Some(Size::Text(s)) => parse_size(&s).map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+fn de_key_values<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<(String, String)>>, D::Error> {
+    de_list_with(deserializer, parse_key_value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_concurrency_still_runs_one_task() {
+        let settings = Settings { concurrency: Some(0), asset_concurrency: Some(0), ..Settings::default() };
+        assert_eq!((settings.concurrency(), settings.asset_concurrency()), (1, 1));
+        assert_eq!(Settings::default().concurrency(), 8);
+    }
+
+    const CONFIG: &str = "
+defaults:
+  concurrency: 4
+  retries: 5
+  inline-max-size: 20K
+  host-limit: [example.com=1/0.5]
+profiles:
+  gentle:
+    concurrency: 1
+    per-host-rps: 0.2
+  fast:
+    concurrency: 32
+categories:
+  papers:
+    renderer: http
+domains:
+  Example.com:
+    proxy: socks5://127.0.0.1:1080
+";
+
+    #[test]
+    fn config_profile_and_cli_are_layered_over_the_defaults() {
+        let config = Config::parse(CONFIG).unwrap();
+        let cli = Settings { retries: Some(1), ..Settings::default() };
+
+        let settings = config.resolve(None, Settings::default()).unwrap();
+        assert_eq!((settings.concurrency(), settings.retries()), (4, 5));
+        assert_eq!(settings.inline_max_size(), 20 * 1024);
+        // Not set anywhere: the built-in default
+        assert_eq!(settings.retry_delay_ms(), Settings::default().retry_delay_ms());
+
+        let settings = config.resolve(Some("gentle"), cli.clone()).unwrap();
+        assert_eq!((settings.concurrency(), settings.retries()), (1, 1));
+        assert_eq!(settings.per_host_rps, Some(0.2));
+        assert_eq!(settings.host_limit.as_ref().unwrap()[0].1.requests_per_second, Some(0.5));
+
+        let settings = config.resolve(Some("fast"), Settings { concurrency: Some(2), ..cli }).unwrap();
+        assert_eq!(settings.concurrency(), 2);
+    }
+
+    #[test]
+    fn unknown_profiles_list_the_available_ones() {
+        let error = Config::parse(CONFIG).unwrap().resolve(Some("slow"), Settings::default()).unwrap_err();
+        assert_eq!(error.to_string(), "unknown profile `slow` (available: fast, gentle)");
+    }
+
+    #[test]
+    fn overrides_apply_by_category_and_domain() {
+        let overrides = Config::parse(CONFIG).unwrap().post_overrides();
+        let mut paper = crate::model::Post::new(
+            "https://www.example.com/paper".to_string(),
+            "Paper".to_string(),
+            "papers".to_string(),
+            String::new(),
+            String::new(),
+        );
+        let merged = overrides.for_post(&paper);
+        assert_eq!(merged.renderer.as_deref(), Some("http"));
+        assert_eq!(merged.proxy.as_deref(), Some("socks5://127.0.0.1:1080"));
+        paper.url = "https://example.org/paper".to_string();
+        assert_eq!(overrides.for_post(&paper).proxy, None);
+    }
+
+    #[test]
+    fn invalid_configs_name_the_offending_key() {
+        let error = |content: &str| format!("{:#}", Config::parse(content).unwrap_err());
+        assert!(error("defaults:\n  concurency: 4\n").contains("unknown field `concurency`"));
+        assert!(error("profiles:\n  fast:\n    retries: many\n").contains("profiles.fast.retries"));
+        assert!(error("defaults:\n  inline-max-size: 20X\n").contains("invalid size `20X`"));
+        assert!(error("defaults:\n  host-limit: [example.com=0]\n").contains("invalid concurrency `0`"));
+        assert!(error("defaults:\n\tconcurrency: 4\n").contains("at line 2 column 1"));
+        assert_eq!(
+            error("categories:\n  papers:\n    proxy: http://proxy\n"),
+            "categories.papers.proxy: only allowed under `domains`"
+        );
+    }
+}
@@ -0,0 +1,307 @@
+use crate::fetcher::events::ProgressEvent;
+use crate::fetcher::http::HttpSession;
+use crate::fetcher::naming::{asset_file_name, normalize_url};
+use crate::fetcher::partial::PartialFile;
+use crate::fetcher::processor::PageContext;
+use crate::manifest::STATE_DIR;
+use anyhow::{Context, Result};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use url::Url;
+
+const ASSET_INDEX_FILE: &str = "assets.json";
+/// Bytes kept from the start of a download to recognize its file type
+const HEAD_BYTES: usize = 512;
+
+/// A page asset and the directory it is saved in when there is no shared asset store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssetJob {
+    pub url: String,
+    pub dir: PathBuf,
+}
+
+/// Whether an asset is available locally after `download_assets`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssetStatus {
+    /// Downloaded in this run, with its size
+    Downloaded(u64),
+    /// Already on disk from an earlier run or page
+    Existing,
+    Failed,
+}
+
+impl AssetStatus {
+    pub fn is_available(self) -> bool {
+        !matches!(self, AssetStatus::Failed)
+    }
+}
+
+/// Local copy of an asset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalAsset {
+    /// Empty if the asset failed
+    pub path: PathBuf,
+    pub status: AssetStatus,
+}
+
+impl LocalAsset {
+    pub fn failed() -> Self {
+        Self { path: PathBuf::new(), status: AssetStatus::Failed }
+    }
+}
+
+/// Where an asset URL was saved
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AssetRecord {
+    /// File name used in the asset folders next to pages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+    /// File in the shared store, relative to the outputs directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stored: Option<String>,
+}
+
+/// Files of downloaded assets by normalized URL, kept in `.hyfetcher/assets.json` so that
+/// later runs find the same files instead of downloading them again
+#[derive(Debug, Default)]
+pub struct AssetIndex {
+    path: Option<PathBuf>,
+    records: Mutex<BTreeMap<String, AssetRecord>>,
+}
+
+impl AssetIndex {
+    /// Load the index of an outputs directory, or start an empty one
+    pub fn load(outputs_dir: &Path) -> Result<Self> {
+        let path = outputs_dir.join(STATE_DIR).join(ASSET_INDEX_FILE);
+        let records = if path.exists() {
+            let content = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read asset index {}", path.display()))?;
+            serde_json::from_str(&content)
+                .with_context(|| format!("Corrupted asset index {}", path.display()))?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self { path: Some(path), records: Mutex::new(records) })
+    }
+
+    /// Write the index atomically; an index not loaded from a directory is not saved
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let content = serde_json::to_string_pretty(&*self.records.lock().unwrap())?;
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, content)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, url: &str) -> Option<AssetRecord> {
+        self.records.lock().unwrap().get(&normalize_url(url)).cloned()
+    }
+
+    pub fn update(&self, url: &str, update: impl FnOnce(&mut AssetRecord)) {
+        update(self.records.lock().unwrap().entry(normalize_url(url)).or_default());
+    }
+}
+
+/// A body downloaded under a temporary name, to be moved to a name that depends on its contents
+pub(crate) struct Download {
+    pub file: PartialFile,
+    pub content_type: Option<String>,
+    /// Start of the body
+    pub head: Vec<u8>,
+    pub bytes: u64,
+    /// MD5 of the body, in hex
+    pub md5: String,
+}
+
+/// Download `url` into a temporary file in `dir`; a failed download is reported as an event and gives `None`
+pub(crate) async fn download_to_temp(
+    http: &HttpSession,
+    page_url: &str,
+    url: &str,
+    dir: &Path,
+) -> Result<Option<Download>> {
+    let failed = |error: String| {
+        http.events().emit(ProgressEvent::AssetFailed {
+            page_url: page_url.to_string(),
+            url: url.to_string(),
+            error,
+        });
+    };
+    let mut response = match http.get(url).await {
+        Ok(response) => response,
+        Err(e) => {
+            failed(e.to_string());
+            return Ok(None);
+        }
+    };
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let mut file = PartialFile::create(&dir.join(".download")).await?;
+    let mut head = Vec::new();
+    let mut hash = md5::Context::new();
+    let mut bytes = 0;
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                if head.len() < HEAD_BYTES {
+                    head.extend_from_slice(&chunk[..chunk.len().min(HEAD_BYTES - head.len())]);
+                }
+                hash.consume(&chunk);
+                file.write_all(&chunk).await?;
+                bytes += chunk.len() as u64;
+            }
+            Ok(None) => break,
+            Err(e) => {
+                failed(e.to_string());
+                return Ok(None);
+            }
+        }
+    }
+    http.events().emit(ProgressEvent::AssetDownloaded {
+        page_url: page_url.to_string(),
+        url: url.to_string(),
+        bytes,
+    });
+    Ok(Some(Download { file, content_type, head, bytes, md5: format!("{:x}", hash.compute()) }))
+}
+
+/// Absolute http(s) URL of an asset reference; placeholders such as `data:` URIs and `#fragment`s give `None`
+pub fn resolve_asset_url(base: Option<&Url>, raw: &str) -> Option<String> {
+    let raw = raw.trim();
+    if raw.is_empty() || raw.starts_with('#') {
+        return None;
+    }
+    let url = match base {
+        Some(base) => base.join(raw).ok()?,
+        None => Url::parse(raw).ok()?,
+    };
+    matches!(url.scheme(), "http" | "https").then(|| url.to_string())
+}
+
+/// Download the assets of a page, up to `asset_concurrency` at once, returning the local copy of each URL.
+///
+/// Requests go through the session, so the per-host limits still apply across all pages. URLs
+/// that normalize to the same one are fetched once. With a shared store, assets go there;
+/// otherwise each is saved in its job's directory under a name from [`asset_file_name`].
+/// Download failures are reported as events and [`AssetStatus::Failed`]; only local I/O
+/// errors are returned as errors.
+pub async fn download_assets(page: &PageContext, jobs: &[AssetJob]) -> Result<HashMap<String, LocalAsset>> {
+    let mut unique: Vec<(String, AssetJob)> = Vec::new();
+    for job in jobs {
+        let key = normalize_url(&job.url);
+        if !unique.iter().any(|(seen, _)| *seen == key) {
+            unique.push((key, job.clone()));
+        }
+    }
+    let limit = page.options.media.asset_concurrency.max(1);
+    let by_key: HashMap<String, LocalAsset> = stream::iter(unique)
+        .map(|(key, job)| async move {
+            let local = match &page.options.store {
+                Some(store) => store.fetch(&page.http, &page.post.url, &job.url).await?,
+                None => download_asset(page, &job).await?,
+            };
+            Ok::<_, anyhow::Error>((key, local))
+        })
+        .buffer_unordered(limit)
+        .try_collect()
+        .await?;
+    Ok(jobs
+        .iter()
+        .map(|job| (job.url.clone(), by_key[&normalize_url(&job.url)].clone()))
+        .collect())
+}
+
+/// Bytes downloaded in this run for a set of assets
+pub fn downloaded_bytes(assets: &HashMap<String, LocalAsset>) -> u64 {
+    // URLs that normalize to the same one share a download
+    let mut downloaded: HashMap<String, u64> = HashMap::new();
+    for (url, asset) in assets {
+        if let AssetStatus::Downloaded(bytes) = asset.status {
+            downloaded.insert(normalize_url(url), bytes);
+        }
+    }
+    downloaded.values().sum()
+}
+
+async fn download_asset(page: &PageContext, job: &AssetJob) -> Result<LocalAsset> {
+    let index = &page.options.asset_index;
+    if let Some(name) = index.get(&job.url).and_then(|record| record.file_name) {
+        let path = job.dir.join(name);
+        if path.exists() {
+            return Ok(LocalAsset { path, status: AssetStatus::Existing });
+        }
+    }
+    let Some(download) = download_to_temp(&page.http, &page.post.url, &job.url, &job.dir).await? else {
+        return Ok(LocalAsset::failed());
+    };
+    let name = asset_file_name(&job.url, download.content_type.as_deref(), &download.head);
+    let path = job.dir.join(&name);
+    download.file.commit_to(&path).await?;
+    index.update(&job.url, |record| record.file_name = Some(name));
+    Ok(LocalAsset { path, status: AssetStatus::Downloaded(download.bytes) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Archive;
+    use crate::config::Settings;
+    use crate::testing::{filler, post, Pages, Route, TestServer};
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\nfirst";
+
+    #[test]
+    fn only_http_references_are_assets() {
+        let base = Url::parse("https://example.com/posts/1/").unwrap();
+        let resolve = |raw| resolve_asset_url(Some(&base), raw);
+        assert_eq!(resolve("../img/a.png").as_deref(), Some("https://example.com/posts/img/a.png"));
+        assert_eq!(resolve(" //cdn.example/a.png ").as_deref(), Some("https://cdn.example/a.png"));
+        for raw in ["", "#top", "data:image/png;base64,AAAA", "blob:https://example.com/1", "mailto:a@example.com"] {
+            assert_eq!(resolve(raw), None, "{}", raw);
+        }
+        assert_eq!(resolve_asset_url(None, "a.png"), None);
+    }
+
+    #[tokio::test]
+    async fn assets_are_downloaded_once_and_rewritten_in_document_order() {
+        // The first image finishes last
+        let server = TestServer::start(vec![
+            Route::ok("/slow.png", "image/png", PNG).delayed(200),
+            Route::ok("/fast.png", "image/png", b"\x89PNG\r\n\x1a\nsecond".to_vec()),
+        ])
+        .await;
+        let images = r#"<img src="/slow.png"><img src="/fast.png"><img src="/slow.png#again">"#;
+        let html = format!("<html><body><p>{}</p>{}</body></html>", filler(), images);
+        let page_url = server.url("/post.html");
+        let outputs = tempfile::tempdir().unwrap();
+        let archive = Archive::builder()
+            .renderer(Pages::default().with(&page_url, &html))
+            .settings(Settings { renderer: Some("pages".to_string()), ..Default::default() })
+            .pipeline(["images"])
+            .posts([post(&page_url, "Post")])
+            .outputs_dir(outputs.path())
+            .build()
+            .unwrap();
+        let report = archive.run().await.unwrap();
+        assert_eq!(report.posts[0].assets.images, 3);
+        assert_eq!((server.hits("/slow.png"), server.hits("/fast.png")), (1, 1));
+
+        let saved = fs::read_to_string(outputs.path().join("notes/Post.html")).unwrap();
+        let srcs: Vec<&str> = saved.split(r#"<img src="images/"#).skip(1).map(|rest| &rest[..4]).collect();
+        assert_eq!(srcs, ["slow", "fast", "slow"]);
+    }
+}
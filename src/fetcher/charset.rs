@@ -0,0 +1,184 @@
+use crate::fetcher::dom;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252, X_USER_DEFINED};
+use regex::Regex;
+use scraper::{Html, Selector};
+use std::sync::LazyLock;
+
+/// Number of leading bytes scanned for a `<meta>` charset declaration
+const META_PRESCAN_BYTES: usize = 4096;
+
+static META_CHARSET_RE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"(?i)<meta[^>]+charset\s*=\s*["']?\s*([a-z0-9_:.\-]+)"#).unwrap()
+});
+
+/// Charset named in a `Content-Type` header value
+pub fn charset_from_content_type(content_type: &str) -> Option<&'static Encoding> {
+    content_type
+        .split(';')
+        .skip(1)
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .and_then(|(_, value)| Encoding::for_label(value.trim().trim_matches('"').as_bytes()))
+}
+
+/// Charset declared by `<meta charset>` or `<meta http-equiv="Content-Type">`.
+///
+/// As in the HTML meta prescan, a UTF-16 declaration means UTF-8: a body whose markup could be
+/// read as ASCII is not UTF-16, and `x-user-defined` means windows-1252.
+pub fn charset_from_meta(bytes: &[u8]) -> Option<&'static Encoding> {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(META_PRESCAN_BYTES)]);
+    let label = META_CHARSET_RE.captures(&head)?.get(1)?.as_str().to_string();
+    match Encoding::for_label(label.as_bytes())? {
+        encoding if encoding == UTF_16LE || encoding == UTF_16BE => Some(UTF_8),
+        encoding if encoding == X_USER_DEFINED => Some(WINDOWS_1252),
+        encoding => Some(encoding),
+    }
+}
+
+/// Decode an HTML body to UTF-8.
+///
+/// A BOM always wins. A body that is valid UTF-8 and not plain ASCII is UTF-8 whatever the
+/// page claims: single-byte charsets such as ISO-8859-1 decode any bytes, so a wrong header
+/// would otherwise silently produce mojibake. Then the `<meta>` and header charsets are tried
+/// in that order, skipping any declaration the body is not actually valid in, then `fallback`.
+/// Bodies valid in none of them are decoded lossily with the declared non-UTF-8 charset,
+/// or `fallback`.
+pub fn decode_html(
+    bytes: &[u8],
+    content_type: Option<&str>,
+    fallback: &'static Encoding,
+) -> (String, &'static Encoding) {
+    if let Some((encoding, bom_len)) = Encoding::for_bom(bytes) {
+        let (text, _) = encoding.decode_without_bom_handling(&bytes[bom_len..]);
+        return (text.into_owned(), encoding);
+    }
+    let utf8 = std::str::from_utf8(bytes).ok();
+    if let Some(text) = utf8
+        && !text.is_ascii()
+    {
+        return (text.to_string(), UTF_8);
+    }
+
+    let declared = [
+        charset_from_meta(bytes),
+        content_type.and_then(charset_from_content_type),
+    ];
+    for encoding in declared.into_iter().flatten() {
+        if let Some(text) = encoding.decode_without_bom_handling_and_without_replacement(bytes) {
+            return (text.into_owned(), encoding);
+        }
+    }
+    if let Some(text) = utf8 {
+        return (text.to_string(), UTF_8);
+    }
+
+    // The body is not UTF-8, whatever it says
+    let encoding = declared.into_iter().flatten().find(|e| *e != UTF_8).unwrap_or(fallback);
+    let (text, _) = encoding.decode_without_bom_handling(bytes);
+    (text.into_owned(), encoding)
+}
+
+/// Make the document declare UTF-8 so the offline copy renders as decoded.
+///
+/// Declarations are edited on the parsed page, so text that merely looks like one is left alone:
+///
+/// ```
+/// use hyfetcher::fetcher::charset::declare_utf8;
+/// use scraper::Html;
+///
+/// let mut document = Html::parse_document(
+///     r#"<head><meta http-equiv=Content-Type content="text/html; charset=gb2312"><title>t</title></head>
+///     <body><pre>&lt;meta charset=gbk&gt;</pre></body>"#,
+/// );
+/// declare_utf8(&mut document);
+/// let html = document.html();
+/// assert!(html.contains(r#"<meta charset="UTF-8"><meta http-equiv="Content-Type" content="text/html; charset=UTF-8">"#));
+/// assert!(html.contains("<pre>&lt;meta charset=gbk&gt;</pre>"));
+/// ```
+pub fn declare_utf8(document: &mut Html) {
+    let charset = Selector::parse("meta[charset]").unwrap();
+    for id in dom::select_ids(document, &charset) {
+        dom::set_attr(document, id, "charset", "UTF-8");
+    }
+    let http_equiv = Selector::parse("meta[http-equiv][content]").unwrap();
+    for id in dom::select_ids(document, &http_equiv) {
+        let is_content_type = dom::attr(document, id, "http-equiv")
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("content-type"));
+        let content = dom::attr(document, id, "content").unwrap_or_default();
+        if !is_content_type || !content.to_lowercase().contains("charset") {
+            continue;
+        }
+        let content: Vec<String> = content
+            .split(';')
+            .map(|param| match param.split_once('=') {
+                Some((name, _)) if name.trim().eq_ignore_ascii_case("charset") => format!("{}=UTF-8", name),
+                _ => param.to_string(),
+            })
+            .collect();
+        dom::set_attr(document, id, "content", &content.join(";"));
+    }
+    // Browsers only look for the declaration near the start of the document
+    if dom::find(document, "meta[charset]").is_none()
+        && let Some(head) = dom::find(document, "head")
+    {
+        dom::prepend_html(document, head, r#"<meta charset="UTF-8">"#);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{GB18030, GBK};
+
+    const UTF8_PAGE: &str = r#"<html><head><meta charset="utf-8"><title>中文标题 café</title></head></html>"#;
+
+    #[test]
+    fn utf8_body_beats_a_wrong_latin1_header() {
+        let (text, encoding) = decode_html(UTF8_PAGE.as_bytes(), Some("text/html; charset=iso-8859-1"), GB18030);
+        assert_eq!(encoding, UTF_8);
+        assert_eq!(text, UTF8_PAGE);
+    }
+
+    #[test]
+    fn gbk_pages_are_decoded_from_their_declaration_or_the_fallback() {
+        let html = r#"<html><head><meta charset="gbk"><title>中文标题</title></head></html>"#;
+        let (bytes, _, _) = GBK.encode(html);
+        // A wrong header that accepts any bytes loses to the meta declaration
+        let (text, encoding) = decode_html(&bytes, Some("text/html; charset=iso-8859-1"), UTF_8);
+        assert_eq!((text.as_str(), encoding), (html, GBK));
+
+        let undeclared = "<html><head><title>中文标题</title></head></html>";
+        let (bytes, _, _) = GBK.encode(undeclared);
+        let (text, encoding) = decode_html(&bytes, Some("text/html; charset=utf-8"), GB18030);
+        assert_eq!((text.as_str(), encoding), (undeclared, GB18030));
+    }
+
+    #[test]
+    fn declarations_apply_to_non_utf8_bodies() {
+        let html = "<html><head><title>caf\u{e9}</title></head></html>";
+        let (bytes, _, _) = WINDOWS_1252.encode(html);
+        let (text, encoding) = decode_html(&bytes, Some("text/html; charset=windows-1252"), GB18030);
+        assert_eq!((text.as_str(), encoding), (html, WINDOWS_1252));
+    }
+
+    #[test]
+    fn bom_wins_over_everything() {
+        let mut bytes = vec![0xEF, 0xBB, 0xBF];
+        bytes.extend_from_slice(UTF8_PAGE.as_bytes());
+        let (text, encoding) = decode_html(&bytes, Some("text/html; charset=gbk"), GB18030);
+        assert_eq!((text.as_str(), encoding), (UTF8_PAGE, UTF_8));
+    }
+
+    #[test]
+    fn utf16_meta_on_an_ascii_compatible_body_means_utf8() {
+        let html = r#"<html><head><meta charset="utf-16"><title>中文标题</title></head></html>"#;
+        let (text, encoding) = decode_html(html.as_bytes(), None, GB18030);
+        assert_eq!((text.as_str(), encoding), (html, UTF_8));
+
+        let ascii = r#"<html><head><meta charset="utf-16le"><title>title</title></head></html>"#;
+        assert_eq!(charset_from_meta(ascii.as_bytes()), Some(UTF_8));
+        let (text, encoding) = decode_html(ascii.as_bytes(), Some("text/html"), GB18030);
+        assert_eq!((text.as_str(), encoding), (ascii, UTF_8));
+        assert_eq!(charset_from_meta(br#"<meta charset="x-user-defined">"#), Some(WINDOWS_1252));
+    }
+}
@@ -0,0 +1,259 @@
+use crate::config::DEFAULT_USER_AGENT;
+use crate::fetcher::cookies::SessionJar;
+use crate::fetcher::scheduler::host_of;
+use anyhow::{Context, Result};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::{Certificate, Client, ClientBuilder, Proxy};
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+/// Per-domain proxy value that bypasses the global proxy
+pub const DIRECT: &str = "direct";
+
+/// Settings for the HTTP client shared by every request of a run
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    pub user_agent: String,
+    /// Proxy for every request: `http://`, `https://`, `socks5://` or `socks5h://` URL
+    pub proxy: Option<String>,
+    /// Proxy for a domain and its subdomains, or `direct` to bypass `proxy`
+    pub domain_proxies: Vec<(String, String)>,
+    pub connect_timeout: Option<Duration>,
+    /// Longest wait for the next piece of a response
+    pub read_timeout: Option<Duration>,
+    /// Limit for a whole request, including reading the body
+    pub timeout: Option<Duration>,
+    /// Extra trusted root certificates (PEM files, which may hold several certificates)
+    pub ca_certs: Vec<PathBuf>,
+    /// Hosts (and their subdomains) whose certificates are not verified
+    pub insecure_hosts: Vec<String>,
+    pub cookies: Option<Arc<SessionJar>>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            proxy: None,
+            domain_proxies: Vec::new(),
+            connect_timeout: Some(Duration::from_secs(30)),
+            read_timeout: Some(Duration::from_secs(60)),
+            timeout: None,
+            ca_certs: Vec::new(),
+            insecure_hosts: Vec::new(),
+            cookies: None,
+        }
+    }
+}
+
+/// Whether `host` is `domain` or one of its subdomains
+fn matches_domain(host: &str, domain: &str) -> bool {
+    host == domain || host.ends_with(&format!(".{}", domain))
+}
+
+fn parse_proxy(value: &str) -> Result<Url> {
+    let url = Url::parse(value).with_context(|| format!("Invalid proxy URL `{}`", value))?;
+    match url.scheme() {
+        "http" | "https" | "socks5" | "socks5h" => Ok(url),
+        other => anyhow::bail!("Unsupported proxy scheme `{}` in `{}` (expected http, https, socks5 or socks5h)", other, value),
+    }
+}
+
+impl ClientOptions {
+    /// The configured proxies, or `None` if there are none and the environment's apply
+    fn proxy_rules(&self) -> Result<Option<ProxyRules>> {
+        if self.proxy.is_none() && self.domain_proxies.is_empty() {
+            return Ok(None);
+        }
+        let default = self.proxy.as_deref().map(parse_proxy).transpose()?;
+        let mut by_domain = Vec::new();
+        for (domain, value) in &self.domain_proxies {
+            let proxy = if value == DIRECT { None } else { Some(parse_proxy(value)?) };
+            by_domain.push((domain.to_lowercase(), proxy));
+        }
+        // Most specific domain first
+        by_domain.sort_by_key(|(domain, _)| std::cmp::Reverse(domain.len()));
+        Ok(Some(ProxyRules { default, by_domain }))
+    }
+
+    /// Build the shared client (plus a second one without certificate checks if insecure hosts are set)
+    pub fn build(&self) -> Result<SharedClient> {
+        let secure = self.builder()?.build()?;
+        let insecure = if self.insecure_hosts.is_empty() {
+            None
+        } else {
+            Some(self.builder()?.danger_accept_invalid_certs(true).build()?)
+        };
+        Ok(SharedClient {
+            secure,
+            insecure,
+            insecure_hosts: Arc::new(self.insecure_hosts.iter().map(|h| h.to_lowercase()).collect()),
+            proxies: self.proxy_rules()?.map(Arc::new),
+            read_timeout: self.read_timeout,
+            custom_ca: !self.ca_certs.is_empty(),
+        })
+    }
+
+    fn builder(&self) -> Result<ClientBuilder> {
+        let mut builder = Client::builder()
+            .user_agent(&self.user_agent)
+            .dns_resolver(Arc::new(SystemResolver));
+        if let Some(jar) = &self.cookies {
+            builder = builder.cookie_provider(jar.clone());
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.read_timeout {
+            builder = builder.read_timeout(timeout);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        for path in &self.ca_certs {
+            let pem = std::fs::read(path)
+                .with_context(|| format!("Failed to read CA bundle {}", path.display()))?;
+            let certs = Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("Invalid CA bundle {}", path.display()))?;
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        // Environment proxies apply only when no proxy is configured
+        if let Some(rules) = self.proxy_rules()? {
+            builder = builder.proxy(Proxy::custom(move |url| rules.for_host(url.host_str().unwrap_or(""))));
+        }
+        Ok(builder)
+    }
+}
+
+/// Failed host name lookup, a type of its own so that retries can tell it from other connect errors
+#[derive(Debug, thiserror::Error)]
+#[error("failed to resolve {host}")]
+pub struct DnsError {
+    pub host: String,
+    #[source]
+    pub source: io::Error,
+}
+
+/// The system resolver, reporting failures as [`DnsError`]
+#[derive(Debug)]
+struct SystemResolver;
+
+impl Resolve for SystemResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let lookup = tokio::net::lookup_host((host.as_str(), 0))
+                .await
+                .map(|addrs| addrs.collect::<Vec<SocketAddr>>());
+            match lookup {
+                Ok(addrs) => Ok(Box::new(addrs.into_iter()) as Addrs),
+                Err(source) => Err(Box::new(DnsError { host, source }) as _),
+            }
+        })
+    }
+}
+
+/// Which proxy each host goes through
+#[derive(Debug, Clone)]
+pub struct ProxyRules {
+    /// Proxy for hosts without a domain entry; `None` connects directly
+    pub default: Option<Url>,
+    /// Proxy per domain and its subdomains, most specific first; `None` connects directly
+    pub by_domain: Vec<(String, Option<Url>)>,
+}
+
+impl ProxyRules {
+    pub fn for_host(&self, host: &str) -> Option<Url> {
+        let host = host.to_lowercase();
+        match self.by_domain.iter().find(|(domain, _)| matches_domain(&host, domain)) {
+            Some((_, proxy)) => proxy.clone(),
+            None => self.default.clone(),
+        }
+    }
+}
+
+/// The configured client, picking the unverified variant for insecure hosts
+#[derive(Debug, Clone)]
+pub struct SharedClient {
+    secure: Client,
+    insecure: Option<Client>,
+    insecure_hosts: Arc<Vec<String>>,
+    proxies: Option<Arc<ProxyRules>>,
+    read_timeout: Option<Duration>,
+    custom_ca: bool,
+}
+
+impl From<Client> for SharedClient {
+    fn from(client: Client) -> Self {
+        Self {
+            secure: client,
+            insecure: None,
+            insecure_hosts: Arc::new(Vec::new()),
+            proxies: None,
+            read_timeout: None,
+            custom_ca: false,
+        }
+    }
+}
+
+impl SharedClient {
+    pub fn for_url(&self, url: &str) -> &Client {
+        match &self.insecure {
+            Some(insecure) if self.is_insecure(url) => insecure,
+            _ => &self.secure,
+        }
+    }
+
+    /// Whether certificates are not verified for `url`
+    pub fn is_insecure(&self, url: &str) -> bool {
+        let host = host_of(url);
+        self.insecure_hosts.iter().any(|domain| matches_domain(&host, domain))
+    }
+
+    pub fn insecure_hosts(&self) -> &[String] {
+        &self.insecure_hosts
+    }
+
+    /// Configured proxies; `None` if requests use the environment's
+    pub fn proxies(&self) -> Option<&ProxyRules> {
+        self.proxies.as_deref()
+    }
+
+    /// Longest wait for the next piece of a response
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    /// Whether extra root certificates are trusted
+    pub fn has_custom_ca(&self) -> bool {
+        self.custom_ca
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn domain_proxies_override_the_default() {
+        let options = ClientOptions {
+            proxy: Some("http://proxy:8080".to_string()),
+            domain_proxies: vec![
+                ("example.com".to_string(), "socks5h://tor:9050".to_string()),
+                ("cdn.example.com".to_string(), DIRECT.to_string()),
+            ],
+            ..ClientOptions::default()
+        };
+        let rules = options.proxy_rules().unwrap().unwrap();
+        assert_eq!(rules.for_host("other.org").unwrap().as_str(), "http://proxy:8080/");
+        assert_eq!(rules.for_host("WWW.Example.com").unwrap().as_str(), "socks5h://tor:9050");
+        assert_eq!(rules.for_host("img.cdn.example.com"), None);
+        assert!(ClientOptions::default().proxy_rules().unwrap().is_none());
+    }
+}
@@ -0,0 +1,223 @@
+use url::Url;
+
+/// How a downloaded post body is stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentKind {
+    /// An HTML page, processed and saved as `.html`
+    Html,
+    /// Any other document, streamed to disk unchanged with this extension
+    Document { extension: String },
+}
+
+/// File extensions for common non-HTML content types
+const MIME_EXTENSIONS: &[(&str, &str)] = &[
+    ("application/pdf", "pdf"),
+    ("application/zip", "zip"),
+    ("application/x-zip-compressed", "zip"),
+    ("application/gzip", "gz"),
+    ("application/x-7z-compressed", "7z"),
+    ("application/x-rar-compressed", "rar"),
+    ("application/vnd.rar", "rar"),
+    ("application/json", "json"),
+    ("application/xml", "xml"),
+    ("application/msword", "doc"),
+    ("application/vnd.openxmlformats-officedocument.wordprocessingml.document", "docx"),
+    ("application/vnd.ms-excel", "xls"),
+    ("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet", "xlsx"),
+    ("application/vnd.ms-powerpoint", "ppt"),
+    ("application/vnd.openxmlformats-officedocument.presentationml.presentation", "pptx"),
+    ("application/epub+zip", "epub"),
+    ("text/plain", "txt"),
+    ("text/markdown", "md"),
+    ("text/csv", "csv"),
+    ("text/xml", "xml"),
+    ("text/css", "css"),
+    ("text/javascript", "js"),
+    ("application/javascript", "js"),
+    ("application/x-javascript", "js"),
+    ("font/woff", "woff"),
+    ("font/woff2", "woff2"),
+    ("font/ttf", "ttf"),
+    ("font/otf", "otf"),
+    ("application/font-woff", "woff"),
+    ("image/jpeg", "jpg"),
+    ("image/png", "png"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("image/svg+xml", "svg"),
+    ("image/avif", "avif"),
+    ("image/bmp", "bmp"),
+    ("video/mp4", "mp4"),
+    ("video/webm", "webm"),
+    ("audio/mpeg", "mp3"),
+    ("audio/mp4", "m4a"),
+    ("audio/ogg", "ogg"),
+];
+
+/// Extension for a MIME type (parameters are ignored)
+pub fn extension_for_mime(mime: &str) -> Option<&'static str> {
+    let mime = mime.split(';').next().unwrap_or("").trim().to_lowercase();
+    MIME_EXTENSIONS
+        .iter()
+        .find(|(m, _)| *m == mime)
+        .map(|(_, ext)| *ext)
+}
+
+/// MIME type for a file extension, the first one listed for it
+pub fn mime_for_extension(extension: &str) -> Option<&'static str> {
+    let extension = extension.to_lowercase();
+    MIME_EXTENSIONS.iter().find(|(_, ext)| *ext == extension).map(|(mime, _)| *mime)
+}
+
+/// Extension guessed from the first bytes of a body
+pub fn sniff_extension(head: &[u8]) -> Option<&'static str> {
+    let signatures: &[(&[u8], &str)] = &[
+        (b"%PDF-", "pdf"),
+        (b"\x89PNG\r\n\x1a\n", "png"),
+        (b"\xff\xd8\xff", "jpg"),
+        (b"GIF87a", "gif"),
+        (b"GIF89a", "gif"),
+        (b"PK\x03\x04", "zip"),
+        (b"\x1f\x8b", "gz"),
+        (b"7z\xbc\xaf\x27\x1c", "7z"),
+        (b"Rar!", "rar"),
+        (b"ID3", "mp3"),
+        (b"\x1a\x45\xdf\xa3", "webm"),
+        (b"wOFF", "woff"),
+        (b"wOF2", "woff2"),
+        (b"OTTO", "otf"),
+    ];
+    if let Some((_, ext)) = signatures.iter().find(|(sig, _)| head.starts_with(sig)) {
+        return Some(ext);
+    }
+    if head.len() >= 12 && &head[..4] == b"RIFF" && &head[8..12] == b"WEBP" {
+        return Some("webp");
+    }
+    if head.len() >= 12 && &head[4..12] == b"ftypavif" {
+        return Some("avif");
+    }
+    if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        return Some("mp4");
+    }
+    None
+}
+
+/// Extension for a downloaded asset: from its first bytes, else its `Content-Type`, else its URL
+pub fn asset_extension(content_type: Option<&str>, head: &[u8], url: &str) -> String {
+    let text = String::from_utf8_lossy(&head[..head.len().min(256)]).to_lowercase();
+    let svg = text.trim_start().starts_with("<svg") || (text.trim_start().starts_with("<?xml") && text.contains("<svg"));
+    sniff_extension(head)
+        .or(svg.then_some("svg"))
+        .or_else(|| content_type.and_then(extension_for_mime))
+        .map(str::to_string)
+        .or_else(|| url_extension(url))
+        .unwrap_or_else(|| "bin".to_string())
+}
+
+/// Whether the first bytes of a body look like an HTML document
+fn looks_like_html(head: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(&head[..head.len().min(1024)]).to_lowercase();
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    text.starts_with("<!doctype html") || text.starts_with("<html") || text.contains("<head") || text.contains("<body")
+}
+
+/// Extension of the URL's last path segment, if it has a short alphanumeric one
+fn url_extension(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let name = url.path_segments()?.next_back()?;
+    let (_, ext) = name.rsplit_once('.')?;
+    (!ext.is_empty() && ext.len() <= 5 && ext.chars().all(|c| c.is_ascii_alphanumeric()))
+        .then(|| ext.to_lowercase())
+}
+
+/// Decide how to store a post from its `Content-Type` and, when that is missing, generic or
+/// HTML on a binary body, its first bytes
+pub fn classify(content_type: Option<&str>, head: &[u8], url: &str) -> ContentKind {
+    let mime = content_type
+        .map(|ct| ct.split(';').next().unwrap_or("").trim().to_lowercase())
+        .unwrap_or_default();
+    match mime.as_str() {
+        // Servers label downloads as HTML too; a binary signature wins
+        "text/html" | "application/xhtml+xml" => {
+            return match sniff_extension(head) {
+                Some(ext) => ContentKind::Document { extension: ext.to_string() },
+                None => ContentKind::Html,
+            };
+        }
+        "" | "application/octet-stream" | "binary/octet-stream" => {}
+        known => {
+            if let Some(ext) = extension_for_mime(known) {
+                return ContentKind::Document { extension: ext.to_string() };
+            }
+        }
+    }
+    if let Some(ext) = sniff_extension(head) {
+        return ContentKind::Document { extension: ext.to_string() };
+    }
+    if looks_like_html(head) {
+        return ContentKind::Html;
+    }
+    ContentKind::Document {
+        extension: url_extension(url).unwrap_or_else(|| "bin".to_string()),
+    }
+}
+
+/// Short badge text for a saved document, based on its extension
+pub fn type_label(extension: &str) -> String {
+    match extension {
+        "jpg" | "png" | "gif" | "webp" | "svg" | "avif" | "bmp" => "Image".to_string(),
+        "mp4" | "webm" => "Video".to_string(),
+        "mp3" | "m4a" | "ogg" => "Audio".to_string(),
+        "txt" | "md" => "Text".to_string(),
+        other => other.to_uppercase(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &[u8] = b"\n<!DOCTYPE html><html><head><title>t</title></head></html>";
+    const PDF: &[u8] = b"%PDF-1.7\n%\xe2\xe3\xcf\xd3";
+
+    fn document(extension: &str) -> ContentKind {
+        ContentKind::Document { extension: extension.to_string() }
+    }
+
+    #[test]
+    fn html_types_are_pages() {
+        assert_eq!(classify(Some("text/html; charset=utf-8"), PAGE, "https://a.example/post"), ContentKind::Html);
+        assert_eq!(classify(Some("application/xhtml+xml"), PAGE, "https://a.example/post"), ContentKind::Html);
+        assert_eq!(classify(Some("TEXT/HTML"), b"", "https://a.example/post"), ContentKind::Html);
+    }
+
+    #[test]
+    fn missing_or_generic_types_are_sniffed() {
+        assert_eq!(classify(None, PAGE, "https://a.example/post"), ContentKind::Html);
+        assert_eq!(classify(Some("application/octet-stream"), PDF, "https://a.example/paper"), document("pdf"));
+        assert_eq!(classify(None, b"plain words", "https://a.example/notes.TXT"), document("txt"));
+        assert_eq!(classify(None, b"plain words", "https://a.example/notes"), document("bin"));
+    }
+
+    #[test]
+    fn declared_types_win_over_the_url() {
+        assert_eq!(classify(Some("application/pdf"), PDF, "https://a.example/paper.html"), document("pdf"));
+        assert_eq!(classify(Some("text/plain"), PAGE, "https://a.example/readme"), document("txt"));
+    }
+
+    #[test]
+    fn binary_bodies_served_as_html_are_documents() {
+        assert_eq!(classify(Some("text/html"), PDF, "https://a.example/download"), document("pdf"));
+        assert_eq!(classify(Some("text/html"), b"PK\x03\x04data", "https://a.example/download"), document("zip"));
+    }
+
+    #[test]
+    fn asset_extensions_prefer_the_body_then_the_type_then_the_url() {
+        let png = b"\x89PNG\r\n\x1a\n";
+        assert_eq!(asset_extension(Some("image/jpeg"), png, "https://a.example/a.gif"), "png");
+        assert_eq!(asset_extension(Some("text/plain"), b"<?xml version=\"1.0\"?><svg>", "https://a.example/a"), "svg");
+        assert_eq!(asset_extension(Some("font/woff2"), b"", "https://a.example/a.ttf"), "woff2");
+        assert_eq!(asset_extension(None, b"", "https://a.example/a.ttf?v=2"), "ttf");
+        assert_eq!(asset_extension(None, b"", "https://a.example/font"), "bin");
+    }
+}
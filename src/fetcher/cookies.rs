@@ -0,0 +1,295 @@
+use crate::fetcher::partial::PartialFile;
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use cookie_store::{CookieStore, RawCookie};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::collections::HashMap;
+use std::fs;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::RwLock;
+use url::Url;
+
+/// Extra request headers per domain (an entry for `example.com` also covers `www.example.com`)
+#[derive(Debug, Clone, Default)]
+pub struct DomainHeaders {
+    domains: Vec<(String, HeaderMap)>,
+}
+
+impl DomainHeaders {
+    pub fn insert(&mut self, domain: &str, headers: &HashMap<String, String>) -> Result<()> {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("Invalid header name `{}` for {}", name, domain))?;
+            let value = HeaderValue::from_str(value)
+                .with_context(|| format!("Invalid value for header `{}` for {}", name, domain))?;
+            map.insert(name, value);
+        }
+        self.domains.push((domain.to_lowercase(), map));
+        Ok(())
+    }
+
+    /// Headers for a host; more specific domains override less specific ones
+    pub fn for_host(&self, host: &str) -> HeaderMap {
+        let mut matching: Vec<&(String, HeaderMap)> = self
+            .domains
+            .iter()
+            .filter(|(domain, _)| host == domain || host.ends_with(&format!(".{}", domain)))
+            .collect();
+        matching.sort_by_key(|(domain, _)| domain.len());
+        let mut headers = HeaderMap::new();
+        for (_, map) in matching {
+            headers.extend(map.clone());
+        }
+        headers
+    }
+}
+
+/// Cookie jar shared by all requests, loadable from cookies.txt and persisted between runs
+#[derive(Debug, Default)]
+pub struct SessionJar {
+    store: RwLock<CookieStore>,
+}
+
+impl SessionJar {
+    /// Load a jar saved by a previous run, or start empty
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let file = fs::File::open(path)
+            .with_context(|| format!("Failed to open cookie jar {}", path.display()))?;
+        let store = cookie_store::serde::json::load(BufReader::new(file))
+            .map_err(|e| anyhow::anyhow!("Corrupted cookie jar {}: {}", path.display(), e))?;
+        Ok(Self { store: RwLock::new(store) })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.read().unwrap().iter_any().next().is_none()
+    }
+
+    /// Save all cookies, including session cookies, so the next run stays logged in.
+    ///
+    /// The file holds login sessions, so only the current user may read it.
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let mut json = Vec::new();
+        {
+            let store = self.store.read().unwrap();
+            cookie_store::serde::json::save_incl_expired_and_nonpersistent(&store, &mut json)
+                .map_err(|e| anyhow::anyhow!("Failed to save cookie jar {}: {}", path.display(), e))?;
+        }
+        PartialFile::write_private(path, json)
+            .await
+            .with_context(|| format!("Failed to save cookie jar {}", path.display()))
+    }
+
+    /// Import a Netscape/Mozilla `cookies.txt` file as exported by browser extensions
+    pub fn import_netscape(&self, path: &Path) -> Result<usize> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read cookies file {}", path.display()))?;
+        let mut store = self.store.write().unwrap();
+        let mut count = 0;
+        for (line_no, line) in content.lines().enumerate() {
+            // `#HttpOnly_` marks HttpOnly cookies, any other `#` line is a comment
+            let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+                Some(rest) => (rest, true),
+                None => (line, false),
+            };
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let [domain, include_subdomains, cookie_path, secure, expires, name, value] = fields[..] else {
+                anyhow::bail!("{}:{}: expected 7 tab-separated fields", path.display(), line_no + 1);
+            };
+            let host = domain.trim_start_matches('.');
+            let mut cookie = format!("{}={}; Path={}", name, value, cookie_path);
+            if include_subdomains.eq_ignore_ascii_case("TRUE") {
+                cookie.push_str(&format!("; Domain={}", host));
+            }
+            if secure.eq_ignore_ascii_case("TRUE") {
+                cookie.push_str("; Secure");
+            }
+            if http_only {
+                cookie.push_str("; HttpOnly");
+            }
+            if let Some(expiry) = expires.parse::<i64>().ok().filter(|e| *e > 0)
+                && let Some(date) = chrono::DateTime::from_timestamp(expiry, 0)
+            {
+                cookie.push_str(&format!("; Expires={}", date.format("%a, %d %b %Y %H:%M:%S GMT")));
+            }
+            let url = Url::parse(&format!("https://{}{}", host, cookie_path))
+                .with_context(|| format!("{}:{}: invalid domain `{}`", path.display(), line_no + 1, domain))?;
+            if store.parse(&cookie, &url).is_ok() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Add a cookie for a domain and its subdomains
+    pub fn add_domain_cookie(&self, domain: &str, name: &str, value: &str) -> Result<()> {
+        let url = Url::parse(&format!("https://{}/", domain))
+            .with_context(|| format!("Invalid cookie domain `{}`", domain))?;
+        let cookie = format!("{}={}; Domain={}; Path=/", name, value, domain);
+        self.store
+            .write()
+            .unwrap()
+            .parse(&cookie, &url)
+            .map_err(|e| anyhow::anyhow!("Invalid cookie `{}` for {}: {}", name, domain, e))?;
+        Ok(())
+    }
+
+    /// Names and values of the cookies a request to `url` would send
+    pub fn request_cookies(&self, url: &Url) -> Vec<(String, String)> {
+        self.store
+            .read()
+            .unwrap()
+            .get_request_values(url)
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// Write the jar as a Netscape `cookies.txt` file (for yt-dlp `--cookies`), readable only by
+    /// the current user
+    pub async fn export_netscape(&self, path: &Path) -> Result<()> {
+        let out = self.to_netscape();
+        PartialFile::write_private(path, out)
+            .await
+            .with_context(|| format!("Failed to write cookies file {}", path.display()))
+    }
+
+    fn to_netscape(&self) -> String {
+        let store = self.store.read().unwrap();
+        let mut out = String::from("# Netscape HTTP Cookie File\n");
+        for cookie in store.iter_unexpired() {
+            let host_only = matches!(cookie.domain, cookie_store::CookieDomain::HostOnly(_));
+            let domain = String::from(&cookie.domain);
+            let expires = cookie
+                .expires_datetime()
+                .map(|t| t.unix_timestamp())
+                .unwrap_or(0);
+            out.push_str(&format!(
+                "{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                if cookie.http_only().unwrap_or(false) { "#HttpOnly_" } else { "" },
+                if host_only { domain } else { format!(".{}", domain) },
+                if host_only { "FALSE" } else { "TRUE" },
+                String::from(&cookie.path),
+                if cookie.secure().unwrap_or(false) { "TRUE" } else { "FALSE" },
+                expires,
+                cookie.name(),
+                cookie.value()
+            ));
+        }
+        out
+    }
+}
+
+impl reqwest::cookie::CookieStore for SessionJar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        let cookies = cookie_headers
+            .filter_map(|v| std::str::from_utf8(v.as_bytes()).ok())
+            .filter_map(|s| RawCookie::parse(s.to_string()).ok())
+            .collect::<Vec<_>>();
+        self.store
+            .write()
+            .unwrap()
+            .store_response_cookies(cookies.into_iter(), url);
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let header = self
+            .store
+            .read()
+            .unwrap()
+            .get_request_values(url)
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        if header.is_empty() {
+            None
+        } else {
+            HeaderValue::from_maybe_shared(Bytes::from(header)).ok()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::cookie::CookieStore as _;
+
+    const COOKIES_TXT: &str = "\
+# Netscape HTTP Cookie File
+# This is a comment
+
+.example.com\tTRUE\t/\tFALSE\t0\tsession\tabc
+#HttpOnly_www.example.org\tFALSE\t/account\tTRUE\t4102444800\ttoken\txyz
+";
+
+    fn cookie_header(jar: &SessionJar, url: &str) -> Option<String> {
+        jar.cookies(&Url::parse(url).unwrap()).map(|v| v.to_str().unwrap().to_string())
+    }
+
+    fn import(content: &str) -> Result<(SessionJar, usize)> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("cookies.txt");
+        fs::write(&path, content)?;
+        let jar = SessionJar::default();
+        let count = jar.import_netscape(&path)?;
+        Ok((jar, count))
+    }
+
+    #[test]
+    fn imports_domain_and_http_only_cookies() {
+        let (jar, count) = import(COOKIES_TXT).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(cookie_header(&jar, "http://blog.example.com/post").as_deref(), Some("session=abc"));
+        assert_eq!(cookie_header(&jar, "https://www.example.org/account/me").as_deref(), Some("token=xyz"));
+        // Secure and path-scoped
+        assert_eq!(cookie_header(&jar, "http://www.example.org/account/me"), None);
+        assert_eq!(cookie_header(&jar, "https://www.example.org/"), None);
+        // Host-only
+        assert_eq!(cookie_header(&jar, "https://sub.www.example.org/account/"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_lines_with_their_line_number() {
+        let error = import("# Netscape HTTP Cookie File\n.example.com TRUE / FALSE 0 name value\n").unwrap_err();
+        assert!(error.to_string().contains(":2: expected 7 tab-separated fields"), "{}", error);
+        let error = import(".example.com\tTRUE\t/\tFALSE\t0\tname\n").unwrap_err();
+        assert!(error.to_string().contains(":1:"), "{}", error);
+        let error = import("exa mple.com\tFALSE\t/\tFALSE\t0\tname\tvalue\n").unwrap_err();
+        assert!(error.to_string().contains("invalid domain"), "{}", error);
+    }
+
+    #[test]
+    fn expired_cookies_are_not_imported() {
+        let (jar, count) = import(".example.com\tTRUE\t/\tFALSE\t1000\told\tvalue\n").unwrap();
+        assert_eq!(count, 0);
+        assert_eq!(cookie_header(&jar, "https://example.com/"), None);
+    }
+
+    #[tokio::test]
+    async fn saved_jars_round_trip_and_are_private() {
+        let (jar, _) = import(COOKIES_TXT).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let json = dir.path().join("session/cookies.json");
+        let txt = dir.path().join("session/cookies.txt");
+        jar.save(&json).await.unwrap();
+        jar.export_netscape(&txt).await.unwrap();
+
+        let loaded = SessionJar::load(&json).unwrap();
+        assert_eq!(cookie_header(&loaded, "https://blog.example.com/").as_deref(), Some("session=abc"));
+        let exported = SessionJar::default();
+        assert_eq!(exported.import_netscape(&txt).unwrap(), 2);
+        assert_eq!(cookie_header(&exported, "https://www.example.org/account/").as_deref(), Some("token=xyz"));
+
+        #[cfg(unix)]
+        for path in [&json, &txt] {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+    }
+}
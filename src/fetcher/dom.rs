@@ -0,0 +1,157 @@
+use ego_tree::NodeId;
+use html5ever::{ns, namespace_url, LocalName, QualName};
+use scraper::node::Text;
+use scraper::{Html, Node, Selector, StrTendril};
+
+/// Ids of the elements matching `selector`, in document order.
+///
+/// Collect ids first and edit afterwards, since a document cannot be borrowed while it is edited.
+/// Attribute values are read decoded and written escaped, however the source quoted them:
+///
+/// ```
+/// use hyfetcher::fetcher::dom;
+/// use scraper::{Html, Selector};
+///
+/// let mut document = Html::parse_document(
+///     r#"<p><img src="a.png" alt="A"><img src='b.png'><img src=c.png?w=1&amp;h=2></p><p>src="a.png"</p>"#,
+/// );
+/// for id in dom::select_ids(&document, &Selector::parse("img").unwrap()) {
+///     let src = dom::attr(&document, id, "src").unwrap();
+///     dom::set_attr(&mut document, id, "src", &format!("images/{}", src));
+/// }
+/// let html = document.html();
+/// assert!(html.contains(r#"<img src="images/a.png" alt="A">"#));
+/// assert!(html.contains(r#"<img src="images/b.png">"#));
+/// assert!(html.contains(r#"<img src="images/c.png?w=1&amp;h=2">"#));
+/// assert!(html.contains(r#"<p>src="a.png"</p>"#));
+/// ```
+pub fn select_ids(document: &Html, selector: &Selector) -> Vec<NodeId> {
+    document.select(selector).map(|element| element.id()).collect()
+}
+
+pub fn attr(document: &Html, id: NodeId, name: &str) -> Option<String> {
+    match document.tree.get(id)?.value() {
+        Node::Element(element) => element.attr(name).map(str::to_string),
+        _ => None,
+    }
+}
+
+/// Set an attribute, keeping its position if it already exists
+pub fn set_attr(document: &mut Html, id: NodeId, name: &str, value: &str) {
+    if let Some(mut node) = document.tree.get_mut(id)
+        && let Node::Element(element) = node.value()
+    {
+        element
+            .attrs
+            .insert(QualName::new(None, ns!(), LocalName::from(name)), StrTendril::from(value));
+    }
+}
+
+/// Names of an element's attributes, in order
+pub fn attr_names(document: &Html, id: NodeId) -> Vec<String> {
+    match document.tree.get(id).map(|node| node.value()) {
+        Some(Node::Element(element)) => element.attrs().map(|(name, _)| name.to_string()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+pub fn remove_attr(document: &mut Html, id: NodeId, name: &str) {
+    if let Some(mut node) = document.tree.get_mut(id)
+        && let Node::Element(element) = node.value()
+    {
+        element
+            .attrs
+            .shift_remove(&QualName::new(None, ns!(), LocalName::from(name)));
+    }
+}
+
+/// Text of an element's text children, e.g. the contents of a `<style>` block
+pub fn text(document: &Html, id: NodeId) -> String {
+    let Some(node) = document.tree.get(id) else {
+        return String::new();
+    };
+    node.children()
+        .filter_map(|child| match child.value() {
+            Node::Text(text) => Some(&*text.text),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Replace the children of an element with a single text node
+pub fn set_text(document: &mut Html, id: NodeId, text: &str) {
+    let children: Vec<NodeId> = document
+        .tree
+        .get(id)
+        .map(|node| node.children().map(|child| child.id()).collect())
+        .unwrap_or_default();
+    for child in children {
+        remove(document, child);
+    }
+    if let Some(mut node) = document.tree.get_mut(id) {
+        node.append(Node::Text(Text { text: StrTendril::from(text) }));
+    }
+}
+
+/// Take a node and its subtree out of the document
+pub fn remove(document: &mut Html, id: NodeId) {
+    if let Some(mut node) = document.tree.get_mut(id) {
+        node.detach();
+    }
+}
+
+/// The first element matching `selector`, e.g. `body`
+pub fn find(document: &Html, selector: &str) -> Option<NodeId> {
+    let selector = Selector::parse(selector).ok()?;
+    document.select(&selector).next().map(|element| element.id())
+}
+
+/// Parse an HTML fragment and add its nodes as the last children of `parent`
+pub fn append_html(document: &mut Html, parent: NodeId, html: &str) {
+    let fragment = Html::parse_fragment(html);
+    // Fragment nodes are the children of an <html> element
+    let mut child = fragment.root_element().first_child();
+    let mut ids = Vec::new();
+    while let Some(node) = child {
+        ids.push(node.id());
+        child = node.next_sibling();
+    }
+    for id in ids {
+        copy_subtree(&fragment, id, document, parent);
+    }
+}
+
+/// Parse an HTML fragment and add its nodes as the first children of `parent`
+pub fn prepend_html(document: &mut Html, parent: NodeId, html: &str) {
+    let first = document.tree.get(parent).and_then(|node| node.first_child()).map(|node| node.id());
+    let before = document.tree.get(parent).map(|node| node.children().count()).unwrap_or(0);
+    append_html(document, parent, html);
+    let Some(first) = first else {
+        return;
+    };
+    // Move the appended nodes in front of the old first child, keeping their order
+    let appended: Vec<NodeId> = document
+        .tree
+        .get(parent)
+        .map(|node| node.children().skip(before).map(|child| child.id()).collect())
+        .unwrap_or_default();
+    for id in appended {
+        if let Some(mut anchor) = document.tree.get_mut(first) {
+            anchor.insert_id_before(id);
+        }
+    }
+}
+
+fn copy_subtree(from: &Html, id: NodeId, to: &mut Html, parent: NodeId) {
+    let Some(source) = from.tree.get(id) else {
+        return;
+    };
+    let Some(mut target) = to.tree.get_mut(parent) else {
+        return;
+    };
+    let copied = target.append(source.value().clone()).id();
+    let children: Vec<NodeId> = source.children().map(|child| child.id()).collect();
+    for child in children {
+        copy_subtree(from, child, to, copied);
+    }
+}
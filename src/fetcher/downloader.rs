@@ -1,37 +1,467 @@
+use crate::config::{PostOverrides, DEFAULT_ASSET_CONCURRENCY, DEFAULT_INLINE_MAX_SIZE, DEFAULT_YT_DLP_FORMAT};
 use crate::model::Post;
-use crate::fetcher::image::process_images;
-use crate::fetcher::video::process_videos;
-use reqwest::Client;
+use crate::fetcher::assets::AssetIndex;
+use crate::fetcher::charset::declare_utf8;
+use crate::fetcher::content_type::{classify, ContentKind};
+use crate::fetcher::dom;
+use crate::fetcher::events::ProgressEvent;
+use crate::fetcher::http::{HttpResponse, HttpSession};
+use crate::fetcher::inline::Inline;
+use crate::fetcher::partial::PartialFile;
+use crate::fetcher::processor::{PageContext, Pipeline, ProcessorRegistry};
+use crate::fetcher::render::{OpenedPage, RenderRequest, RenderedPage, RendererSelection};
+use crate::fetcher::retry::FetchError;
+use crate::fetcher::scripts::{ScriptPolicy, Scripts};
+use crate::fetcher::store::AssetStore;
+use encoding_rs::{Encoding, GB18030};
+use reqwest::header::CONTENT_TYPE;
+use scraper::Html;
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::path::Path;
+use std::sync::Arc;
 use tokio::fs;
 use anyhow::Result;
 
-/// Download web page and process localized resources (images, videos)
+/// Bodies shorter than this are treated as error pages when soft-404 detection is on
+const SOFT_404_MIN_BYTES: usize = 512;
+
+/// Titles of bot challenges and error pages served with a 200 status.
+///
+/// Only the `<title>` is checked: the same words in an article are not an error.
+const ERROR_PAGE_TITLES: &[(&str, &str)] = &[
+    ("just a moment...", "Cloudflare challenge"),
+    ("attention required! | cloudflare", "Cloudflare block page"),
+    ("404 not found", "404 page"),
+    ("page not found", "404 page"),
+    ("页面不存在", "404 page"),
+];
+
+/// Elements only found on challenge pages. Cloudflare also injects its
+/// `/cdn-cgi/challenge-platform/` script into normal pages, so that is not one of them.
+const CHALLENGE_ELEMENTS: &[(&str, &str)] = &[
+    ("#challenge-form, [id^=cf-chl-]", "Cloudflare challenge"),
+    (".cf-browser-verification", "Cloudflare browser check"),
+];
+
+/// Per-run options for page downloads
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Reject 2xx responses that look like error or challenge pages
+    pub detect_soft_404: bool,
+    /// Charset for pages that declare none and are not valid UTF-8
+    pub fallback_encoding: &'static Encoding,
+    /// Renderer producing the HTML of each page
+    pub renderers: RendererSelection,
+    pub media: MediaOptions,
+    /// What happens to the JavaScript of pages
+    pub scripts: ScriptPolicy,
+    /// Steps run on each HTML page before it is saved
+    pub pipeline: Pipeline,
+    /// Embed the assets of each page so it is saved as a single file
+    pub single_file: bool,
+    /// Largest asset embedded in single-file pages
+    pub inline_max_size: u64,
+    /// Content-addressed store shared by all pages, instead of per-page asset folders
+    pub store: Option<Arc<AssetStore>>,
+    /// Where assets were saved in this and earlier runs
+    pub asset_index: Arc<AssetIndex>,
+    /// Processors that category and domain pipelines can name
+    pub processors: Arc<ProcessorRegistry>,
+    /// Per-category and per-domain changes to these options
+    pub overrides: Arc<PostOverrides>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            detect_soft_404: false,
+            fallback_encoding: GB18030,
+            renderers: RendererSelection::default(),
+            media: MediaOptions::default(),
+            scripts: ScriptPolicy::default(),
+            pipeline: Pipeline::default(),
+            single_file: false,
+            inline_max_size: DEFAULT_INLINE_MAX_SIZE,
+            store: None,
+            asset_index: Arc::new(AssetIndex::default()),
+            processors: Arc::new(ProcessorRegistry::default()),
+            overrides: Arc::new(PostOverrides::default()),
+        }
+    }
+}
+
+impl DownloadOptions {
+    /// Options for one post, with its category and domain overrides applied
+    pub fn for_post(&self, post: &Post) -> Result<DownloadOptions> {
+        let mut options = self.clone();
+        let overrides = self.overrides.for_post(post);
+        if let Some(detect) = overrides.detect_soft_404 {
+            options.detect_soft_404 = detect;
+        }
+        if let Some(encoding) = overrides.fallback_charset {
+            options.fallback_encoding = encoding;
+        }
+        if let Some(dir) = overrides.images_dir {
+            options.media.images_dir = dir;
+        }
+        if let Some(dir) = overrides.videos_dir {
+            options.media.videos_dir = dir;
+        }
+        if let Some(dir) = overrides.styles_dir {
+            options.media.styles_dir = dir;
+        }
+        if let Some(dir) = overrides.scripts_dir {
+            options.media.scripts_dir = dir;
+        }
+        if let Some(policy) = overrides.scripts {
+            options.scripts = policy;
+        }
+        if let Some(format) = overrides.yt_dlp_format {
+            options.media.yt_dlp_format = format;
+        }
+        if let Some(steps) = overrides.pipeline {
+            options.pipeline = self.processors.pipeline(&steps)?;
+        }
+        Ok(options)
+    }
+}
+
+/// Where and how page images and videos are saved
+#[derive(Debug, Clone)]
+pub struct MediaOptions {
+    /// Directory next to the page for its images
+    pub images_dir: String,
+    /// Directory next to the page for its videos
+    pub videos_dir: String,
+    /// Directory next to the page for its stylesheets and the files they reference
+    pub styles_dir: String,
+    /// Directory next to the page for its localized scripts
+    pub scripts_dir: String,
+    /// yt-dlp `--format` selection
+    pub yt_dlp_format: String,
+    /// Assets of one page downloaded at the same time
+    pub asset_concurrency: usize,
+}
+
+impl Default for MediaOptions {
+    fn default() -> Self {
+        Self {
+            images_dir: "images".to_string(),
+            videos_dir: "videos".to_string(),
+            styles_dir: "styles".to_string(),
+            scripts_dir: "scripts".to_string(),
+            yt_dlp_format: DEFAULT_YT_DLP_FORMAT.to_string(),
+            asset_concurrency: DEFAULT_ASSET_CONCURRENCY,
+        }
+    }
+}
+
+/// Result of a successful post download
+#[derive(Debug, Clone)]
+pub struct PostOutcome {
+    pub http_status: u16,
+    /// URL after redirects
+    pub final_url: String,
+    /// Saved file relative to the outputs directory
+    pub save_path: String,
+    pub content_type: Option<String>,
+    /// Bytes downloaded for the post and its assets
+    pub bytes: u64,
+    pub assets: AssetCounts,
+    /// Facts extracted from the page by the pipeline
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// Assets of a page stored locally or given up on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct AssetCounts {
+    pub images: usize,
+    pub videos: usize,
+    /// Stylesheets and the fonts and images they reference
+    pub styles: usize,
+    /// Localized scripts
+    pub scripts: usize,
+    pub failed: usize,
+    /// Bytes downloaded for assets in this run (existing local copies count as 0)
+    pub bytes: u64,
+}
+
+impl AssetCounts {
+    pub fn add(&mut self, other: AssetCounts) {
+        self.images += other.images;
+        self.videos += other.videos;
+        self.styles += other.styles;
+        self.scripts += other.scripts;
+        self.failed += other.failed;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Reason why a 2xx body looks like an error page, if it does
+pub fn detect_error_page(html: &str) -> Option<String> {
+    if html.trim().len() < SOFT_404_MIN_BYTES {
+        return Some(format!("body smaller than {} bytes", SOFT_404_MIN_BYTES));
+    }
+    let document = Html::parse_document(html);
+    let title = dom::find(&document, "title")
+        .map(|id| dom::text(&document, id).trim().to_lowercase())
+        .unwrap_or_default();
+    let by_title = ERROR_PAGE_TITLES
+        .iter()
+        .find(|(marker, _)| title.contains(marker))
+        .map(|(_, reason)| reason.to_string());
+    by_title.or_else(|| {
+        CHALLENGE_ELEMENTS
+            .iter()
+            .find(|(selector, _)| dom::find(&document, selector).is_some())
+            .map(|(_, reason)| reason.to_string())
+    })
+}
+
+/// Download a post and save it: HTML pages get their resources localized,
+/// other content types are streamed to disk as-is with a matching extension
 pub async fn download_and_save_post(
     post: &Post,
     outputs_dir: &Path,
-    client: &Client,
-) -> Result<()> {
-    let response = client.get(&post.url).send().await?;
-    let html = response.text().await?;
-    
+    http: &HttpSession,
+    options: &DownloadOptions,
+) -> Result<PostOutcome> {
+    let events = http.events();
+    events.emit(ProgressEvent::PostStarted { url: post.url.clone(), title: post.title.clone() });
+    let result = fetch_post(post, outputs_dir, http, options).await;
+    let url = post.url.clone();
+    let title = post.title.clone();
+    events.emit(match &result {
+        Ok(outcome) => ProgressEvent::PostCompleted {
+            url,
+            title,
+            http_status: outcome.http_status,
+            save_path: outcome.save_path.clone(),
+            bytes: outcome.bytes,
+        },
+        Err(e) => match e.downcast_ref::<FetchError>() {
+            Some(skip @ FetchError::RobotsDisallowed { .. }) => {
+                ProgressEvent::PostSkipped { url, title, reason: skip.to_string() }
+            }
+            _ => ProgressEvent::PostFailed { url, title, error: format!("{:#}", e) },
+        },
+    });
+    result
+}
+
+async fn fetch_post(
+    post: &Post,
+    outputs_dir: &Path,
+    http: &HttpSession,
+    options: &DownloadOptions,
+) -> Result<PostOutcome> {
+    let options = &options.for_post(post)?;
+    let renderer = options.renderers.select(post);
+    if !renderer.needs_response() {
+        // The renderer loads the page itself, so don't download it twice
+        http.check_robots(&post.url).await?;
+        let page = renderer
+            .render(RenderRequest {
+                url: &post.url,
+                http,
+                opened: None,
+                fallback_encoding: options.fallback_encoding,
+            })
+            .await?;
+        return save_html_outcome(post, page, None, outputs_dir, http, options).await;
+    }
+
+    let mut response = http.get(&post.url).await?;
+    let http_status = response.status().as_u16();
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let head = response.chunk().await?.unwrap_or_default();
+
+    match classify(content_type.as_deref(), &head, &post.url) {
+        ContentKind::Html => {
+            let page = renderer
+                .render(RenderRequest {
+                    url: &post.url,
+                    http,
+                    opened: Some(OpenedPage { response, head, content_type: content_type.clone() }),
+                    fallback_encoding: options.fallback_encoding,
+                })
+                .await?;
+            save_html_outcome(post, page, content_type, outputs_dir, http, options).await
+        }
+        ContentKind::Document { extension } => {
+            let save_path = post.get_rel_save_path_with_ext(&extension);
+            let final_url = response.url().to_string();
+            let bytes = save_document(&head, response, &outputs_dir.join(&save_path)).await?;
+            Ok(PostOutcome {
+                http_status,
+                final_url,
+                save_path,
+                content_type,
+                bytes,
+                assets: AssetCounts::default(),
+                metadata: BTreeMap::new(),
+            })
+        }
+    }
+}
+
+/// Save a rendered page and describe the result
+async fn save_html_outcome(
+    post: &Post,
+    page: RenderedPage,
+    content_type: Option<String>,
+    outputs_dir: &Path,
+    http: &HttpSession,
+    options: &DownloadOptions,
+) -> Result<PostOutcome> {
+    let saved = save_html_page(post, &page, outputs_dir, http, options).await?;
+    Ok(PostOutcome {
+        http_status: page.status,
+        final_url: page.final_url,
+        save_path: post.get_rel_save_path(),
+        content_type,
+        bytes: page.html.len() as u64 + saved.assets.bytes,
+        assets: saved.assets,
+        metadata: saved.metadata,
+    })
+}
+
+/// Stream a non-HTML body to disk through a temporary file, returning its size
+async fn save_document(head: &[u8], mut response: HttpResponse, output_path: &Path) -> Result<u64> {
+    let mut file = PartialFile::create(output_path).await?;
+    file.write_all(head).await?;
+    let mut size = head.len() as u64;
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk).await?;
+        size += chunk.len() as u64;
+    }
+    file.commit().await?;
+    Ok(size)
+}
+
+/// Run the processing pipeline on a rendered page and save it, returning what the steps collected
+async fn save_html_page(
+    post: &Post,
+    page: &RenderedPage,
+    outputs_dir: &Path,
+    http: &HttpSession,
+    options: &DownloadOptions,
+) -> Result<PageContext> {
+    // Never replace an archived page with an error page
+    if options.detect_soft_404
+        && let Some(reason) = detect_error_page(&page.html)
+    {
+        let status = reqwest::StatusCode::from_u16(page.status)?;
+        return Err(FetchError::ErrorPage { url: post.url.clone(), status, reason }.into());
+    }
+
     // Get the actual directory of the HTML file (for images storage)
-    let html_file_dir = outputs_dir.join(post.get_rel_save_path()).parent().unwrap().to_path_buf();
-    fs::create_dir_all(&html_file_dir).await?;
-    
-    // Localize images
-    let html_with_images = process_images(&html, &post.url, &html_file_dir, client).await?;
-    
-    // Localize videos
-    let html_with_videos = process_videos(&html_with_images, &post.url, &html_file_dir, client).await?;
-    
     let output_path = outputs_dir.join(post.get_rel_save_path());
-    if let Some(parent) = output_path.parent() {
-        fs::create_dir_all(parent).await?;
-    }
-    
-    fs::write(&output_path, html_with_videos).await?;
-    println!("Downloaded: {}", post.title);
-    
-    Ok(())
-}
\ No newline at end of file
+    let html_file_dir = output_path.parent().unwrap().to_path_buf();
+    fs::create_dir_all(&html_file_dir).await?;
+
+    let mut context = PageContext {
+        post: post.clone(),
+        outputs_dir: outputs_dir.to_path_buf(),
+        page_url: page.final_url.clone(),
+        page_dir: html_file_dir,
+        http: http.clone(),
+        options: options.clone(),
+        assets: AssetCounts::default(),
+        metadata: BTreeMap::new(),
+    };
+    let mut pipeline = options.pipeline.clone();
+    if !pipeline.names().contains(&"scripts") {
+        // The script policy applies whatever the pipeline, after any step that added scripts
+        pipeline = pipeline.then(Arc::new(Scripts));
+    }
+    if options.single_file {
+        // Last, so it sees the files every other step saved
+        pipeline = pipeline.then(Arc::new(Inline));
+    }
+    let mut document = Html::parse_document(&page.html);
+    // The page is UTF-8 now, whatever it was served in
+    declare_utf8(&mut document);
+    pipeline.run_document(&mut document, &mut context).await?;
+    PartialFile::write(&output_path, document.html()).await?;
+
+    Ok(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Archive;
+    use crate::config::Settings;
+    use crate::testing::{filler, post, Pages};
+
+    /// A page with enough text not to count as an empty body
+    fn page(head: &str, body: &str) -> String {
+        format!(
+            "<!DOCTYPE html><html><head>{}</head><body>{}<p>{}</p></body></html>",
+            head,
+            body,
+            filler()
+        )
+    }
+
+    #[test]
+    fn normal_cloudflare_fronted_pages_are_kept() {
+        let html = page(
+            "<title>Fixing broken links - Blog</title>",
+            r#"<article><h1>Page not found?</h1><p>When the server says 页面不存在 or Just a moment...</p></article>
+            <script src="/cdn-cgi/challenge-platform/scripts/jsd/main.js"></script>
+            <script>window.__CF$cv$params={r:'8a1b2c',t:'MTcw'};</script>"#,
+        );
+        assert_eq!(detect_error_page(&html), None);
+    }
+
+    #[test]
+    fn challenge_pages_are_rejected() {
+        let html = page(
+            "<title>Just a moment...</title>",
+            r#"<div id="challenge-running">Checking your browser</div>
+            <form id="challenge-form" action="/?__cf_chl_f_tk=abc" method="POST"></form>"#,
+        );
+        assert_eq!(detect_error_page(&html).as_deref(), Some("Cloudflare challenge"));
+
+        let html = page("<title>example.com</title>", r#"<div id="cf-chl-widget-x1y2z"></div>"#);
+        assert_eq!(detect_error_page(&html).as_deref(), Some("Cloudflare challenge"));
+    }
+
+    #[test]
+    fn error_titles_and_tiny_bodies_are_rejected() {
+        let html = page("<title>页面不存在 - 示例网站</title>", "");
+        assert_eq!(detect_error_page(&html).as_deref(), Some("404 page"));
+        let html = page("<title>Page Not Found | Blog</title>", "");
+        assert_eq!(detect_error_page(&html).as_deref(), Some("404 page"));
+        assert!(detect_error_page("<html><body>gone</body></html>").unwrap().contains("smaller than"));
+    }
+
+    #[tokio::test]
+    async fn script_policy_applies_to_pipelines_without_scripts() {
+        let outputs = tempfile::tempdir().unwrap();
+        let html = page(
+            r#"<title>Hello</title><script src="/app.js"></script>"#,
+            r#"<button onclick="go()">Go</button>"#,
+        );
+        let archive = Archive::builder()
+            .renderer(Pages::default().with("http://127.0.0.1:9/hello.html", &html))
+            .settings(Settings { renderer: Some("pages".to_string()), ..Default::default() })
+            .pipeline(["cleanup"])
+            .posts([post("http://127.0.0.1:9/hello.html", "Hello")])
+            .outputs_dir(outputs.path())
+            .build()
+            .unwrap();
+        assert_eq!(archive.run().await.unwrap().totals.completed, 1);
+
+        let saved = std::fs::read_to_string(outputs.path().join("notes/Hello.html")).unwrap();
+        assert!(!saved.contains("<script"));
+        assert!(!saved.contains("onclick"));
+    }
+}
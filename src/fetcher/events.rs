@@ -0,0 +1,149 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Events buffered per subscriber before the oldest are dropped
+const EVENT_CAPACITY: usize = 4096;
+
+/// Something that happened during a run
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum ProgressEvent {
+    /// A run is about to fetch `total` posts
+    RunStarted { total: usize },
+    PostStarted { url: String, title: String },
+    PostCompleted {
+        url: String,
+        title: String,
+        http_status: u16,
+        /// Saved file relative to the outputs directory
+        save_path: String,
+        bytes: u64,
+    },
+    PostFailed { url: String, title: String, error: String },
+    /// Deliberately not fetched, e.g. disallowed by robots.txt
+    PostSkipped { url: String, title: String, reason: String },
+    AssetDownloaded { page_url: String, url: String, bytes: u64 },
+    AssetFailed { page_url: String, url: String, error: String },
+    /// A request failed transiently and will be retried after `delay`
+    Retrying {
+        url: String,
+        attempt: u32,
+        max_retries: u32,
+        delay: Duration,
+        error: String,
+    },
+    /// Status message, e.g. how many posts were found
+    Info { message: String },
+    /// Non-fatal problem worth showing to the user
+    Warning { message: String },
+    RunFinished,
+}
+
+/// Broadcast channel of progress events, shared by everything taking part in a run.
+///
+/// Emitting never blocks; subscribers that fall behind lose the oldest events.
+///
+/// ```
+/// use hyfetcher::fetcher::events::{Events, ProgressEvent};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let events = Events::new();
+/// let mut rx = events.subscribe();
+/// events.emit(ProgressEvent::RunStarted { total: 3 });
+/// assert!(matches!(rx.recv().await, Ok(ProgressEvent::RunStarted { total: 3 })));
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Events {
+    sender: broadcast::Sender<ProgressEvent>,
+    bytes_received: Arc<AtomicU64>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Events {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        Self { sender, bytes_received: Arc::new(AtomicU64::new(0)) }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ProgressEvent> {
+        self.sender.subscribe()
+    }
+
+    pub fn emit(&self, event: ProgressEvent) {
+        // No subscribers is fine
+        let _ = self.sender.send(event);
+    }
+
+    /// Count body bytes as they arrive (too frequent to send as events)
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Body bytes received so far by all requests
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn info(&self, message: impl Into<String>) {
+        self.emit(ProgressEvent::Info { message: message.into() });
+    }
+
+    pub fn warn(&self, message: impl Into<String>) {
+        self.emit(ProgressEvent::Warning { message: message.into() });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Archive;
+    use crate::config::Settings;
+    use crate::testing::{filler, post, Pages};
+
+    #[tokio::test]
+    async fn a_run_reports_each_post_between_start_and_finish() {
+        let html = format!("<html><body><p>{}</p></body></html>", filler());
+        let (url, missing) = ("https://a.example/1", "https://a.example/missing");
+        let outputs = tempfile::tempdir().unwrap();
+        let events = Events::new();
+        let mut rx = events.subscribe();
+        let archive = Archive::builder()
+            .renderer(Pages::default().with(url, &html))
+            .settings(Settings { renderer: Some("pages".to_string()), retries: Some(0), ..Default::default() })
+            .pipeline(["cleanup"])
+            .events(events)
+            .concurrency(1)
+            .posts([post(url, "Found"), post(missing, "Missing")])
+            .outputs_dir(outputs.path())
+            .build()
+            .unwrap();
+        archive.run().await.unwrap();
+
+        let mut seen = Vec::new();
+        while let Ok(event) = rx.try_recv() {
+            match event {
+                ProgressEvent::RunStarted { total } => seen.push(format!("run {}", total)),
+                ProgressEvent::PostStarted { title, .. } => seen.push(format!("start {}", title)),
+                ProgressEvent::PostCompleted { title, save_path, .. } => {
+                    seen.push(format!("done {} {}", title, save_path))
+                }
+                ProgressEvent::PostFailed { title, .. } => seen.push(format!("failed {}", title)),
+                ProgressEvent::RunFinished => seen.push("finished".to_string()),
+                _ => {}
+            }
+        }
+        assert_eq!(
+            seen,
+            ["run 2", "start Found", "done Found notes/Found.html", "start Missing", "failed Missing", "finished"]
+        );
+    }
+}
@@ -0,0 +1,335 @@
+use crate::fetcher::client::SharedClient;
+use crate::fetcher::cookies::{DomainHeaders, SessionJar};
+use crate::fetcher::events::{Events, ProgressEvent};
+use crate::fetcher::retry::{FetchError, RetryPolicy};
+use crate::fetcher::robots::RobotsCache;
+use crate::fetcher::scheduler::{host_of, HostPermit, HostScheduler};
+use bytes::Bytes;
+use reqwest::Response;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Shared HTTP client applying robots.txt, the host scheduler and retry policy to every request
+#[derive(Debug, Clone)]
+pub struct HttpSession {
+    client: SharedClient,
+    retry: RetryPolicy,
+    scheduler: Arc<HostScheduler>,
+    robots: Option<Arc<RobotsCache>>,
+    headers: Arc<DomainHeaders>,
+    cookies_file: Option<PathBuf>,
+    cookie_jar: Option<Arc<SessionJar>>,
+    max_body_size: Option<u64>,
+    events: Events,
+}
+
+/// Successful response that keeps its host slot until the body has been read.
+///
+/// A body read that fails with a retryable error requests the URL again under the session's
+/// retry policy and skips the bytes already delivered, as long as the length is unchanged.
+#[derive(Debug)]
+pub struct HttpResponse {
+    response: Response,
+    _permit: HostPermit,
+    url: String,
+    session: HttpSession,
+    /// Length announced before any of the body was read
+    content_length: Option<u64>,
+    /// Bytes already delivered to the caller
+    received: u64,
+    /// Rest of a chunk read while skipping to `received` after a retry
+    pending: Option<Bytes>,
+    retries: u32,
+}
+
+impl Deref for HttpResponse {
+    type Target = Response;
+
+    fn deref(&self) -> &Response {
+        &self.response
+    }
+}
+
+impl HttpResponse {
+    pub async fn bytes(mut self) -> Result<Bytes, FetchError> {
+        let mut body = Vec::new();
+        while let Some(chunk) = self.chunk().await? {
+            body.extend_from_slice(&chunk);
+        }
+        Ok(Bytes::from(body))
+    }
+
+    /// Body as UTF-8, replacing invalid sequences
+    pub async fn text(self) -> Result<String, FetchError> {
+        let bytes = self.bytes().await?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// Next chunk of the body, for streaming large files to disk
+    pub async fn chunk(&mut self) -> Result<Option<Bytes>, FetchError> {
+        let chunk = loop {
+            if let Some(chunk) = self.pending.take() {
+                break Some(chunk);
+            }
+            match self.response.chunk().await {
+                Ok(chunk) => {
+                    if let Some(chunk) = &chunk {
+                        self.session.events.add_bytes(chunk.len() as u64);
+                    }
+                    break chunk;
+                }
+                Err(source) => self.resume(FetchError::Request { url: self.url.clone(), source }).await?,
+            }
+        };
+        if let Some(chunk) = &chunk {
+            self.received += chunk.len() as u64;
+            if let Some(limit) = self.session.max_body_size
+                && self.received > limit
+            {
+                return Err(FetchError::BodyTooLarge { url: self.url.clone(), limit });
+            }
+        }
+        Ok(chunk)
+    }
+
+    /// Request the URL again after a failed body read and skip what was already delivered
+    async fn resume(&mut self, mut error: FetchError) -> Result<(), FetchError> {
+        loop {
+            let retry = &self.session.retry;
+            if self.retries >= retry.max_retries || !error.is_retryable() {
+                return Err(error);
+            }
+            self.retries += 1;
+            let delay = retry.delay_for(&error, self.retries);
+            self.session.events.emit(ProgressEvent::Retrying {
+                url: self.url.clone(),
+                attempt: self.retries,
+                max_retries: retry.max_retries,
+                delay,
+                error: error.to_string(),
+            });
+            tokio::time::sleep(delay).await;
+
+            let mut restarted = self.session.fetch(&self.url).await?;
+            // A body of another length is another version of the file; the parts would not fit
+            if restarted.content_length != self.content_length {
+                return Err(error);
+            }
+            let mut skip = self.received;
+            let skipped = loop {
+                if skip == 0 {
+                    break Ok(None);
+                }
+                match restarted.response.chunk().await {
+                    Ok(Some(chunk)) => {
+                        self.session.events.add_bytes(chunk.len() as u64);
+                        if (chunk.len() as u64) > skip {
+                            break Ok(Some(chunk.slice(skip as usize..)));
+                        }
+                        skip -= chunk.len() as u64;
+                    }
+                    Ok(None) => break Err(None),
+                    Err(source) => break Err(Some(FetchError::Request { url: self.url.clone(), source })),
+                }
+            };
+            match skipped {
+                Ok(pending) => {
+                    self.response = restarted.response;
+                    self._permit = restarted._permit;
+                    self.pending = pending;
+                    return Ok(());
+                }
+                // Shorter than what was already read: the body changed
+                Err(None) => return Err(error),
+                Err(Some(next)) => error = next,
+            }
+        }
+    }
+}
+
+impl HttpSession {
+    pub fn new(client: SharedClient, retry: RetryPolicy, scheduler: Arc<HostScheduler>) -> Self {
+        Self {
+            client,
+            retry,
+            scheduler,
+            robots: None,
+            headers: Arc::new(DomainHeaders::default()),
+            cookies_file: None,
+            cookie_jar: None,
+            max_body_size: None,
+            events: Events::new(),
+        }
+    }
+
+    /// Check robots.txt before every request
+    pub fn with_robots(mut self, robots: Arc<RobotsCache>) -> Self {
+        self.robots = Some(robots);
+        self
+    }
+
+    /// Send extra headers to configured domains
+    pub fn with_domain_headers(mut self, headers: DomainHeaders) -> Self {
+        self.headers = Arc::new(headers);
+        self
+    }
+
+    /// Netscape cookies file handed to external tools such as yt-dlp
+    pub fn with_cookies_file(mut self, path: PathBuf) -> Self {
+        self.cookies_file = Some(path);
+        self
+    }
+
+    /// Cookie jar of the client, for renderers that load pages outside of it
+    pub fn with_cookie_jar(mut self, jar: Arc<SessionJar>) -> Self {
+        self.cookie_jar = Some(jar);
+        self
+    }
+
+    /// Reject responses whose body is larger than `limit` bytes
+    pub fn with_max_body_size(mut self, limit: u64) -> Self {
+        self.max_body_size = Some(limit);
+        self
+    }
+
+    /// Report progress events to subscribers of `events`
+    pub fn with_events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
+    }
+
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    pub fn cookies_file(&self) -> Option<&Path> {
+        self.cookies_file.as_deref()
+    }
+
+    pub fn cookie_jar(&self) -> Option<&Arc<SessionJar>> {
+        self.cookie_jar.as_ref()
+    }
+
+    pub fn client(&self) -> &SharedClient {
+        &self.client
+    }
+
+    pub fn scheduler(&self) -> &Arc<HostScheduler> {
+        &self.scheduler
+    }
+
+    /// GET `url`, retrying transient failures; non-success statuses and URLs
+    /// disallowed by robots.txt are returned as errors
+    pub async fn get(&self, url: &str) -> Result<HttpResponse, FetchError> {
+        self.check_robots(url).await?;
+        self.fetch(url).await
+    }
+
+    /// Fail if robots.txt disallows `url`, for pages loaded outside this session
+    pub async fn check_robots(&self, url: &str) -> Result<(), FetchError> {
+        match &self.robots {
+            Some(robots) => robots.check(url, self).await,
+            None => Ok(()),
+        }
+    }
+
+    /// GET without the robots.txt check
+    pub(crate) async fn fetch(&self, url: &str) -> Result<HttpResponse, FetchError> {
+        let headers = self.headers.for_host(&host_of(url));
+        let mut attempt = 0;
+        loop {
+            let permit = self.scheduler.acquire(url).await;
+            let error = match self.client.for_url(url).get(url).headers(headers.clone()).send().await {
+                Ok(response) if response.status().is_success() => {
+                    if let Some(limit) = self.max_body_size
+                        && response.content_length().is_some_and(|len| len > limit)
+                    {
+                        return Err(FetchError::BodyTooLarge { url: url.to_string(), limit });
+                    }
+                    return Ok(HttpResponse {
+                        content_length: response.content_length(),
+                        response,
+                        _permit: permit,
+                        url: url.to_string(),
+                        session: self.clone(),
+                        received: 0,
+                        pending: None,
+                        retries: 0,
+                    });
+                }
+                Ok(resp) => FetchError::from_status(url, resp.status(), resp.headers()),
+                Err(source) => FetchError::Request { url: url.to_string(), source },
+            };
+            // Free the host slot while backing off
+            drop(permit);
+            attempt += 1;
+            if attempt > self.retry.max_retries || !error.is_retryable() {
+                return Err(error);
+            }
+            let delay = self.retry.delay_for(&error, attempt);
+            self.events.emit(ProgressEvent::Retrying {
+                url: url.to_string(),
+                attempt,
+                max_retries: self.retry.max_retries,
+                delay,
+                error: error.to_string(),
+            });
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetcher::client::ClientOptions;
+    use crate::fetcher::scheduler::HostLimits;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve `body` with its full length, but cut the first `drops` connections off halfway
+    async fn flaky_server(body: &'static [u8], drops: usize) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/file", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let count = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let n = count.fetch_add(1, Ordering::SeqCst);
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                let _ = stream.write_all(head.as_bytes()).await;
+                let sent = if n < drops { &body[..body.len() / 2] } else { body };
+                let _ = stream.write_all(sent).await;
+            }
+        });
+        (url, connections)
+    }
+
+    fn session(max_retries: u32) -> HttpSession {
+        let retry = RetryPolicy { max_retries, base_delay: Duration::from_millis(1), ..RetryPolicy::default() };
+        let scheduler = Arc::new(HostScheduler::new(HostLimits::default(), HashMap::new()));
+        HttpSession::new(ClientOptions::default().build().unwrap(), retry, scheduler)
+    }
+
+    #[tokio::test]
+    async fn interrupted_bodies_are_resumed() {
+        let (url, connections) = flaky_server(b"0123456789abcdefghij", 2).await;
+        let body = session(3).get(&url).await.unwrap().bytes().await.unwrap();
+        assert_eq!(&body[..], b"0123456789abcdefghij");
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn interrupted_bodies_fail_once_retries_run_out() {
+        let (url, connections) = flaky_server(b"0123456789abcdefghij", 5).await;
+        let error = session(1).get(&url).await.unwrap().bytes().await.unwrap_err();
+        assert!(matches!(error, FetchError::Request { .. }));
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+}
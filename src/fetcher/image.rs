@@ -1,72 +1,279 @@
-use reqwest::Client;
+use crate::fetcher::assets::{download_assets, downloaded_bytes, resolve_asset_url, AssetJob, LocalAsset};
+use crate::fetcher::dom;
+use crate::fetcher::processor::{PageContext, Processor};
+use ego_tree::NodeId;
+use futures::future::BoxFuture;
 use scraper::{Html, Selector};
+use std::collections::HashMap;
 use url::Url;
-use std::path::Path;
-use tokio::io::AsyncWriteExt;
-
-/// Process all images in HTML, download them locally, and replace HTML src to point to local files
-pub async fn process_images(
-    html: &str,
-    page_url: &str,
-    html_file_dir: &Path,  // pass in the directory where the HTML file is located
-    client: &Client,
-) -> anyhow::Result<String> {
-    let base_url = Url::parse(page_url).ok();
-    let document = Html::parse_document(html);
-    let img_selector = Selector::parse("img").unwrap();
-
-    let mut replacements = vec![];
-
-    for img in document.select(&img_selector) {
-        if let Some(src) = img.value().attr("src") {
-            if src.starts_with("http://") || src.starts_with("https://") || base_url.is_some() {
-                let img_url = if src.starts_with("http") {
-                    src.to_string()
-                } else if let Some(base) = &base_url {
-                    base.join(src).map(|u| u.to_string()).unwrap_or(src.to_string())
-                } else {
-                    src.to_string()
-                };
-
-                // Get filename
-                let filename = img_url.split('/').last().and_then(|f| {
-                    if f.is_empty() { None } else { Some(f) }
-                }).unwrap_or("image.jpg");
-
-                // Images stored in html_file_dir/images/filename
-                let local_img_dir = html_file_dir.join("images");
-                let local_path = local_img_dir.join(filename);
-
-                // Concurrent download
-                if !local_path.exists() {
-                    if let Some(parent) = local_path.parent() {
-                        std::fs::create_dir_all(parent)?;
-                    }
-                    match client.get(&img_url).send().await {
-                        Ok(resp) => {
-                            if resp.status().is_success() {
-                                let bytes = resp.bytes().await?;
-                                let mut file = tokio::fs::File::create(&local_path).await?;
-                                file.write_all(&bytes).await?;
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to download image {}: {}", img_url, e);
-                        }
-                    }
-                }
-
-                // Replace with relative path in HTML (images/filename at the same level as html file)
-                let rel_path = format!("images/{}", filename);
-                replacements.push((src.to_string(), rel_path));
-            }
+
+/// Attributes holding the real image URL on lazy-loaded images, in order of preference
+const LAZY_SRC_ATTRS: &[&str] = &["data-src", "data-original", "data-lazy-src"];
+/// Attributes holding the real `srcset` on lazy-loaded images and sources
+const LAZY_SRCSET_ATTRS: &[&str] = &["data-srcset", "data-lazy-srcset"];
+
+/// Download all images of the page locally and point their src to the local files.
+///
+/// Lazy-load attributes (`data-src`, `data-original`, `data-lazy-src`, `data-srcset`) are
+/// resolved into `src` and `srcset`, so the saved page does not depend on the site's
+/// lazy-load script. Of each `srcset`, on `<img>` and on `<picture><source>`, the best
+/// candidate is downloaded and kept.
+#[derive(Debug, Default)]
+pub struct Images;
+
+impl Processor for Images {
+    fn name(&self) -> &'static str {
+        "images"
+    }
+
+    fn process<'a>(&'a self, document: &'a mut Html, page: &'a mut PageContext) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(process_images(document, page))
+    }
+}
+
+/// One entry of a `srcset` attribute
+#[derive(Debug, Clone, PartialEq)]
+pub struct SrcsetCandidate {
+    pub url: String,
+    /// Width (`800w`) or pixel density (`2x`) descriptor, if any
+    pub descriptor: Option<String>,
+}
+
+/// Split a `srcset` attribute into its candidates.
+///
+/// URLs may contain commas, as long as they are not at the end of the URL:
+///
+/// ```
+/// use hyfetcher::fetcher::image::{best_candidate, parse_srcset};
+///
+/// let candidates = parse_srcset("a.jpg 480w, https://cdn.example/w_960,h_540/a.jpg 960w,b.jpg");
+/// let urls: Vec<_> = candidates.iter().map(|c| c.url.as_str()).collect();
+/// assert_eq!(urls, ["a.jpg", "https://cdn.example/w_960,h_540/a.jpg", "b.jpg"]);
+/// assert_eq!(candidates[1].descriptor.as_deref(), Some("960w"));
+///
+/// assert_eq!(best_candidate(&candidates).unwrap().descriptor.as_deref(), Some("960w"));
+/// assert_eq!(best_candidate(&parse_srcset("a.jpg, a@3x.jpg 3x, a@2x.jpg 2x")).unwrap().url, "a@3x.jpg");
+/// ```
+pub fn parse_srcset(srcset: &str) -> Vec<SrcsetCandidate> {
+    let mut candidates = Vec::new();
+    let mut rest = srcset;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        if rest.is_empty() {
+            break;
+        }
+        let url_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let (url, after) = rest.split_at(url_end);
+        // A URL ending with commas has no descriptor
+        let trimmed = url.trim_end_matches(',');
+        if trimmed.len() < url.len() {
+            candidates.push(SrcsetCandidate { url: trimmed.to_string(), descriptor: None });
+            rest = after;
+            continue;
+        }
+        let descriptor_end = after.find(',').unwrap_or(after.len());
+        let descriptor = after[..descriptor_end].trim();
+        candidates.push(SrcsetCandidate {
+            url: url.to_string(),
+            descriptor: (!descriptor.is_empty()).then(|| descriptor.to_string()),
+        });
+        rest = &after[descriptor_end..];
+    }
+    candidates
+}
+
+/// The candidate with the largest width, or else the largest pixel density
+pub fn best_candidate(candidates: &[SrcsetCandidate]) -> Option<&SrcsetCandidate> {
+    let size = |candidate: &SrcsetCandidate, unit: char| {
+        candidate
+            .descriptor
+            .as_deref()
+            .and_then(|d| d.split_whitespace().find_map(|part| part.strip_suffix(unit)?.parse::<f64>().ok()))
+    };
+    let largest = |unit: char, default: Option<f64>| {
+        candidates
+            .iter()
+            .filter_map(|c| size(c, unit).or(default).map(|s| (c, s)))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(c, _)| c)
+    };
+    // A candidate without descriptor counts as 1x
+    largest('w', None).or_else(|| largest('x', Some(1.0)))
+}
+
+/// First non-empty attribute among `names`
+fn first_attr(document: &Html, id: NodeId, names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .filter_map(|name| dom::attr(document, id, name))
+        .find(|value| !value.trim().is_empty())
+}
+
+/// An `<img>` or `<picture><source>` and the images it needs
+struct ImageTarget {
+    id: NodeId,
+    is_source: bool,
+    src: Option<AssetJob>,
+    /// Best `srcset` candidate
+    best: Option<AssetJob>,
+    /// Whether the element had a `srcset` to replace
+    has_srcset: bool,
+}
+
+async fn process_images(document: &mut Html, page: &mut PageContext) -> anyhow::Result<()> {
+    let base_url = Url::parse(&page.page_url).ok();
+    let selector = Selector::parse("img, picture > source").unwrap();
+    // Images stored in <page dir>/<images dir>/, unless a shared store is used
+    let dir = page.page_dir.join(&page.options.media.images_dir);
+    let job = |url| AssetJob { url, dir: dir.clone() };
+
+    let mut targets = Vec::new();
+    let elements: Vec<(NodeId, bool)> = document
+        .select(&selector)
+        .map(|element| (element.id(), element.value().name() == "source"))
+        .collect();
+    for (id, is_source) in elements {
+        let src = if is_source {
+            None
+        } else {
+            // A lazy-load attribute holds the real image while `src` is a placeholder
+            first_attr(document, id, LAZY_SRC_ATTRS)
+                .or_else(|| dom::attr(document, id, "src"))
+                .and_then(|src| resolve_asset_url(base_url.as_ref(), &src))
+        };
+        let srcset = first_attr(document, id, LAZY_SRCSET_ATTRS).or_else(|| dom::attr(document, id, "srcset"));
+        let best = srcset
+            .as_deref()
+            .map(parse_srcset)
+            .and_then(|candidates| best_candidate(&candidates).map(|c| c.url.clone()))
+            .and_then(|url| resolve_asset_url(base_url.as_ref(), &url))
+            .filter(|url| src.as_ref() != Some(url));
+        if src.is_none() && best.is_none() {
+            continue;
         }
+        targets.push(ImageTarget {
+            id,
+            is_source,
+            src: src.map(&job),
+            best: best.map(&job),
+            has_srcset: srcset.is_some(),
+        });
+    }
+
+    let jobs: Vec<AssetJob> = targets
+        .iter()
+        .flat_map(|target| target.src.iter().chain(&target.best))
+        .cloned()
+        .collect();
+    let local = download_assets(page, &jobs).await?;
+    page.assets.bytes += downloaded_bytes(&local);
+
+    // Rewrite in document order, whatever order the downloads finished in
+    for target in targets {
+        rewrite_image(document, page, &target, &local);
     }
+    Ok(())
+}
+
+/// Point an element at its local images and drop the attributes that would load remote ones
+fn rewrite_image(document: &mut Html, page: &mut PageContext, target: &ImageTarget, local: &HashMap<String, LocalAsset>) {
+    let mut href = |job: &Option<AssetJob>| {
+        let asset = &local[&job.as_ref()?.url];
+        if asset.status.is_available() {
+            page.assets.images += 1;
+            Some(page.href(&asset.path))
+        } else {
+            page.assets.failed += 1;
+            None
+        }
+    };
+    let src = href(&target.src);
+    let best = href(&target.best);
+    let id = target.id;
+
+    if !target.is_source {
+        // A failed lazy image keeps its real URL rather than the placeholder
+        let remote = target.src.as_ref().map(|job| job.url.clone());
+        if let Some(value) = src.clone().or(best.clone()).or(remote) {
+            dom::set_attr(document, id, "src", &value);
+        }
+    }
+    match &best {
+        // A single candidate: the browser has nothing else to choose from
+        Some(path) => dom::set_attr(document, id, "srcset", path),
+        None if target.is_source => {
+            // Without its image, the source would load a remote one instead of the <img> fallback
+            dom::remove(document, id);
+            return;
+        }
+        None if target.has_srcset => dom::remove_attr(document, id, "srcset"),
+        None => {}
+    }
+    for name in LAZY_SRC_ATTRS.iter().chain(LAZY_SRCSET_ATTRS) {
+        dom::remove_attr(document, id, name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::Archive;
+    use crate::config::Settings;
+    use crate::fetcher::naming::asset_file_name;
+    use crate::testing::{filler, post, Pages, Route, TestServer};
+
+    #[tokio::test]
+    async fn lazy_sources_and_the_best_candidates_are_localized() {
+        let png = b"\x89PNG\r\n\x1a\nimage".to_vec();
+        let webp = b"RIFF\0\0\0\0WEBPVP8 ".to_vec();
+        let server = TestServer::start(vec![
+            Route::ok("/lazy.png", "image/png", png.clone()),
+            Route::ok("/small.png", "image/png", png.clone()),
+            Route::ok("/large.png", "image/png", png.clone()),
+            Route::ok("/wide@2x.webp", "image/webp", webp.clone()),
+        ])
+        .await;
+        let images = r#"
+            <img id="lazy" src="/placeholder.gif" data-src="/lazy.png">
+            <img id="responsive" src="/small.png" srcset="/small.png 480w, /large.png 960w">
+            <picture>
+              <source id="wide" srcset="/wide.webp 1x, /wide@2x.webp 2x" type="image/webp">
+              <source id="gone" srcset="/gone.webp">
+              <img id="fallback" src="/small.png">
+            </picture>"#;
+        let html = format!("<html><body><p>{}</p>{}</body></html>", filler(), images);
+        let page_url = server.url("/post.html");
+        let outputs = tempfile::tempdir().unwrap();
+        let archive = Archive::builder()
+            .renderer(Pages::default().with(&page_url, &html))
+            .settings(Settings { renderer: Some("pages".to_string()), ..Default::default() })
+            .pipeline(["images"])
+            .posts([post(&page_url, "Post")])
+            .outputs_dir(outputs.path())
+            .build()
+            .unwrap();
+        let report = archive.run().await.unwrap();
+        // lazy, small (shared by two elements), large, wide@2x; gone.webp failed
+        assert_eq!((report.posts[0].assets.images, report.posts[0].assets.failed), (5, 1));
+        for unused in ["/placeholder.gif", "/wide.webp"] {
+            assert_eq!(server.hits(unused), 0, "{}", unused);
+        }
+        assert_eq!(server.hits("/small.png"), 1);
 
-    // Batch replacement
-    let mut result = html.to_string();
-    for (from, to) in replacements {
-        result = result.replace(&format!("src=\"{}\"", from), &format!("src=\"{}\"", to));
+        let saved = std::fs::read_to_string(outputs.path().join("notes/Post.html")).unwrap();
+        let document = scraper::Html::parse_document(&saved);
+        let attr = |id: &str, name: &str| {
+            let selector = scraper::Selector::parse(&format!("#{}", id)).unwrap();
+            document.select(&selector).next().and_then(|e| e.value().attr(name)).map(str::to_string)
+        };
+        let local = |path: &str, head: &[u8]| {
+            Some(format!("images/{}", asset_file_name(&server.url(path), None, head)))
+        };
+        assert_eq!(attr("lazy", "src"), local("/lazy.png", &png));
+        assert_eq!(attr("lazy", "data-src"), None);
+        assert_eq!(attr("responsive", "src"), local("/small.png", &png));
+        assert_eq!(attr("responsive", "srcset"), local("/large.png", &png));
+        assert_eq!(attr("wide", "srcset"), local("/wide@2x.webp", &webp));
+        assert_eq!(attr("gone", "id"), None);
+        assert_eq!(attr("fallback", "src"), local("/small.png", &png));
     }
-    Ok(result)
-}
\ No newline at end of file
+}
@@ -0,0 +1,224 @@
+use crate::fetcher::content_type::mime_for_extension;
+use crate::fetcher::dom;
+use crate::fetcher::image::parse_srcset;
+use crate::fetcher::processor::{PageContext, Processor};
+use crate::fetcher::styles::rewrite_css;
+use crate::utils::relative_url;
+use anyhow::Result;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use futures::future::BoxFuture;
+use scraper::{Html, Selector};
+use std::fs;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// Nesting of `@import`ed sheets embedded in each other
+const MAX_SHEET_DEPTH: usize = 8;
+
+/// Attributes pointing at a single local file
+const FILE_ATTRS: &[(&str, &str)] = &[
+    ("img, source, video, audio, track, embed, input[type=image]", "src"),
+    ("video", "poster"),
+    ("script", "src"),
+    ("link[rel~=stylesheet], link[rel~=icon]", "href"),
+];
+
+/// Embed the local files of the page as `data:` URIs, so the page is a single self-contained file.
+///
+/// Runs after the steps that save assets: images, sources, posters, scripts, icons and
+/// stylesheets (with the fonts and images they use) up to `inline_max_size` bytes are embedded,
+/// and larger ones keep their relative links. Added automatically at the end of the pipeline
+/// in single-file mode.
+#[derive(Debug, Default)]
+pub struct Inline;
+
+impl Processor for Inline {
+    fn name(&self) -> &'static str {
+        "inline"
+    }
+
+    fn process<'a>(&'a self, document: &'a mut Html, page: &'a mut PageContext) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let (Ok(root), Ok(page_dir)) = (page.outputs_dir.canonicalize(), page.page_dir.canonicalize()) else {
+                return Ok(());
+            };
+            let inliner = Inliner { root, page_dir, max_size: page.options.inline_max_size };
+            inline_page(document, &inliner);
+            Ok(())
+        })
+    }
+}
+
+struct Inliner {
+    /// Canonical outputs directory
+    root: PathBuf,
+    /// Canonical directory of the page
+    page_dir: PathBuf,
+    max_size: u64,
+}
+
+impl Inliner {
+    /// `data:` URI for a relative link from `dir`, if it is a local file small enough to embed
+    fn data_uri(&self, dir: &Path, link: &str, depth: usize) -> Option<String> {
+        self.embed(&self.local_file(dir, link)?, depth)
+    }
+
+    /// `data:` URI with the contents of a local file, unless it is too large
+    fn embed(&self, path: &Path, depth: usize) -> Option<String> {
+        if fs::metadata(path).ok()?.len() > self.max_size {
+            return None;
+        }
+        let extension = path.extension().map(|ext| ext.to_string_lossy().into_owned()).unwrap_or_default();
+        let mime = mime_for_extension(&extension).unwrap_or("application/octet-stream");
+        let content = if mime == "text/css" {
+            if depth >= MAX_SHEET_DEPTH {
+                return None;
+            }
+            let css = fs::read_to_string(path).ok()?;
+            let sheet_dir = path.parent()?;
+            self.inline_css(&css, sheet_dir, depth + 1).into_bytes()
+        } else {
+            fs::read(path).ok()?
+        };
+        if content.len() as u64 > self.max_size {
+            return None;
+        }
+        Some(format!("data:{};base64,{}", mime, STANDARD.encode(content)))
+    }
+
+    /// The file a relative link points to, if it exists inside the outputs directory
+    fn local_file(&self, dir: &Path, link: &str) -> Option<PathBuf> {
+        let link = link.trim();
+        // Absolute URLs, data: URIs, fragments and root-relative links are not local files
+        if link.is_empty() || link.starts_with(['#', '/']) || Url::parse(link).is_ok() {
+            return None;
+        }
+        let link = link.split(['?', '#']).next()?;
+        let path = dir.join(link).canonicalize().ok()?;
+        (path.starts_with(&self.root) && path.is_file()).then_some(path)
+    }
+
+    /// CSS found in `dir` with the local files it references embedded.
+    ///
+    /// Embedded sheets resolve links against the page, so files too large to embed are
+    /// linked from the page directory.
+    fn inline_css(&self, css: &str, dir: &Path, depth: usize) -> String {
+        rewrite_css(css, |url, _| {
+            let path = self.local_file(dir, url)?;
+            self.embed(&path, depth).or_else(|| (dir != self.page_dir).then(|| relative_url(&self.page_dir, &path)))
+        })
+    }
+
+    fn inline_srcset(&self, srcset: &str, dir: &Path) -> String {
+        parse_srcset(srcset)
+            .into_iter()
+            .map(|candidate| {
+                let url = self.data_uri(dir, &candidate.url, 0).unwrap_or(candidate.url);
+                match candidate.descriptor {
+                    Some(descriptor) => format!("{} {}", url, descriptor),
+                    None => url,
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+fn inline_page(document: &mut Html, inliner: &Inliner) {
+    let page_dir = inliner.page_dir.as_path();
+    for (selector, name) in FILE_ATTRS {
+        let selector = Selector::parse(selector).unwrap();
+        for id in dom::select_ids(document, &selector) {
+            if let Some(link) = dom::attr(document, id, name)
+                && let Some(uri) = inliner.data_uri(page_dir, &link, 0)
+            {
+                dom::set_attr(document, id, name, &uri);
+            }
+        }
+    }
+    for id in dom::select_ids(document, &Selector::parse("img[srcset], source[srcset]").unwrap()) {
+        let srcset = dom::attr(document, id, "srcset").unwrap_or_default();
+        let inlined = inliner.inline_srcset(&srcset, page_dir);
+        if inlined != srcset {
+            dom::set_attr(document, id, "srcset", &inlined);
+        }
+    }
+    for id in dom::select_ids(document, &Selector::parse("style").unwrap()) {
+        let css = dom::text(document, id);
+        let inlined = inliner.inline_css(&css, page_dir, 0);
+        if inlined != css {
+            dom::set_text(document, id, &inlined);
+        }
+    }
+    for id in dom::select_ids(document, &Selector::parse("[style]").unwrap()) {
+        let css = dom::attr(document, id, "style").unwrap_or_default();
+        let inlined = inliner.inline_css(&css, page_dir, 0);
+        if inlined != css {
+            dom::set_attr(document, id, "style", &inlined);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Archive;
+    use crate::config::Settings;
+    use crate::fetcher::naming::asset_file_name;
+    use crate::testing::{filler, post, Pages, Route, TestServer};
+
+    #[tokio::test]
+    async fn files_up_to_the_size_limit_are_embedded() {
+        let small = b"\x89PNG\r\n\x1a\nsmall".to_vec();
+        let large = [b"\x89PNG\r\n\x1a\n".to_vec(), vec![b'x'; 300]].concat();
+        let server = TestServer::start(vec![
+            Route::ok("/small.png", "image/png", small.clone()),
+            Route::ok("/large.png", "image/png", large.clone()),
+            Route::ok("/site.css", "text/css", ".a{background:url(/small.png)} .b{background:url(/large.png)}"),
+        ])
+        .await;
+        let html = format!(
+            r#"<html><head><link rel="stylesheet" href="/site.css"></head>
+            <body><p>{}</p><img id="small" src="/small.png"><img id="large" src="/large.png"></body></html>"#,
+            filler()
+        );
+        let page_url = server.url("/post.html");
+        let outputs = tempfile::tempdir().unwrap();
+        let archive = Archive::builder()
+            .renderer(Pages::default().with(&page_url, &html))
+            .settings(Settings {
+                renderer: Some("pages".to_string()),
+                single_file: Some(true),
+                inline_max_size: Some(160),
+                ..Default::default()
+            })
+            .pipeline(["styles", "images"])
+            .posts([post(&page_url, "Post")])
+            .outputs_dir(outputs.path())
+            .build()
+            .unwrap();
+        archive.run().await.unwrap();
+
+        let saved = fs::read_to_string(outputs.path().join("notes/Post.html")).unwrap();
+        let document = Html::parse_document(&saved);
+        let attr = |selector: &str, name: &str| {
+            let selector = Selector::parse(selector).unwrap();
+            document.select(&selector).next().and_then(|e| e.value().attr(name)).unwrap().to_string()
+        };
+        let small_uri = format!("data:image/png;base64,{}", STANDARD.encode(&small));
+        assert_eq!(attr("#small", "src"), small_uri);
+        // Too large: kept as a file next to the page
+        let large_name = asset_file_name(&server.url("/large.png"), Some("image/png"), &large);
+        assert_eq!(attr("#large", "src"), format!("images/{}", large_name));
+        assert!(outputs.path().join("notes/images").join(&large_name).exists());
+
+        // The sheet is embedded with its small image; its large image is linked from the page
+        let sheet = attr("link", "href");
+        let css = STANDARD.decode(sheet.strip_prefix("data:text/css;base64,").unwrap()).unwrap();
+        assert_eq!(
+            String::from_utf8(css).unwrap(),
+            format!(r#".a{{background:url("{}")}} .b{{background:url("styles/{}")}}"#, small_uri, large_name)
+        );
+    }
+}
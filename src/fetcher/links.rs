@@ -0,0 +1,367 @@
+use crate::fetcher::dom;
+use crate::fetcher::events::Events;
+use crate::fetcher::partial::PartialFile;
+use crate::manifest::Manifest;
+use crate::utils::relative_url;
+use scraper::{Html, Selector};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use tokio::fs;
+use url::Url;
+
+/// Remote URL of a link rewritten to a local copy, so later runs can relink it
+const ORIGINAL_HREF_ATTR: &str = "data-original-href";
+/// Class of links leaving the archive, when external links are marked
+const EXTERNAL_CLASS: &str = "hyfetcher-external";
+const EXTERNAL_STYLE_ID: &str = "hyfetcher-links";
+const EXTERNAL_STYLE: &str = r#"a.hyfetcher-external::after { content: "\2197"; font-size: 0.8em; margin-left: 0.15em; }"#;
+
+/// Save paths of the archived posts by link key, under both the post URL and the URL it was
+/// last redirected to
+pub fn link_targets(outputs_dir: &Path, manifest: &Manifest) -> HashMap<String, String> {
+    let mut targets = HashMap::new();
+    for entry in manifest.entries() {
+        if entry.save_path.is_empty() || !outputs_dir.join(&entry.save_path).is_file() {
+            continue;
+        }
+        for url in std::iter::once(&entry.url).chain(&entry.final_url) {
+            targets.insert(link_key(url), entry.save_path.clone());
+        }
+    }
+    targets
+}
+
+/// Point links between archived posts to their local copies.
+///
+/// Runs once all downloads are done, on the pages in `written` (saved in this run) and, when
+/// the archived posts changed since `targets_before` was taken with [`link_targets`], on the
+/// pages saved in earlier runs too, so they link to posts archived since. Links to other pages
+/// are made absolute, so they still work from disk, and get the `hyfetcher-external` class when
+/// `mark_external` is set. Pages that cannot be updated are reported as warnings and left as
+/// they are. Returns the save paths of the pages that changed.
+pub async fn link_posts(
+    outputs_dir: &Path,
+    manifest: &Manifest,
+    targets_before: &HashMap<String, String>,
+    written: &HashSet<String>,
+    mark_external: bool,
+    events: &Events,
+) -> Vec<String> {
+    let local = link_targets(outputs_dir, manifest);
+    let targets_changed = local != *targets_before;
+
+    let mut changed = Vec::new();
+    for entry in manifest.entries() {
+        let fresh = written.contains(&entry.save_path);
+        if !entry.save_path.ends_with(".html") || !(fresh || targets_changed) {
+            continue;
+        }
+        let path = outputs_dir.join(&entry.save_path);
+        if !path.is_file() {
+            continue;
+        }
+        let html = match fs::read_to_string(&path).await {
+            Ok(html) => html,
+            Err(e) => {
+                events.warn(format!("Failed to update links in {}: {}", entry.save_path, e));
+                continue;
+            }
+        };
+        let html = {
+            let mut document = Html::parse_document(&html);
+            let page_dir = path.parent().unwrap_or(outputs_dir);
+            let linker = Linker { outputs_dir, page_dir, local: &local, mark_external };
+            // Relative links were written against the URL the page was served from
+            let page_url = entry.final_url.as_deref().unwrap_or(&entry.url);
+            if !linker.link_page(&mut document, page_url) {
+                continue;
+            }
+            document.html()
+        };
+        match PartialFile::write(&path, html).await {
+            Ok(()) => changed.push(entry.save_path.clone()),
+            Err(e) => events.warn(format!("Failed to update links in {}: {}", entry.save_path, e)),
+        }
+    }
+    changed
+}
+
+struct Linker<'a> {
+    outputs_dir: &'a Path,
+    page_dir: &'a Path,
+    /// Save paths of the archived posts, by link key
+    local: &'a HashMap<String, String>,
+    mark_external: bool,
+}
+
+impl Linker<'_> {
+    /// Rewrite the links of a page saved from `page_url`, returning whether anything changed
+    fn link_page(&self, document: &mut Html, page_url: &str) -> bool {
+        let base = dom::find(document, "base[href]");
+        let base_url = base
+            .and_then(|id| dom::attr(document, id, "href"))
+            .and_then(|href| Url::parse(page_url).ok()?.join(&href).ok())
+            .or_else(|| Url::parse(page_url).ok());
+        let mut changed = false;
+        let mut linked = false;
+        let mut external = false;
+        for id in dom::select_ids(document, &Selector::parse("a[href]").unwrap()) {
+            let href = dom::attr(document, id, "href").unwrap_or_default();
+            let original = dom::attr(document, id, ORIGINAL_HREF_ATTR);
+            // Fragments within the page and non-web schemes such as mailto: stay as they are
+            let Some(url) = original.clone().or_else(|| resolve_link(base_url.as_ref(), &href)) else {
+                continue;
+            };
+            let mut attrs: Vec<(&str, Option<String>)> = Vec::new();
+            match self.local.get(&link_key(&url)) {
+                Some(save_path) => {
+                    linked = true;
+                    let target = relative_url(self.page_dir, &self.outputs_dir.join(save_path));
+                    let fragment = Url::parse(&url).ok().and_then(|u| u.fragment().map(str::to_string));
+                    let local_href = match fragment {
+                        Some(fragment) => format!("{}#{}", encode_path(&target), fragment),
+                        None => encode_path(&target),
+                    };
+                    attrs.push(("href", Some(local_href)));
+                    attrs.push((ORIGINAL_HREF_ATTR, Some(url)));
+                    attrs.push(("class", with_class(dom::attr(document, id, "class"), false)));
+                }
+                None => {
+                    external = true;
+                    attrs.push(("href", Some(url)));
+                    attrs.push((ORIGINAL_HREF_ATTR, None));
+                    attrs.push(("class", with_class(dom::attr(document, id, "class"), self.mark_external)));
+                }
+            }
+            for (name, value) in attrs {
+                if dom::attr(document, id, name) == value {
+                    continue;
+                }
+                match value {
+                    Some(value) => dom::set_attr(document, id, name, &value),
+                    None => dom::remove_attr(document, id, name),
+                }
+                changed = true;
+            }
+        }
+        // A <base> would resolve the relative links to local copies against the live site
+        if linked && let Some(base) = base {
+            dom::remove(document, base);
+            changed = true;
+        }
+        let style = dom::find(document, &format!("style#{}", EXTERNAL_STYLE_ID));
+        match (style, external && self.mark_external) {
+            (None, true) => {
+                if let Some(head) = dom::find(document, "head") {
+                    let style = format!(r#"<style id="{}">{}</style>"#, EXTERNAL_STYLE_ID, EXTERNAL_STYLE);
+                    dom::append_html(document, head, &style);
+                    changed = true;
+                }
+            }
+            (Some(id), false) => {
+                dom::remove(document, id);
+                changed = true;
+            }
+            _ => {}
+        }
+        changed
+    }
+}
+
+/// Absolute http(s) URL of a link, or `None` for fragments within the page and other schemes
+fn resolve_link(base: Option<&Url>, href: &str) -> Option<String> {
+    let href = href.trim();
+    if href.is_empty() || href.starts_with('#') {
+        return None;
+    }
+    let url = match base {
+        Some(base) => base.join(href).ok()?,
+        None => Url::parse(href).ok()?,
+    };
+    matches!(url.scheme(), "http" | "https").then(|| url.to_string())
+}
+
+/// Key matching a link to a post: the URL without fragment or trailing slash, with the scheme
+/// and host lowercased and the default port dropped (as parsing does)
+fn link_key(url: &str) -> String {
+    let Ok(mut parsed) = Url::parse(url) else {
+        return url.to_string();
+    };
+    parsed.set_fragment(None);
+    if parsed.path().len() > 1 && parsed.path().ends_with('/') {
+        let path = parsed.path().trim_end_matches('/').to_string();
+        parsed.set_path(&path);
+    }
+    parsed.to_string()
+}
+
+/// The class attribute with the external marker added or removed
+fn with_class(class: Option<String>, external: bool) -> Option<String> {
+    let marked = class
+        .as_deref()
+        .is_some_and(|class| class.split_ascii_whitespace().any(|name| name == EXTERNAL_CLASS));
+    if marked == external {
+        return class;
+    }
+    let mut classes: Vec<&str> = class
+        .as_deref()
+        .unwrap_or_default()
+        .split_ascii_whitespace()
+        .filter(|name| *name != EXTERNAL_CLASS)
+        .collect();
+    if external {
+        classes.push(EXTERNAL_CLASS);
+    }
+    (!classes.is_empty()).then(|| classes.join(" "))
+}
+
+/// Percent-encode the characters of a relative path that would change the meaning of a URL
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            '%' => encoded.push_str("%25"),
+            '#' => encoded.push_str("%23"),
+            '?' => encoded.push_str("%3F"),
+            ' ' => encoded.push_str("%20"),
+            c => encoded.push(c),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Archive;
+    use crate::config::Settings;
+    use crate::fetcher::downloader::PostOutcome;
+    use crate::model::Post;
+    use crate::testing::{post, Pages};
+
+    /// Save a page as if fetched from `url` and served from `final_url`
+    fn archive_page(outputs_dir: &Path, manifest: &mut Manifest, urls: (&str, &str), title: &str, html: &str) {
+        let (url, final_url) = urls;
+        let post = post(url, title);
+        let save_path = post.get_rel_save_path();
+        std::fs::create_dir_all(outputs_dir.join("notes")).unwrap();
+        std::fs::write(outputs_dir.join(&save_path), html).unwrap();
+        let outcome = PostOutcome {
+            http_status: 200,
+            final_url: final_url.to_string(),
+            save_path,
+            content_type: Some("text/html".to_string()),
+            bytes: html.len() as u64,
+            assets: Default::default(),
+            metadata: Default::default(),
+        };
+        manifest.record_success(&post, outputs_dir, &outcome);
+    }
+
+    fn saved(outputs_dir: &Path, title: &str) -> String {
+        std::fs::read_to_string(outputs_dir.join("notes").join(format!("{}.html", title))).unwrap()
+    }
+
+    #[test]
+    fn link_keys_ignore_case_default_ports_fragments_and_trailing_slashes() {
+        assert_eq!(link_key("HTTP://Example.COM:80/Post/#top"), "http://example.com/Post");
+        assert_eq!(link_key("https://example.com:443/"), "https://example.com/");
+        assert_eq!(link_key("https://example.com:8443/post"), "https://example.com:8443/post");
+    }
+
+    #[tokio::test]
+    async fn links_follow_redirects_and_only_affected_pages_are_rewritten() {
+        let outputs = tempfile::tempdir().unwrap();
+        let outputs_dir = outputs.path();
+        let mut manifest = Manifest::load(outputs_dir).unwrap();
+        let events = Events::new();
+        let page = |links: &str| format!("<html><head></head><body>{}</body></html>", links);
+        archive_page(
+            outputs_dir,
+            &mut manifest,
+            ("http://a.example/a", "http://a.example/a"),
+            "A",
+            &page(r#"<a href="HTTP://A.example:80/old-b/">B</a> <a href="/new-b/">B again</a>"#),
+        );
+        // B redirected, and its relative links were written against where it was served from
+        archive_page(
+            outputs_dir,
+            &mut manifest,
+            ("http://a.example/old-b", "http://a.example/new-b/"),
+            "B",
+            &page(r#"<a href="sibling">C</a>"#),
+        );
+        let sibling = "http://a.example/new-b/sibling";
+        archive_page(outputs_dir, &mut manifest, (sibling, sibling), "C", &page(""));
+
+        let written: HashSet<String> = manifest.entries().map(|entry| entry.save_path.clone()).collect();
+        let changed = link_posts(outputs_dir, &manifest, &HashMap::new(), &written, false, &events).await;
+        assert_eq!(changed, ["notes/A.html", "notes/B.html"]);
+        assert_eq!(saved(outputs_dir, "A").matches(r#"href="B.html""#).count(), 2);
+        assert!(saved(outputs_dir, "B").contains(r#"href="C.html""#));
+
+        // Nothing new: pages from earlier runs are not touched
+        let targets = link_targets(outputs_dir, &manifest);
+        let d = "http://a.example/d";
+        archive_page(outputs_dir, &mut manifest, (d, d), "D", &page(r#"<a href="/e">E</a>"#));
+        let targets_with_d = link_targets(outputs_dir, &manifest);
+        assert_ne!(targets, targets_with_d);
+        let changed = link_posts(outputs_dir, &manifest, &targets_with_d, &HashSet::new(), false, &events).await;
+        assert!(changed.is_empty());
+        assert!(saved(outputs_dir, "D").contains(r#"<a href="/e">"#));
+
+        // A newly archived post is linked from the older pages pointing at it
+        let e = "http://a.example/e";
+        archive_page(outputs_dir, &mut manifest, (e, e), "E", &page(""));
+        let written = HashSet::from(["notes/E.html".to_string()]);
+        let changed = link_posts(outputs_dir, &manifest, &targets_with_d, &written, false, &events).await;
+        assert_eq!(changed, ["notes/D.html"]);
+        assert!(saved(outputs_dir, "D").contains(r#"href="E.html""#));
+    }
+
+    #[tokio::test]
+    async fn archived_posts_link_to_each_other() {
+        let outputs = tempfile::tempdir().unwrap();
+        let pages = Pages::default()
+            .with(
+                "http://127.0.0.1:9/a.html",
+                r#"<html><head><title>A</title></head><body>
+                <a href="/b/#intro">Next</a> <a href="https://example.org/x">Elsewhere</a>
+                </body></html>"#,
+            )
+            .with(
+                "http://127.0.0.1:9/b/",
+                r#"<html><head><title>B</title></head><body><a href="../a.html">Back</a></body></html>"#,
+            );
+        let posts = [
+            Post::new("http://127.0.0.1:9/a.html".into(), "First".into(), "notes".into(), String::new(), String::new()),
+            Post::new("http://127.0.0.1:9/b/".into(), "Second Post".into(), "more".into(), String::new(), String::new()),
+        ];
+        let archive = Archive::builder()
+            .renderer(pages)
+            .settings(Settings {
+                renderer: Some("pages".to_string()),
+                mark_external_links: Some(true),
+                ..Default::default()
+            })
+            .posts(posts)
+            .outputs_dir(outputs.path())
+            .build()
+            .unwrap();
+        assert_eq!(archive.run().await.unwrap().totals.completed, 2);
+
+        let first = std::fs::read_to_string(outputs.path().join("notes/First.html")).unwrap();
+        assert!(first.contains(r#"href="../more/Second%20Post.html#intro""#));
+        assert!(first.contains(r#"data-original-href="http://127.0.0.1:9/b/#intro""#));
+        assert!(first.contains(r#"href="https://example.org/x" class="hyfetcher-external""#));
+        let second = std::fs::read_to_string(outputs.path().join("more/Second Post.html")).unwrap();
+        assert!(second.contains(r#"href="../notes/First.html""#));
+
+        // The manifest was saved again with the hashes of the relinked pages
+        let manifest = Manifest::load(outputs.path()).unwrap();
+        for entry in manifest.entries() {
+            let bytes = std::fs::read(outputs.path().join(&entry.save_path)).unwrap();
+            assert_eq!(entry.content_hash, Some(format!("{:x}", md5::compute(bytes))));
+        }
+    }
+}
@@ -1,3 +1,23 @@
+pub mod assets;
+pub mod charset;
+pub mod client;
+pub mod content_type;
+pub mod cookies;
+pub mod dom;
 pub mod downloader;
+pub mod events;
+pub mod http;
 pub mod image;
-pub mod video;
\ No newline at end of file
+pub mod inline;
+pub mod links;
+pub mod naming;
+pub mod partial;
+pub mod processor;
+pub mod render;
+pub mod retry;
+pub mod robots;
+pub mod scheduler;
+pub mod scripts;
+pub mod store;
+pub mod styles;
+pub mod video;
@@ -0,0 +1,84 @@
+use crate::fetcher::content_type::asset_extension;
+use url::Url;
+
+/// Longest stem kept from the URL in asset file names
+const MAX_STEM_CHARS: usize = 48;
+/// Hex digits of the URL hash in asset file names
+const HASH_CHARS: usize = 10;
+
+/// URL identifying an asset: scheme and host lowercased, default port and fragment dropped,
+/// path segments resolved. The query string is kept since it often selects a different file.
+pub fn normalize_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut parsed) => {
+            parsed.set_fragment(None);
+            parsed.to_string()
+        }
+        Err(_) => url.to_string(),
+    }
+}
+
+/// Local file name for an asset: `<stem>-<hash>.<ext>`.
+///
+/// The stem is the sanitized last path segment, the hash comes from the normalized URL so
+/// different URLs never share a file, and the extension is taken from the file's first bytes,
+/// then its `Content-Type`, then the URL.
+///
+/// ```
+/// use hyfetcher::fetcher::naming::asset_file_name;
+///
+/// let png = b"\x89PNG\r\n\x1a\n....";
+/// let small = asset_file_name("https://example.com/a/photo.jpg?w=800", Some("image/png"), png);
+/// let large = asset_file_name("https://example.com/a/photo.jpg?w=1600", Some("image/png"), png);
+/// assert!(small.starts_with("photo-") && small.ends_with(".png"));
+/// assert_ne!(small, large);
+/// assert_eq!(small, asset_file_name("HTTPS://Example.com:443/a/photo.jpg?w=800#top", None, png));
+///
+/// let odd = asset_file_name("https://example.com/%E5%9B%BE%20%3C1%3E.gif", None, b"GIF89a");
+/// assert!(odd.starts_with("图__1-") && odd.ends_with(".gif"));
+/// assert!(asset_file_name("https://example.com/", None, b"").starts_with("asset-"));
+/// ```
+pub fn asset_file_name(url: &str, content_type: Option<&str>, head: &[u8]) -> String {
+    let normalized = normalize_url(url);
+    let hash = format!("{:x}", md5::compute(normalized.as_bytes()));
+    let extension = asset_extension(content_type, head, &normalized);
+    format!("{}-{}.{}", url_stem(&normalized), &hash[..HASH_CHARS], extension)
+}
+
+/// Last path segment without its extension, decoded and reduced to characters safe in file names
+fn url_stem(url: &str) -> String {
+    let segment = Url::parse(url)
+        .ok()
+        .and_then(|url| url.path_segments()?.next_back().map(str::to_string))
+        .unwrap_or_default();
+    let decoded = percent_decode(&segment);
+    let stem = match decoded.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => decoded.as_str(),
+    };
+    let safe: String = stem
+        .chars()
+        .map(|c| if c.is_alphanumeric() || matches!(c, '-' | '_' | '.') { c } else { '_' })
+        .take(MAX_STEM_CHARS)
+        .collect();
+    let safe = safe.trim_matches(['.', '_']);
+    if safe.is_empty() { "asset".to_string() } else { safe.to_string() }
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(byte) = s.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
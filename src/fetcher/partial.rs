@@ -0,0 +1,128 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+/// File written under a temporary `.part` name and moved into place by `commit`.
+///
+/// Dropping it before `commit` (an error, or a download aborted on Ctrl-C) removes the
+/// partial file, so a file at the final path is always complete.
+#[derive(Debug)]
+pub struct PartialFile {
+    path: PathBuf,
+    part_path: PathBuf,
+    file: File,
+    committed: bool,
+}
+
+impl PartialFile {
+    pub async fn create(path: &Path) -> io::Result<Self> {
+        Self::open(path, false).await
+    }
+
+    /// Like `create`, for files only the current user may read, e.g. session cookies
+    pub async fn create_private(path: &Path) -> io::Result<Self> {
+        Self::open(path, true).await
+    }
+
+    async fn open(path: &Path, private: bool) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        // Unique, so concurrent downloads of the same file do not write into each other
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{:08x}.part", rand::random::<u32>()));
+        let part_path = path.with_file_name(name);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        if private {
+            options.mode(0o600);
+        }
+        #[cfg(not(unix))]
+        let _ = private;
+        let file = options.open(&part_path).await?;
+        Ok(Self { path: path.to_path_buf(), part_path, file, committed: false })
+    }
+
+    pub async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.file.write_all(buf).await
+    }
+
+    /// Flush and rename to the final path
+    pub async fn commit(self) -> io::Result<()> {
+        let path = self.path.clone();
+        self.commit_to(&path).await
+    }
+
+    /// Flush and rename to `path`, for files whose name depends on their contents
+    pub async fn commit_to(mut self, path: &Path) -> io::Result<()> {
+        self.file.flush().await?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(&self.part_path, path).await?;
+        self.committed = true;
+        Ok(())
+    }
+
+    /// Write a whole file at once
+    pub async fn write(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+        let mut file = Self::create(path).await?;
+        file.write_all(contents.as_ref()).await?;
+        file.commit().await
+    }
+
+    /// Write a whole file at once, readable only by the current user
+    pub async fn write_private(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+        let mut file = Self::create_private(path).await?;
+        file.write_all(contents.as_ref()).await?;
+        file.commit().await
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_file(&self.part_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn only_committed_files_reach_their_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sub/page.html");
+        let mut file = PartialFile::create(&path).await.unwrap();
+        file.write_all(b"half a page").await.unwrap();
+        let part_name = names(&dir.path().join("sub")).remove(0);
+        assert!(part_name.starts_with("page.html.") && part_name.ends_with(".part"));
+        drop(file);
+        assert!(names(&dir.path().join("sub")).is_empty());
+
+        let mut file = PartialFile::create(&path).await.unwrap();
+        file.write_all(b"<html></html>").await.unwrap();
+        file.commit().await.unwrap();
+        assert_eq!(names(&dir.path().join("sub")), ["page.html"]);
+        assert_eq!(std::fs::read(&path).unwrap(), b"<html></html>");
+
+        let mut file = PartialFile::create(&dir.path().join("unnamed")).await.unwrap();
+        file.write_all(b"renamed").await.unwrap();
+        file.commit_to(&dir.path().join("store/named.bin")).await.unwrap();
+        assert_eq!(names(dir.path()), ["store", "sub"]);
+        assert_eq!(std::fs::read(dir.path().join("store/named.bin")).unwrap(), b"renamed");
+    }
+}
@@ -0,0 +1,346 @@
+use crate::fetcher::dom;
+use crate::fetcher::downloader::{AssetCounts, DownloadOptions};
+use crate::fetcher::http::HttpSession;
+use crate::fetcher::image::Images;
+use crate::fetcher::inline::Inline;
+use crate::fetcher::scripts::Scripts;
+use crate::fetcher::styles::Styles;
+use crate::fetcher::video::Videos;
+use crate::model::Post;
+use crate::utils::relative_url;
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use scraper::{Html, Node, Selector};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Steps run on every page unless configured otherwise
+pub const DEFAULT_PIPELINE: &[&str] = &["scripts", "styles", "images", "videos"];
+
+/// What processors know about the page being saved, and what they report back
+#[derive(Debug, Clone)]
+pub struct PageContext {
+    pub post: Post,
+    /// Root of the archive; processors never read local files outside of it
+    pub outputs_dir: PathBuf,
+    /// URL after redirects, the base for relative links
+    pub page_url: String,
+    /// Directory the page is saved in; local assets are referenced relative to it
+    pub page_dir: PathBuf,
+    pub http: HttpSession,
+    /// Options for this post, with its overrides applied
+    pub options: DownloadOptions,
+    pub assets: AssetCounts,
+    /// Facts extracted from the page, e.g. by the `metadata` step
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl PageContext {
+    /// Link from the page to a local file
+    pub fn href(&self, path: &Path) -> String {
+        relative_url(&self.page_dir, path)
+    }
+}
+
+/// One step of the HTML pipeline, editing the parsed page in place.
+///
+/// Collect what you need from the document before awaiting: it can only be held mutably.
+///
+/// ```
+/// use futures::future::BoxFuture;
+/// use hyfetcher::fetcher::dom;
+/// use hyfetcher::fetcher::processor::{PageContext, Processor};
+/// use scraper::Html;
+///
+/// /// Mark every external link to open in a new tab
+/// #[derive(Debug)]
+/// struct NewTabLinks;
+///
+/// impl Processor for NewTabLinks {
+///     fn name(&self) -> &'static str {
+///         "new-tab-links"
+///     }
+///
+///     fn process<'a>(&'a self, document: &'a mut Html, _page: &'a mut PageContext) -> BoxFuture<'a, anyhow::Result<()>> {
+///         Box::pin(async move {
+///             let links = scraper::Selector::parse("a[href^='http']").unwrap();
+///             for id in dom::select_ids(document, &links) {
+///                 dom::set_attr(document, id, "target", "_blank");
+///             }
+///             Ok(())
+///         })
+///     }
+/// }
+/// ```
+pub trait Processor: Send + Sync + fmt::Debug {
+    /// Name used in the `pipeline` setting
+    fn name(&self) -> &'static str;
+
+    fn process<'a>(&'a self, document: &'a mut Html, page: &'a mut PageContext) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Ordered processors sharing one parsed document
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    steps: Vec<Arc<dyn Processor>>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        ProcessorRegistry::default()
+            .pipeline(DEFAULT_PIPELINE)
+            .expect("default steps are built in")
+    }
+}
+
+impl Pipeline {
+    pub fn new(steps: Vec<Arc<dyn Processor>>) -> Self {
+        Self { steps }
+    }
+
+    /// The same steps followed by `step`
+    pub fn then(mut self, step: Arc<dyn Processor>) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.steps.iter().map(|step| step.name()).collect()
+    }
+
+    /// Run every step on an already parsed page
+    pub async fn run_document(&self, document: &mut Html, page: &mut PageContext) -> Result<()> {
+        for step in &self.steps {
+            step.process(document, page)
+                .await
+                .with_context(|| format!("Processor `{}` failed", step.name()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Processors available by name: the built-in steps plus any registered by the application
+#[derive(Debug, Clone)]
+pub struct ProcessorRegistry {
+    by_name: HashMap<&'static str, Arc<dyn Processor>>,
+}
+
+impl Default for ProcessorRegistry {
+    fn default() -> Self {
+        let mut registry = Self { by_name: HashMap::new() };
+        registry.register(Arc::new(Scripts));
+        registry.register(Arc::new(Styles));
+        registry.register(Arc::new(Images));
+        registry.register(Arc::new(Videos));
+        registry.register(Arc::new(Cleanup));
+        registry.register(Arc::new(Metadata));
+        registry.register(Arc::new(SourceBanner));
+        registry.register(Arc::new(Inline));
+        registry
+    }
+}
+
+impl ProcessorRegistry {
+    /// Add a processor, replacing any with the same name
+    pub fn register(&mut self, processor: Arc<dyn Processor>) {
+        self.by_name.insert(processor.name(), processor);
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<dyn Processor>> {
+        self.by_name.get(name).cloned().with_context(|| {
+            let mut known: Vec<_> = self.by_name.keys().copied().collect();
+            known.sort();
+            format!("unknown processor `{}` (available: {})", name, known.join(", "))
+        })
+    }
+
+    pub fn pipeline<S: AsRef<str>>(&self, names: &[S]) -> Result<Pipeline> {
+        let steps = names.iter().map(|name| self.get(name.as_ref())).collect::<Result<_>>()?;
+        Ok(Pipeline::new(steps))
+    }
+}
+
+/// Drop what is useless or harmful offline: comments, `<base>` (it would redirect local
+/// asset paths to the live site) and resource hints that make the browser go online
+#[derive(Debug, Default)]
+pub struct Cleanup;
+
+impl Processor for Cleanup {
+    fn name(&self) -> &'static str {
+        "cleanup"
+    }
+
+    fn process<'a>(&'a self, document: &'a mut Html, _page: &'a mut PageContext) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let selector = Selector::parse(
+                "base, link[rel~=preload], link[rel~=prefetch], link[rel~=dns-prefetch], \
+                 link[rel~=preconnect], link[rel~=modulepreload]",
+            )
+            .unwrap();
+            let mut removed = dom::select_ids(document, &selector);
+            removed.extend(
+                document
+                    .tree
+                    .nodes()
+                    .filter(|node| matches!(node.value(), Node::Comment(_)))
+                    .map(|node| node.id()),
+            );
+            for id in removed {
+                dom::remove(document, id);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Collect the title, description, author, dates and Open Graph data into the page metadata
+#[derive(Debug, Default)]
+pub struct Metadata;
+
+impl Processor for Metadata {
+    fn name(&self) -> &'static str {
+        "metadata"
+    }
+
+    fn process<'a>(&'a self, document: &'a mut Html, page: &'a mut PageContext) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let title = Selector::parse("title").unwrap();
+            if let Some(title) = document.select(&title).next() {
+                let text = title.text().collect::<String>().trim().to_string();
+                if !text.is_empty() {
+                    page.metadata.insert("title".to_string(), text);
+                }
+            }
+            let meta = Selector::parse("meta[content]").unwrap();
+            for element in document.select(&meta) {
+                let element = element.value();
+                let Some(key) = element.attr("property").or_else(|| element.attr("name")) else {
+                    continue;
+                };
+                let key = key.to_lowercase();
+                let wanted = matches!(key.as_str(), "description" | "author" | "keywords")
+                    || key.starts_with("og:")
+                    || key.starts_with("article:");
+                let content = element.attr("content").unwrap_or_default().trim();
+                if wanted && !content.is_empty() {
+                    page.metadata.entry(key).or_insert_with(|| content.to_string());
+                }
+            }
+            let canonical = Selector::parse("link[rel=canonical][href]").unwrap();
+            if let Some(link) = document.select(&canonical).next() {
+                let href = link.value().attr("href").unwrap_or_default();
+                page.metadata.insert("canonical".to_string(), href.to_string());
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Add a notice at the top of the page with the original URL and the archive date
+#[derive(Debug, Default)]
+pub struct SourceBanner;
+
+impl Processor for SourceBanner {
+    fn name(&self) -> &'static str {
+        "banner"
+    }
+
+    fn process<'a>(&'a self, document: &'a mut Html, page: &'a mut PageContext) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let Some(body) = dom::find(document, "body") else {
+                return Ok(());
+            };
+            let url = escape_html(&page.post.url);
+            let banner = format!(
+                "<div class=\"hyfetcher-banner\" style=\"font:13px sans-serif; padding:6px 10px; \
+                 background:#f5f5f5; border-bottom:1px solid #ddd; color:#555;\">\
+                 Archived from <a href=\"{}\">{}</a> on {}</div>",
+                url,
+                url,
+                chrono::Local::now().format("%Y-%m-%d")
+            );
+            dom::prepend_html(document, body, &banner);
+            Ok(())
+        })
+    }
+}
+
+pub(crate) fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Archive;
+    use crate::config::Settings;
+    use crate::testing::{filler, post, Pages, Route, TestServer};
+
+    /// Appends its name to the `steps` metadata
+    #[derive(Debug)]
+    struct Trace(&'static str);
+
+    impl Processor for Trace {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn process<'a>(&'a self, _document: &'a mut Html, page: &'a mut PageContext) -> BoxFuture<'a, Result<()>> {
+            let steps = page.metadata.entry("steps".to_string()).or_default();
+            if !steps.is_empty() {
+                steps.push(',');
+            }
+            steps.push_str(self.0);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[test]
+    fn pipelines_keep_the_configured_order_and_reject_unknown_steps() {
+        let mut registry = ProcessorRegistry::default();
+        registry.register(Arc::new(Trace("trace")));
+        let pipeline = registry.pipeline(&["videos", "trace", "scripts"]).unwrap();
+        assert_eq!(pipeline.names(), ["videos", "trace", "scripts"]);
+        assert_eq!(pipeline.then(Arc::new(Inline)).names(), ["videos", "trace", "scripts", "inline"]);
+        assert_eq!(Pipeline::default().names(), DEFAULT_PIPELINE);
+
+        let error = format!("{:#}", registry.pipeline(&["images", "thumbnails"]).unwrap_err());
+        assert!(error.contains("unknown processor `thumbnails`"));
+        assert!(error.contains("available: banner, cleanup, images, inline, metadata, scripts, styles, trace, videos"));
+    }
+
+    #[tokio::test]
+    async fn only_configured_steps_run_and_their_metadata_is_reported() {
+        let server = TestServer::start(vec![Route::ok("/photo.png", "image/png", b"\x89PNG\r\n\x1a\n".to_vec())]).await;
+        let html = format!(
+            r#"<html><head><title>Titled</title></head><body><!-- note --><p>{}</p><img src="/photo.png"></body></html>"#,
+            filler()
+        );
+        let page_url = server.url("/post.html");
+        let outputs = tempfile::tempdir().unwrap();
+        let archive = Archive::builder()
+            .renderer(Pages::default().with(&page_url, &html))
+            .settings(Settings { renderer: Some("pages".to_string()), ..Default::default() })
+            .processor(Trace("second"))
+            .processor(Trace("first"))
+            .pipeline(["second", "metadata", "first"])
+            .posts([post(&page_url, "Post")])
+            .outputs_dir(outputs.path())
+            .build()
+            .unwrap();
+        let report = archive.run().await.unwrap();
+        let metadata = &report.posts[0].metadata;
+        assert_eq!(metadata.get("steps").map(String::as_str), Some("second,first"));
+        assert_eq!(metadata.get("title").map(String::as_str), Some("Titled"));
+
+        // Neither `images` nor `cleanup` ran
+        assert_eq!(server.hits("/photo.png"), 0);
+        let saved = std::fs::read_to_string(outputs.path().join("notes/Post.html")).unwrap();
+        assert!(saved.contains(r#"<img src="/photo.png">"#) && saved.contains("<!-- note -->"));
+    }
+}
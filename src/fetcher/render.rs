@@ -0,0 +1,441 @@
+use crate::fetcher::charset::decode_html;
+use crate::fetcher::http::{HttpResponse, HttpSession};
+use crate::fetcher::scheduler::host_of;
+use crate::model::Post;
+use anyhow::Result;
+use bytes::Bytes;
+use encoding_rs::Encoding;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What to wait for before capturing a rendered page
+#[derive(Debug, Clone, PartialEq)]
+pub enum WaitCondition {
+    /// Capture as soon as the document has loaded
+    Load,
+    /// Wait until no new network requests have started for a short quiet period
+    NetworkIdle,
+    /// Wait until an element matching the CSS selector exists
+    Selector(String),
+    /// Wait a fixed time after load
+    Delay(Duration),
+}
+
+impl FromStr for WaitCondition {
+    type Err = String;
+
+    /// Parse `load`, `network-idle`, `selector:<CSS>` or `delay:<MS>`
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.split_once(':') {
+            None if s == "load" => Ok(WaitCondition::Load),
+            None if s == "network-idle" => Ok(WaitCondition::NetworkIdle),
+            Some(("selector", css)) if !css.is_empty() => Ok(WaitCondition::Selector(css.to_string())),
+            Some(("delay", ms)) => ms
+                .parse::<u64>()
+                .map(|ms| WaitCondition::Delay(Duration::from_millis(ms)))
+                .map_err(|_| format!("invalid delay `{}`", ms)),
+            _ => Err(format!(
+                "unknown wait condition `{}` (expected load, network-idle, selector:<CSS> or delay:<MS>)",
+                s
+            )),
+        }
+    }
+}
+
+/// A page response already opened by the downloader to check its content type
+#[derive(Debug)]
+pub struct OpenedPage {
+    pub response: HttpResponse,
+    /// First chunk of the body, already consumed from `response`
+    pub head: Bytes,
+    pub content_type: Option<String>,
+}
+
+/// Everything a renderer needs to produce one page
+#[derive(Debug)]
+pub struct RenderRequest<'a> {
+    pub url: &'a str,
+    pub http: &'a HttpSession,
+    /// Plain HTTP response for the page, if the downloader already opened one
+    pub opened: Option<OpenedPage>,
+    pub fallback_encoding: &'static Encoding,
+}
+
+/// Final HTML of a page, decoded to UTF-8
+#[derive(Debug, Clone)]
+pub struct RenderedPage {
+    pub status: u16,
+    /// URL after redirects
+    pub final_url: String,
+    pub html: String,
+}
+
+/// Turns a page URL into HTML.
+///
+/// Implementations must be cheap to share between concurrent downloads. Renderers that load
+/// pages themselves are not sent the plain HTTP response, so a fake renderer registered with
+/// [`ArchiveBuilder::renderer`](crate::archive::ArchiveBuilder::renderer) runs the whole
+/// download pipeline without network or browser:
+///
+/// ```
+/// use futures::future::BoxFuture;
+/// use hyfetcher::fetcher::render::{RenderRequest, RenderedPage, Renderer};
+/// use hyfetcher::model::Post;
+/// use hyfetcher::Archive;
+///
+/// #[derive(Debug)]
+/// struct FixedRenderer(&'static str);
+///
+/// impl Renderer for FixedRenderer {
+///     fn name(&self) -> &'static str {
+///         "fixed"
+///     }
+///
+///     fn render<'a>(&'a self, request: RenderRequest<'a>) -> BoxFuture<'a, anyhow::Result<RenderedPage>> {
+///         Box::pin(async move {
+///             Ok(RenderedPage {
+///                 status: 200,
+///                 final_url: request.url.to_string(),
+///                 html: self.0.to_string(),
+///             })
+///         })
+///     }
+/// }
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> anyhow::Result<()> {
+/// # let outputs = tempfile::tempdir()?;
+/// // Nothing listens on port 9: the page never goes over the network
+/// let post = Post::new(
+///     "http://127.0.0.1:9/hello.html".to_string(),
+///     "Hello".to_string(),
+///     "notes".to_string(),
+///     String::new(),
+///     String::new(),
+/// );
+/// let archive = Archive::builder()
+///     .renderer(FixedRenderer("<html><head><title>Hello</title></head><body>Hi</body></html>"))
+///     .settings(hyfetcher::config::Settings { renderer: Some("fixed".to_string()), ..Default::default() })
+///     .posts([post])
+///     .outputs_dir(outputs.path())
+///     .build()?;
+/// let report = archive.run().await?;
+/// assert_eq!(report.totals.completed, 1);
+/// let saved = std::fs::read_to_string(outputs.path().join("notes/Hello.html"))?;
+/// assert!(saved.contains("<body>Hi</body>"));
+/// # Ok(())
+/// # }
+/// ```
+pub trait Renderer: Send + Sync + fmt::Debug {
+    fn name(&self) -> &'static str;
+
+    /// Whether the downloader opens the page over HTTP first and passes it in
+    /// [`RenderRequest::opened`].
+    ///
+    /// That response also tells documents such as PDFs apart from pages. Renderers that load
+    /// the page themselves keep the default `false`, and everything they return is saved as HTML.
+    fn needs_response(&self) -> bool {
+        false
+    }
+
+    fn render<'a>(&'a self, request: RenderRequest<'a>) -> BoxFuture<'a, Result<RenderedPage>>;
+}
+
+/// Plain HTTP renderer: the server's HTML as-is, without running scripts
+#[derive(Debug, Default)]
+pub struct HttpRenderer;
+
+impl Renderer for HttpRenderer {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    fn needs_response(&self) -> bool {
+        true
+    }
+
+    fn render<'a>(&'a self, request: RenderRequest<'a>) -> BoxFuture<'a, Result<RenderedPage>> {
+        Box::pin(async move {
+            let (mut response, mut bytes, content_type) = match request.opened {
+                Some(opened) => (opened.response, opened.head.to_vec(), opened.content_type),
+                None => {
+                    let response = request.http.get(request.url).await?;
+                    let content_type = response
+                        .headers()
+                        .get(reqwest::header::CONTENT_TYPE)
+                        .and_then(|v| v.to_str().ok())
+                        .map(str::to_string);
+                    (response, Vec::new(), content_type)
+                }
+            };
+            let status = response.status().as_u16();
+            let final_url = response.url().to_string();
+            while let Some(chunk) = response.chunk().await? {
+                bytes.extend_from_slice(&chunk);
+            }
+            let (html, _) = decode_html(&bytes, content_type.as_deref(), request.fallback_encoding);
+            Ok(RenderedPage { status, final_url, html })
+        })
+    }
+}
+
+/// Create a renderer by name (`http`, or `chromium` with the `js_render` feature)
+pub fn renderer_by_name(name: &str, wait: &WaitCondition) -> Result<Arc<dyn Renderer>> {
+    match name {
+        "http" => Ok(Arc::new(HttpRenderer)),
+        #[cfg(feature = "js_render")]
+        "chromium" => Ok(Arc::new(chromium::ChromiumRenderer::new(wait.clone()))),
+        #[cfg(not(feature = "js_render"))]
+        "chromium" => {
+            let _ = wait;
+            anyhow::bail!("renderer `chromium` requires building with `--features js_render`")
+        }
+        other => anyhow::bail!("unknown renderer `{}` (expected http or chromium)", other),
+    }
+}
+
+/// Which renderer handles which post: per domain, then per category, then the default
+#[derive(Debug, Clone)]
+pub struct RendererSelection {
+    default: Arc<dyn Renderer>,
+    by_domain: HashMap<String, Arc<dyn Renderer>>,
+    by_category: HashMap<String, Arc<dyn Renderer>>,
+}
+
+impl Default for RendererSelection {
+    fn default() -> Self {
+        Self::new(Arc::new(HttpRenderer))
+    }
+}
+
+impl RendererSelection {
+    pub fn new(default: Arc<dyn Renderer>) -> Self {
+        Self {
+            default,
+            by_domain: HashMap::new(),
+            by_category: HashMap::new(),
+        }
+    }
+
+    /// Use `renderer` for a domain and its subdomains
+    pub fn with_domain(mut self, domain: &str, renderer: Arc<dyn Renderer>) -> Self {
+        self.by_domain.insert(domain.to_lowercase(), renderer);
+        self
+    }
+
+    pub fn with_category(mut self, category: &str, renderer: Arc<dyn Renderer>) -> Self {
+        self.by_category.insert(category.to_string(), renderer);
+        self
+    }
+
+    pub fn select(&self, post: &Post) -> &Arc<dyn Renderer> {
+        let host = host_of(&post.url);
+        self.by_domain
+            .iter()
+            .filter(|(domain, _)| host == domain.as_str() || host.ends_with(&format!(".{}", domain)))
+            .max_by_key(|(domain, _)| domain.len())
+            .map(|(_, renderer)| renderer)
+            .or_else(|| self.by_category.get(&post.category))
+            .unwrap_or(&self.default)
+    }
+}
+
+#[cfg(feature = "js_render")]
+mod chromium {
+    use super::{RenderRequest, RenderedPage, Renderer, WaitCondition};
+    use crate::fetcher::http::HttpSession;
+    use crate::fetcher::retry::FetchError;
+    use anyhow::{Context, Result};
+    use chromiumoxide::cdp::browser_protocol::network::{CookieParam, SetCookiesParams};
+    use chromiumoxide::{Browser, BrowserConfig, Page};
+    use futures::StreamExt;
+    use futures::future::BoxFuture;
+    use std::time::Duration;
+    use tokio::sync::OnceCell;
+    use tokio::time::{Instant, sleep};
+
+    /// Longest time spent waiting for a wait condition
+    const RENDER_TIMEOUT: Duration = Duration::from_secs(30);
+    const POLL_INTERVAL: Duration = Duration::from_millis(250);
+    /// Polls without new network requests before the page counts as idle
+    const IDLE_POLLS: u32 = 3;
+
+    /// Command-line switches giving the browser the client's network settings, as far as
+    /// Chromium supports them; warns about the rest
+    fn browser_args(http: &HttpSession) -> Vec<String> {
+        let client = http.client();
+        let mut args = Vec::new();
+        if let Some(rules) = client.proxies() {
+            // Chromium has one proxy for every page, plus hosts that bypass it
+            if let Some(proxy) = &rules.default {
+                let scheme = if proxy.scheme() == "socks5h" { "socks5" } else { proxy.scheme() };
+                let host = proxy.host_str().unwrap_or_default();
+                match proxy.port_or_known_default() {
+                    Some(port) => args.push(format!("--proxy-server={}://{}:{}", scheme, host, port)),
+                    None => args.push(format!("--proxy-server={}://{}", scheme, host)),
+                }
+                if !proxy.username().is_empty() {
+                    http.events().warn("Chromium does not support proxy credentials; pages it renders may fail");
+                }
+            }
+            let direct: Vec<String> = rules
+                .by_domain
+                .iter()
+                .filter(|(_, proxy)| proxy.is_none())
+                .flat_map(|(domain, _)| [domain.clone(), format!("*.{}", domain)])
+                .collect();
+            if rules.default.is_some() && !direct.is_empty() {
+                args.push(format!("--proxy-bypass-list={}", direct.join(";")));
+            }
+            let routed: Vec<&str> = rules
+                .by_domain
+                .iter()
+                .filter(|(_, proxy)| proxy.is_some())
+                .map(|(domain, _)| domain.as_str())
+                .collect();
+            if !routed.is_empty() {
+                http.events().warn(format!(
+                    "Chromium ignores the per-domain proxies of {} and uses the default proxy",
+                    routed.join(", ")
+                ));
+            }
+        }
+        if !client.insecure_hosts().is_empty() {
+            http.events().warn("Chromium still verifies the certificates of insecure hosts");
+        }
+        if client.has_custom_ca() {
+            http.events().warn("Chromium only trusts the system certificates, not --ca-cert");
+        }
+        args
+    }
+
+    /// Browser tab that is closed when dropped, whether rendering succeeded, failed or was cancelled
+    struct OpenTab(Option<Page>);
+
+    impl Drop for OpenTab {
+        fn drop(&mut self) {
+            if let Some(page) = self.0.take()
+                && let Ok(runtime) = tokio::runtime::Handle::try_current()
+            {
+                runtime.spawn(async move {
+                    let _ = page.close().await;
+                });
+            }
+        }
+    }
+
+    /// Headless Chromium renderer; the browser is launched on first use and shared
+    #[derive(Debug)]
+    pub struct ChromiumRenderer {
+        wait: WaitCondition,
+        browser: OnceCell<Browser>,
+    }
+
+    impl ChromiumRenderer {
+        pub fn new(wait: WaitCondition) -> Self {
+            Self { wait, browser: OnceCell::new() }
+        }
+
+        async fn browser(&self, http: &HttpSession) -> Result<&Browser> {
+            self.browser
+                .get_or_try_init(|| async {
+                    let mut config = BrowserConfig::builder();
+                    for arg in browser_args(http) {
+                        config = config.arg(arg);
+                    }
+                    let config = config.build().map_err(anyhow::Error::msg)?;
+                    let (browser, mut handler) = Browser::launch(config)
+                        .await
+                        .context("Failed to launch Chromium")?;
+                    tokio::spawn(async move { while handler.next().await.is_some() {} });
+                    Ok(browser)
+                })
+                .await
+        }
+
+        async fn wait_until_ready(&self, page: &Page) -> Result<()> {
+            let deadline = Instant::now() + RENDER_TIMEOUT;
+            match &self.wait {
+                WaitCondition::Load => {}
+                WaitCondition::Delay(delay) => sleep(*delay).await,
+                WaitCondition::Selector(css) => {
+                    while page.find_element(css.as_str()).await.is_err() {
+                        if Instant::now() >= deadline {
+                            anyhow::bail!("timed out waiting for selector `{}`", css);
+                        }
+                        sleep(POLL_INTERVAL).await;
+                    }
+                }
+                WaitCondition::NetworkIdle => {
+                    let mut last = -1i64;
+                    let mut quiet = 0;
+                    while quiet < IDLE_POLLS && Instant::now() < deadline {
+                        let count: i64 = page
+                            .evaluate("performance.getEntriesByType('resource').length")
+                            .await?
+                            .into_value()?;
+                        quiet = if count == last { quiet + 1 } else { 0 };
+                        last = count;
+                        sleep(POLL_INTERVAL).await;
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl Renderer for ChromiumRenderer {
+        fn name(&self) -> &'static str {
+            "chromium"
+        }
+
+        fn render<'a>(&'a self, request: RenderRequest<'a>) -> BoxFuture<'a, Result<RenderedPage>> {
+            Box::pin(async move {
+                let browser = self.browser(request.http).await?;
+                let _permit = request.http.scheduler().acquire(request.url).await;
+                let tab = OpenTab(Some(browser.new_page("about:blank").await?));
+                let page = tab.0.as_ref().expect("tab is open");
+
+                // Send the session's cookies, as the HTTP client would
+                if let Some(jar) = request.http.cookie_jar()
+                    && let Ok(url) = url::Url::parse(request.url)
+                {
+                    let cookies: Vec<CookieParam> = jar
+                        .request_cookies(&url)
+                        .into_iter()
+                        .map(|(name, value)| {
+                            let mut cookie = CookieParam::new(name, value);
+                            cookie.url = Some(request.url.to_string());
+                            cookie
+                        })
+                        .collect();
+                    if !cookies.is_empty() {
+                        page.execute(SetCookiesParams::new(cookies)).await?;
+                    }
+                }
+
+                page.goto(request.url).await?;
+                page.wait_for_navigation().await?;
+                self.wait_until_ready(page).await?;
+
+                let status: u16 = page
+                    .evaluate("performance.getEntriesByType('navigation')[0]?.responseStatus || 200")
+                    .await?
+                    .into_value()?;
+                let final_url = page.url().await?.unwrap_or_else(|| request.url.to_string());
+                let html = page.content().await?;
+                drop(tab);
+
+                if status >= 400 {
+                    let status = reqwest::StatusCode::from_u16(status)?;
+                    return Err(FetchError::Status { url: request.url.to_string(), status, retry_after: None }.into());
+                }
+                Ok(RenderedPage { status, final_url, html })
+            })
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use crate::fetcher::client::DnsError;
+use rand::Rng;
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use std::error::Error as StdError;
+use std::io;
+use std::time::Duration;
+
+/// Retry settings shared by page, image and video requests
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled on each further attempt
+    pub base_delay: Duration,
+    /// Upper bound for a single backoff (also caps `Retry-After`)
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Jittered exponential backoff before retry number `attempt` (starting at 1)
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        // "Equal jitter": half fixed, half random, so retries of many posts spread out
+        let half = exp / 2;
+        let jitter = rand::thread_rng().gen_range(0..=half.as_millis() as u64);
+        half + Duration::from_millis(jitter)
+    }
+
+    /// Delay before retrying after `error`, preferring the server's `Retry-After`
+    pub fn delay_for(&self, error: &FetchError, attempt: u32) -> Duration {
+        match error {
+            FetchError::Status { retry_after: Some(wait), .. } => (*wait).min(self.max_delay),
+            _ => self.backoff(attempt),
+        }
+    }
+}
+
+/// Error of a single network request, classified as retryable or permanent
+#[derive(Debug, thiserror::Error)]
+pub enum FetchError {
+    #[error("HTTP {status} for {url}")]
+    Status {
+        url: String,
+        status: StatusCode,
+        retry_after: Option<Duration>,
+    },
+    #[error("request to {url} failed")]
+    Request {
+        url: String,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("disallowed by robots.txt: {url}")]
+    RobotsDisallowed { url: String },
+    #[error("error page served with HTTP {status} for {url}: {reason}")]
+    ErrorPage {
+        url: String,
+        status: StatusCode,
+        reason: String,
+    },
+    #[error("response body of {url} exceeds the limit of {limit} bytes")]
+    BodyTooLarge { url: String, limit: u64 },
+}
+
+impl FetchError {
+    pub fn from_status(url: &str, status: StatusCode, headers: &HeaderMap) -> Self {
+        let retry_after = match status {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => parse_retry_after(headers),
+            _ => None,
+        };
+        FetchError::Status { url: url.to_string(), status, retry_after }
+    }
+
+    /// Timeouts, connection resets, 408/429 and 5xx are worth retrying; 4xx and DNS failures are not
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            FetchError::Status { status, .. } => {
+                matches!(
+                    *status,
+                    StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS
+                ) || (status.is_server_error()
+                    && !matches!(
+                        *status,
+                        StatusCode::NOT_IMPLEMENTED | StatusCode::HTTP_VERSION_NOT_SUPPORTED
+                    ))
+            }
+            FetchError::Request { source, .. } => {
+                !is_dns_error(source)
+                    && (source.is_timeout() || source.is_connect() || source.is_request() || is_interrupted(source))
+            }
+            FetchError::RobotsDisallowed { .. } | FetchError::ErrorPage { .. } | FetchError::BodyTooLarge { .. } => {
+                false
+            }
+        }
+    }
+
+    /// HTTP status of the failed response, if the server answered
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            FetchError::Status { status, .. } | FetchError::ErrorPage { status, .. } => Some(*status),
+            FetchError::Request { .. } | FetchError::RobotsDisallowed { .. } | FetchError::BodyTooLarge { .. } => None,
+        }
+    }
+}
+
+/// Whether the error chain holds a failed host name lookup of the client's resolver
+fn is_dns_error(error: &reqwest::Error) -> bool {
+    let mut current: Option<&dyn StdError> = Some(error);
+    while let Some(err) = current {
+        if err.is::<DnsError>() {
+            return true;
+        }
+        current = err.source();
+    }
+    false
+}
+
+/// Whether the error chain holds a body read cut short, e.g. by a reset connection; the
+/// decompression layer reports those as decode errors
+fn is_interrupted(error: &reqwest::Error) -> bool {
+    let mut current: Option<&dyn StdError> = Some(error);
+    while let Some(err) = current {
+        if let Some(err) = err.downcast_ref::<reqwest::Error>()
+            && (err.is_body() || err.is_timeout())
+        {
+            return true;
+        }
+        if let Some(err) = err.downcast_ref::<io::Error>()
+            && matches!(
+                err.kind(),
+                io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::TimedOut
+            )
+        {
+            return true;
+        }
+        current = err.source();
+    }
+    false
+}
+
+/// Parse `Retry-After` given either as delay seconds or as an HTTP date
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let wait = date.signed_duration_since(chrono::Utc::now());
+    Some(wait.to_std().unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetcher::client::ClientOptions;
+    use reqwest::header::HeaderValue;
+
+    fn status_error(status: StatusCode) -> FetchError {
+        FetchError::from_status("https://example.com/", status, &HeaderMap::new())
+    }
+
+    #[test]
+    fn classifies_statuses() {
+        for status in [408, 429, 500, 502, 503, 504] {
+            assert!(status_error(StatusCode::from_u16(status).unwrap()).is_retryable(), "{}", status);
+        }
+        for status in [400, 401, 403, 404, 410, 501, 505] {
+            assert!(!status_error(StatusCode::from_u16(status).unwrap()).is_retryable(), "{}", status);
+        }
+        let page = FetchError::ErrorPage {
+            url: "https://example.com/".to_string(),
+            status: StatusCode::OK,
+            reason: "soft 404".to_string(),
+        };
+        assert!(!page.is_retryable());
+        assert_eq!(page.status(), Some(StatusCode::OK));
+    }
+
+    #[tokio::test]
+    async fn refused_connections_are_retried_but_invalid_requests_are_not() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        let refused = reqwest::get(&url).await.unwrap_err();
+        assert!(FetchError::Request { url: url.clone(), source: refused }.is_retryable());
+
+        let invalid = reqwest::get("http://exa mple.com/").await.unwrap_err();
+        assert!(!FetchError::Request { url, source: invalid }.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn failed_lookups_are_not_retried() {
+        let client = ClientOptions::default().build().unwrap();
+        let url = "http://no-such-host.invalid/";
+        let error = client.for_url(url).get(url).send().await.unwrap_err();
+        assert!(is_dns_error(&error));
+        assert!(!FetchError::Request { url: url.to_string(), source: error }.is_retryable());
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter_up_to_the_limit() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(350),
+        };
+        for (attempt, full) in [(1, 100), (2, 200), (3, 350), (10, 350)] {
+            let delay = policy.backoff(attempt).as_millis() as u64;
+            assert!((full / 2..=full).contains(&delay), "attempt {}: {} ms", attempt, delay);
+        }
+    }
+
+    #[test]
+    fn retry_after_is_honored_and_capped() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("30"));
+        let error = FetchError::from_status("https://example.com/", StatusCode::TOO_MANY_REQUESTS, &headers);
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.delay_for(&error, 1), Duration::from_secs(30));
+        let short = RetryPolicy { max_delay: Duration::from_secs(10), ..RetryPolicy::default() };
+        assert_eq!(short.delay_for(&error, 1), Duration::from_secs(10));
+
+        // Only 429 and 503 carry a meaningful Retry-After
+        let error = FetchError::from_status("https://example.com/", StatusCode::BAD_GATEWAY, &headers);
+        assert!(matches!(error, FetchError::Status { retry_after: None, .. }));
+    }
+
+    #[test]
+    fn parses_retry_after_dates_and_ignores_garbage() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(parse_retry_after(&headers), Some(Duration::ZERO));
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("later"));
+        assert_eq!(parse_retry_after(&headers), None);
+    }
+}
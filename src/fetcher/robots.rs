@@ -0,0 +1,332 @@
+use crate::fetcher::http::HttpSession;
+use crate::fetcher::retry::FetchError;
+use crate::model::sanitize_filename;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use url::Url;
+
+/// Product token of a `User-Agent` header, matched against `User-agent` lines: the name in a
+/// `(compatible; Name/1.0)` comment, otherwise the first product name
+///
+/// ```
+/// use hyfetcher::fetcher::robots::robots_agent;
+///
+/// assert_eq!(robots_agent("Mozilla/5.0 (compatible; hyfetcher/0.1; +https://example.com)"), "hyfetcher");
+/// assert_eq!(robots_agent("ArchiveBot/2.3 (+https://example.com/bot)"), "ArchiveBot");
+/// ```
+pub fn robots_agent(user_agent: &str) -> String {
+    let compatible = user_agent
+        .split_once("(compatible;")
+        .and_then(|(_, rest)| rest.split([';', ')']).next())
+        .map(str::trim)
+        .filter(|product| !product.is_empty());
+    let product = compatible.or_else(|| user_agent.split_whitespace().next()).unwrap_or_default();
+    product.split('/').next().unwrap_or_default().to_string()
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+#[derive(Debug, Default)]
+struct Group {
+    agents: Vec<String>,
+    rules: Vec<Rule>,
+    crawl_delay: Option<f64>,
+}
+
+/// Rules of one robots.txt that apply to our agent
+#[derive(Debug, Clone, Default)]
+pub struct RobotsRules {
+    rules: Vec<Rule>,
+    crawl_delay: Option<Duration>,
+}
+
+impl RobotsRules {
+    /// Rules that allow everything (robots.txt missing)
+    pub fn allow_all() -> Self {
+        Self::default()
+    }
+
+    /// Rules that disallow everything (robots.txt unreachable)
+    pub fn disallow_all() -> Self {
+        Self {
+            rules: vec![Rule { allow: false, pattern: "/".to_string() }],
+            crawl_delay: None,
+        }
+    }
+
+    /// Parse a robots.txt body, keeping the groups for `agent` (or `*` if none match)
+    pub fn parse(content: &str, agent: &str) -> Self {
+        let agent = agent.to_lowercase();
+        let mut groups: Vec<Group> = Vec::new();
+        let mut current = Group::default();
+        let mut in_agent_lines = false;
+
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let key = key.trim().to_lowercase();
+            let value = value.trim();
+            match key.as_str() {
+                "user-agent" => {
+                    if !in_agent_lines {
+                        // Rules before the first User-agent line belong to no group
+                        let previous = std::mem::take(&mut current);
+                        if !previous.agents.is_empty() {
+                            groups.push(previous);
+                        }
+                    }
+                    current.agents.push(value.to_lowercase());
+                    in_agent_lines = true;
+                }
+                "allow" | "disallow" => {
+                    in_agent_lines = false;
+                    if !value.is_empty() {
+                        current.rules.push(Rule {
+                            allow: key == "allow",
+                            pattern: value.to_string(),
+                        });
+                    }
+                }
+                "crawl-delay" => {
+                    in_agent_lines = false;
+                    current.crawl_delay = value.parse::<f64>().ok().filter(|d| *d >= 0.0);
+                }
+                _ => {}
+            }
+        }
+        if !current.agents.is_empty() {
+            groups.push(current);
+        }
+
+        let specific: Vec<&Group> = groups
+            .iter()
+            .filter(|g| g.agents.iter().any(|a| a != "*" && agent.contains(a.as_str())))
+            .collect();
+        let selected = if specific.is_empty() {
+            groups.iter().filter(|g| g.agents.iter().any(|a| a == "*")).collect()
+        } else {
+            specific
+        };
+
+        Self {
+            rules: selected.iter().flat_map(|g| g.rules.iter().cloned()).collect(),
+            crawl_delay: selected
+                .iter()
+                .filter_map(|g| g.crawl_delay)
+                .reduce(f64::max)
+                .map(Duration::from_secs_f64),
+        }
+    }
+
+    /// Whether a path (with query) may be fetched; the longest match wins, `Allow` on ties
+    pub fn is_allowed(&self, path: &str) -> bool {
+        let mut best: Option<(usize, bool)> = None;
+        for rule in &self.rules {
+            if pattern_matches(&rule.pattern, path) {
+                let len = rule.pattern.len();
+                best = match best {
+                    Some((best_len, best_allow))
+                        if best_len > len || (best_len == len && best_allow) =>
+                    {
+                        Some((best_len, best_allow))
+                    }
+                    _ => Some((len, rule.allow)),
+                };
+            }
+        }
+        best.map(|(_, allow)| allow).unwrap_or(true)
+    }
+
+    pub fn crawl_delay(&self) -> Option<Duration> {
+        self.crawl_delay
+    }
+}
+
+/// Match a robots.txt path pattern supporting `*` wildcards and a trailing `$` anchor
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(p) => (p, true),
+        None => (pattern, false),
+    };
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let Some(rest) = path.strip_prefix(parts[0]) else {
+        return false;
+    };
+    let mut rest = rest;
+    for (i, part) in parts.iter().enumerate().skip(1) {
+        let is_last = i == parts.len() - 1;
+        if is_last && anchored {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    !anchored || rest.is_empty()
+}
+
+/// Per-origin robots.txt cache; fetched copies are kept on disk as evidence
+#[derive(Debug)]
+pub struct RobotsCache {
+    agent: String,
+    save_dir: Option<PathBuf>,
+    origins: Mutex<HashMap<String, Arc<OnceCell<Arc<RobotsRules>>>>>,
+}
+
+impl RobotsCache {
+    pub fn new(agent: &str, save_dir: Option<PathBuf>) -> Self {
+        Self {
+            agent: agent.to_string(),
+            save_dir,
+            origins: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fail with `RobotsDisallowed` if robots.txt forbids fetching `url`
+    pub async fn check(&self, url: &str, http: &HttpSession) -> Result<(), FetchError> {
+        let Ok(parsed) = Url::parse(url) else {
+            return Ok(());
+        };
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Ok(());
+        }
+        let rules = self.rules_for(&parsed, http).await;
+        let mut path = parsed.path().to_string();
+        if let Some(query) = parsed.query() {
+            path.push('?');
+            path.push_str(query);
+        }
+        if rules.is_allowed(&path) {
+            Ok(())
+        } else {
+            Err(FetchError::RobotsDisallowed { url: url.to_string() })
+        }
+    }
+
+    async fn rules_for(&self, url: &Url, http: &HttpSession) -> Arc<RobotsRules> {
+        let origin = url.origin().ascii_serialization();
+        let cell = self
+            .origins
+            .lock()
+            .unwrap()
+            .entry(origin.clone())
+            .or_default()
+            .clone();
+        cell.get_or_init(|| async {
+            let robots_url = format!("{}/robots.txt", origin);
+            let rules = match http.fetch(&robots_url).await {
+                Ok(resp) => match resp.text().await {
+                    Ok(body) => {
+                        self.save_copy(url, &body, http);
+                        RobotsRules::parse(&body, &self.agent)
+                    }
+                    Err(_) => RobotsRules::disallow_all(),
+                },
+                // A missing robots.txt means no restrictions; an unreachable one means full disallow
+                Err(e) if e.status().is_some_and(|s| s.is_client_error()) => RobotsRules::allow_all(),
+                Err(e) => {
+                    http.events().warn(format!("robots.txt unreachable, disallowing {}: {}", origin, e));
+                    RobotsRules::disallow_all()
+                }
+            };
+            if let Some(delay) = rules.crawl_delay() {
+                http.scheduler().apply_crawl_delay(url.as_str(), delay);
+            }
+            Arc::new(rules)
+        })
+        .await
+        .clone()
+    }
+
+    fn save_copy(&self, url: &Url, body: &str, http: &HttpSession) {
+        let Some(dir) = &self.save_dir else {
+            return;
+        };
+        let name = match url.port() {
+            Some(port) => format!("{}_{}.txt", url.host_str().unwrap_or("unknown"), port),
+            None => format!("{}.txt", url.host_str().unwrap_or("unknown")),
+        };
+        let saved = std::fs::create_dir_all(dir)
+            .and_then(|_| std::fs::write(dir.join(sanitize_filename(&name)), body));
+        if let Err(e) = saved {
+            http.events().warn(format!("Failed to save robots.txt copy for {}: {}", url, e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROBOTS: &str = "\
+# Rules before any group are ignored
+Disallow: /everything
+
+User-agent: *
+Disallow: /private
+Crawl-delay: 5
+
+User-agent: OtherBot
+User-agent: hyfetcher
+Disallow: /drafts
+Allow: /drafts/public
+Disallow: /*.pdf$
+Crawl-delay: 1.5
+";
+
+    #[test]
+    fn specific_group_replaces_the_wildcard_group() {
+        let rules = RobotsRules::parse(ROBOTS, "hyfetcher");
+        assert!(rules.is_allowed("/private/page"));
+        assert!(!rules.is_allowed("/drafts/post"));
+        assert!(rules.is_allowed("/everything"));
+        assert_eq!(rules.crawl_delay(), Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn other_agents_fall_back_to_the_wildcard_group() {
+        let rules = RobotsRules::parse(ROBOTS, "somebot");
+        assert!(!rules.is_allowed("/private/page"));
+        assert!(rules.is_allowed("/drafts/post"));
+        assert_eq!(rules.crawl_delay(), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn longest_match_wins_and_allow_wins_ties() {
+        let rules = RobotsRules::parse(ROBOTS, "hyfetcher");
+        assert!(rules.is_allowed("/drafts/public/post"));
+        let tie = RobotsRules::parse("User-agent: *\nDisallow: /a\nAllow: /a\n", "hyfetcher");
+        assert!(tie.is_allowed("/a/b"));
+    }
+
+    #[test]
+    fn wildcards_and_end_anchor() {
+        assert!(pattern_matches("/*.pdf$", "/files/report.pdf"));
+        assert!(!pattern_matches("/*.pdf$", "/files/report.pdf?download=1"));
+        assert!(pattern_matches("/*.pdf", "/files/report.pdf?download=1"));
+        assert!(pattern_matches("/a*b*c", "/a-x-b-y-c-z"));
+        assert!(!pattern_matches("/a*b*c", "/a-x-c-y-b"));
+        assert!(pattern_matches("/exact$", "/exact"));
+        assert!(!pattern_matches("/exact$", "/exact/more"));
+        assert!(!pattern_matches("/private", "/public/private"));
+    }
+
+    #[test]
+    fn empty_disallow_and_malformed_lines_allow_everything() {
+        let rules = RobotsRules::parse("User-agent: *\nDisallow:\nnonsense line\nCrawl-delay: soon\n", "hyfetcher");
+        assert!(rules.is_allowed("/anything"));
+        assert_eq!(rules.crawl_delay(), None);
+        assert!(!RobotsRules::disallow_all().is_allowed("/"));
+        assert!(RobotsRules::allow_all().is_allowed("/"));
+    }
+}
@@ -0,0 +1,300 @@
+use crate::model::Post;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use url::Url;
+
+/// Politeness limits applied to a single host
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HostLimits {
+    /// Maximum number of requests in flight to the host
+    pub max_in_flight: usize,
+    /// Maximum request starts per second, unlimited if `None`
+    pub requests_per_second: Option<f64>,
+}
+
+impl Default for HostLimits {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 2,
+            requests_per_second: None,
+        }
+    }
+}
+
+/// Parse a `--host-limit` value of the form `DOMAIN=CONCURRENCY[/RPS]`
+pub fn parse_host_limit(s: &str) -> Result<(String, HostLimits), String> {
+    let (domain, spec) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected DOMAIN=CONCURRENCY[/RPS], got `{}`", s))?;
+    let (conc, rps) = match spec.split_once('/') {
+        Some((conc, rps)) => (conc, Some(rps)),
+        None => (spec, None),
+    };
+    let max_in_flight = conc
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|n| *n > 0)
+        .ok_or_else(|| format!("invalid concurrency `{}` for {}", conc, domain))?;
+    let requests_per_second = match rps {
+        Some(rps) => Some(
+            rps.trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|r| *r > 0.0)
+                .ok_or_else(|| format!("invalid requests per second `{}` for {}", rps, domain))?,
+        ),
+        None => None,
+    };
+    Ok((
+        domain.trim().to_lowercase(),
+        HostLimits { max_in_flight, requests_per_second },
+    ))
+}
+
+/// Host name of a URL, lowercased; empty for unparsable URLs
+pub fn host_of(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.to_lowercase()))
+        .unwrap_or_default()
+}
+
+#[derive(Debug)]
+struct HostState {
+    semaphore: Arc<Semaphore>,
+    max_in_flight: usize,
+    /// Requests queued for a free slot
+    waiting: AtomicUsize,
+    min_interval: Mutex<Duration>,
+    next_start: tokio::sync::Mutex<Instant>,
+}
+
+/// Counts a queued request, also when the waiting future is dropped
+struct WaitingGuard<'a>(&'a AtomicUsize);
+
+impl<'a> WaitingGuard<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for WaitingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Current load on one host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostActivity {
+    pub host: String,
+    pub in_flight: usize,
+    pub waiting: usize,
+}
+
+/// Slot for one request to a host, released on drop
+#[derive(Debug)]
+pub struct HostPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+/// Limits in-flight requests and request rate per host, for pages and assets alike
+#[derive(Debug)]
+pub struct HostScheduler {
+    default_limits: HostLimits,
+    overrides: HashMap<String, HostLimits>,
+    hosts: Mutex<HashMap<String, Arc<HostState>>>,
+}
+
+impl HostScheduler {
+    pub fn new(default_limits: HostLimits, overrides: HashMap<String, HostLimits>) -> Self {
+        Self {
+            default_limits,
+            overrides,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Limits for a host; an override for `example.com` also covers `www.example.com`
+    pub fn limits_for(&self, host: &str) -> HostLimits {
+        self.overrides
+            .iter()
+            .filter(|(domain, _)| host == domain.as_str() || host.ends_with(&format!(".{}", domain)))
+            .max_by_key(|(domain, _)| domain.len())
+            .map(|(_, limits)| *limits)
+            .unwrap_or(self.default_limits)
+    }
+
+    fn state(&self, host: &str) -> Arc<HostState> {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts
+            .entry(host.to_string())
+            .or_insert_with(|| {
+                let limits = self.limits_for(host);
+                let max_in_flight = limits.max_in_flight.max(1);
+                Arc::new(HostState {
+                    semaphore: Arc::new(Semaphore::new(max_in_flight)),
+                    max_in_flight,
+                    waiting: AtomicUsize::new(0),
+                    min_interval: Mutex::new(
+                        limits
+                            .requests_per_second
+                            .map(|rps| Duration::from_secs_f64(1.0 / rps))
+                            .unwrap_or(Duration::ZERO),
+                    ),
+                    next_start: tokio::sync::Mutex::new(Instant::now()),
+                })
+            })
+            .clone()
+    }
+
+    /// Wait for a free slot on the URL's host and for its rate limit
+    pub async fn acquire(&self, url: &str) -> HostPermit {
+        let state = self.state(&host_of(url));
+        let waiting = WaitingGuard::new(&state.waiting);
+        let permit = state
+            .semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("host semaphore is never closed");
+        drop(waiting);
+        let min_interval = *state.min_interval.lock().unwrap();
+        if !min_interval.is_zero() {
+            let start = {
+                let mut next_start = state.next_start.lock().await;
+                let start = (*next_start).max(Instant::now());
+                *next_start = start + min_interval;
+                start
+            };
+            tokio::time::sleep_until(start).await;
+        }
+        HostPermit { _permit: permit }
+    }
+
+    /// In-flight and queued requests of every host seen so far that is busy, busiest first
+    pub fn snapshot(&self) -> Vec<HostActivity> {
+        let hosts = self.hosts.lock().unwrap();
+        let mut activity: Vec<HostActivity> = hosts
+            .iter()
+            .map(|(host, state)| HostActivity {
+                host: host.clone(),
+                in_flight: state.max_in_flight - state.semaphore.available_permits(),
+                waiting: state.waiting.load(Ordering::Relaxed),
+            })
+            .filter(|a| a.in_flight > 0 || a.waiting > 0)
+            .collect();
+        activity.sort_by(|a, b| (b.in_flight + b.waiting, &a.host).cmp(&(a.in_flight + a.waiting, &b.host)));
+        activity
+    }
+
+    /// Slow a host down to at least one request per `delay` (robots.txt `Crawl-delay`)
+    pub fn apply_crawl_delay(&self, url: &str, delay: Duration) {
+        let state = self.state(&host_of(url));
+        let mut min_interval = state.min_interval.lock().unwrap();
+        *min_interval = (*min_interval).max(delay);
+    }
+}
+
+/// Reorder posts round-robin by host so the global concurrency is spread across hosts
+pub fn interleave_by_host(posts: Vec<Post>) -> Vec<Post> {
+    let mut order = Vec::new();
+    let mut queues: HashMap<String, VecDeque<Post>> = HashMap::new();
+    for post in posts {
+        let host = host_of(&post.url);
+        if !queues.contains_key(&host) {
+            order.push(host.clone());
+        }
+        queues.entry(host).or_default().push_back(post);
+    }
+    let mut result = Vec::new();
+    while !queues.is_empty() {
+        for host in &order {
+            if let Some(queue) = queues.get_mut(host) {
+                if let Some(post) = queue.pop_front() {
+                    result.push(post);
+                }
+                if queue.is_empty() {
+                    queues.remove(host);
+                }
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::post;
+
+    #[test]
+    fn host_limits_are_parsed_and_validated() {
+        let limits = |max_in_flight, requests_per_second| HostLimits { max_in_flight, requests_per_second };
+        assert_eq!(parse_host_limit("Example.com=3"), Ok(("example.com".to_string(), limits(3, None))));
+        assert_eq!(parse_host_limit("example.com=1/0.5"), Ok(("example.com".to_string(), limits(1, Some(0.5)))));
+        let malformed = ["example.com", "example.com=0", "example.com=x", "example.com=2/0", "example.com=2/fast"];
+        for spec in malformed {
+            assert!(parse_host_limit(spec).is_err(), "{} should be rejected", spec);
+        }
+    }
+
+    #[test]
+    fn overrides_cover_subdomains() {
+        let (domain, limits) = parse_host_limit("example.com=5").unwrap();
+        let scheduler = HostScheduler::new(HostLimits::default(), HashMap::from([(domain, limits)]));
+        assert_eq!(scheduler.limits_for("www.example.com").max_in_flight, 5);
+        assert_eq!(scheduler.limits_for("notexample.com"), HostLimits::default());
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_the_host_limit_wait() {
+        let scheduler = HostScheduler::new(HostLimits { max_in_flight: 2, requests_per_second: None }, HashMap::new());
+        let first = scheduler.acquire("https://a.example/1").await;
+        let _second = scheduler.acquire("https://a.example/2").await;
+        // Other hosts have their own slots
+        let _other = scheduler.acquire("https://b.example/1").await;
+
+        let third = scheduler.acquire("https://a.example/3");
+        tokio::pin!(third);
+        assert!(tokio::time::timeout(Duration::from_millis(50), &mut third).await.is_err());
+        assert_eq!(
+            scheduler.snapshot()[0],
+            HostActivity { host: "a.example".to_string(), in_flight: 2, waiting: 1 }
+        );
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(1), third).await.expect("a slot was freed");
+    }
+
+    #[tokio::test]
+    async fn request_starts_are_spaced_by_the_rate_limit() {
+        let scheduler =
+            HostScheduler::new(HostLimits { max_in_flight: 4, requests_per_second: Some(20.0) }, HashMap::new());
+        let started = Instant::now();
+        for i in 0..3 {
+            scheduler.acquire(&format!("https://a.example/{}", i)).await;
+        }
+        // 50ms between starts, the first one immediate
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        scheduler.apply_crawl_delay("https://b.example/", Duration::from_millis(150));
+        let started = Instant::now();
+        scheduler.acquire("https://b.example/1").await;
+        scheduler.acquire("https://b.example/2").await;
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn posts_are_interleaved_round_robin_by_host() {
+        let urls = ["a.example/1", "a.example/2", "a.example/3", "b.example/1", "c.example/1", "b.example/2"];
+        let posts = urls.iter().map(|url| post(&format!("https://{}", url), "Post")).collect();
+        let order: Vec<String> = interleave_by_host(posts).into_iter().map(|post| post.url[8..].to_string()).collect();
+        assert_eq!(order, ["a.example/1", "b.example/1", "c.example/1", "a.example/2", "b.example/2", "a.example/3"]);
+    }
+}
@@ -0,0 +1,186 @@
+use crate::fetcher::assets::{download_assets, downloaded_bytes, resolve_asset_url, AssetJob};
+use crate::fetcher::dom;
+use crate::fetcher::processor::{PageContext, Processor};
+use anyhow::Result;
+use futures::future::BoxFuture;
+use scraper::{Html, Selector};
+use std::str::FromStr;
+use url::Url;
+
+/// Content-Security-Policy of neutralized pages: local files, inline code and `data:` URIs only
+pub const OFFLINE_CSP: &str = "default-src 'self' file: data: blob:; \
+    script-src 'self' file: data: blob: 'unsafe-inline' 'unsafe-eval'; \
+    style-src 'self' file: data: 'unsafe-inline'; \
+    connect-src 'none'; form-action 'none'";
+
+/// What happens to the JavaScript of saved pages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ScriptPolicy {
+    /// Remove scripts, event handler attributes and `javascript:` links
+    #[default]
+    Strip,
+    /// Keep scripts, with a Content-Security-Policy that blocks all network access
+    Neutralize,
+    /// Download external scripts next to the page, e.g. for MathJax or code highlighting
+    Localize,
+}
+
+impl FromStr for ScriptPolicy {
+    type Err = String;
+
+    /// Parse `strip`, `neutralize` or `localize`
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "strip" => Ok(ScriptPolicy::Strip),
+            "neutralize" => Ok(ScriptPolicy::Neutralize),
+            "localize" => Ok(ScriptPolicy::Localize),
+            _ => Err(format!("unknown script policy `{}` (expected strip, neutralize or localize)", s)),
+        }
+    }
+}
+
+/// Apply the post's script policy
+#[derive(Debug, Default)]
+pub struct Scripts;
+
+impl Processor for Scripts {
+    fn name(&self) -> &'static str {
+        "scripts"
+    }
+
+    fn process<'a>(&'a self, document: &'a mut Html, page: &'a mut PageContext) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            match page.options.scripts {
+                ScriptPolicy::Strip => strip_scripts(document),
+                ScriptPolicy::Neutralize => neutralize_scripts(document),
+                ScriptPolicy::Localize => localize_scripts(document, page).await?,
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Remove everything that runs code, keeping structured data such as JSON-LD.
+///
+/// ```
+/// use hyfetcher::fetcher::scripts::strip_scripts;
+/// use scraper::Html;
+///
+/// let mut document = Html::parse_document(
+///     r#"<script src="https://tracker.example/t.js"></script>
+///     <script type="application/ld+json">{"@type": "Article"}</script>
+///     <a href="javascript:void(0)" onclick="share()">Share</a><body onload="init()"></body>"#,
+/// );
+/// strip_scripts(&mut document);
+/// let html = document.html();
+/// assert!(!html.contains("tracker") && !html.contains("javascript:") && !html.contains("onclick"));
+/// assert!(html.contains("application/ld+json") && html.contains("<a>Share</a>"));
+/// ```
+pub fn strip_scripts(document: &mut Html) {
+    let scripts = Selector::parse("script").unwrap();
+    for id in dom::select_ids(document, &scripts) {
+        let data = dom::attr(document, id, "type")
+            .is_some_and(|kind| kind.trim().eq_ignore_ascii_case("application/ld+json"));
+        if !data {
+            dom::remove(document, id);
+        }
+    }
+    let all = Selector::parse("*").unwrap();
+    for id in dom::select_ids(document, &all) {
+        for name in dom::attr_names(document, id) {
+            let handler = name.len() > 2 && name.get(..2).is_some_and(|prefix| prefix.eq_ignore_ascii_case("on"));
+            let script_link = ["href", "src", "action", "formaction"].contains(&name.as_str())
+                && dom::attr(document, id, &name)
+                    .is_some_and(|value| value.trim_start().to_lowercase().starts_with("javascript:"));
+            if handler || script_link {
+                dom::remove_attr(document, id, &name);
+            }
+        }
+    }
+}
+
+/// Keep scripts but forbid the page from reaching the network
+pub fn neutralize_scripts(document: &mut Html) {
+    // A meta policy only covers what comes after it
+    if let Some(head) = dom::find(document, "head") {
+        let meta = format!(r#"<meta http-equiv="Content-Security-Policy" content="{}">"#, OFFLINE_CSP);
+        dom::prepend_html(document, head, &meta);
+    }
+}
+
+/// Download external scripts and point their `src` to the local copies; scripts that cannot be
+/// downloaded are removed rather than left loading from the network
+async fn localize_scripts(document: &mut Html, page: &mut PageContext) -> Result<()> {
+    let base_url = Url::parse(&page.page_url).ok();
+    let dir = page.page_dir.join(&page.options.media.scripts_dir);
+    let selector = Selector::parse("script[src]").unwrap();
+    let targets: Vec<_> = dom::select_ids(document, &selector)
+        .into_iter()
+        .filter_map(|id| {
+            let src = dom::attr(document, id, "src")?;
+            let url = resolve_asset_url(base_url.as_ref(), &src)?;
+            Some((id, AssetJob { url, dir: dir.clone() }))
+        })
+        .collect();
+
+    let jobs: Vec<AssetJob> = targets.iter().map(|(_, job)| job.clone()).collect();
+    let local = download_assets(page, &jobs).await?;
+    page.assets.bytes += downloaded_bytes(&local);
+
+    for (id, job) in targets {
+        let asset = &local[&job.url];
+        if asset.status.is_available() {
+            page.assets.scripts += 1;
+            dom::set_attr(document, id, "src", &page.href(&asset.path));
+            // Integrity checks need a CORS request, which pages opened from disk cannot make
+            dom::remove_attr(document, id, "integrity");
+        } else {
+            page.assets.failed += 1;
+            dom::remove(document, id);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Archive;
+    use crate::config::Settings;
+    use crate::testing::{filler, post, Pages, Route, TestServer};
+
+    #[tokio::test]
+    async fn scripts_that_fail_to_download_are_removed() {
+        let server = TestServer::start(vec![Route::ok("/app.js", "text/javascript", "console.log(1);")]).await;
+        let html = format!(
+            r#"<html><body><p>{}</p>
+            <script src="/app.js" integrity="sha384-x"></script>
+            <script src="/missing.js"></script>
+            <script>inline();</script>
+            </body></html>"#,
+            filler()
+        );
+        let page_url = server.url("/post.html");
+        let outputs = tempfile::tempdir().unwrap();
+        let archive = Archive::builder()
+            .renderer(Pages::default().with(&page_url, &html))
+            .settings(Settings {
+                renderer: Some("pages".to_string()),
+                scripts: Some(ScriptPolicy::Localize),
+                ..Default::default()
+            })
+            .pipeline(["scripts"])
+            .posts([post(&page_url, "Post")])
+            .outputs_dir(outputs.path())
+            .build()
+            .unwrap();
+        let report = archive.run().await.unwrap();
+        let assets = &report.posts[0].assets;
+        assert_eq!((assets.scripts, assets.failed), (1, 1));
+
+        let saved = std::fs::read_to_string(outputs.path().join("notes/Post.html")).unwrap();
+        assert!(saved.contains(r#"src="scripts/app-"#) && !saved.contains("integrity"));
+        assert!(!saved.contains("missing.js"));
+        assert!(saved.contains("inline();"));
+    }
+}
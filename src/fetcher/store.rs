@@ -0,0 +1,159 @@
+use crate::fetcher::assets::{download_to_temp, AssetIndex, AssetStatus, LocalAsset};
+use crate::fetcher::content_type::asset_extension;
+use crate::fetcher::http::HttpSession;
+use crate::fetcher::naming::normalize_url;
+use crate::fetcher::partial::PartialFile;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+
+/// Directory under the outputs root holding shared assets
+pub const STORE_DIR: &str = "assets";
+
+/// Content-addressed storage for page assets, shared by all pages of the outputs directory.
+///
+/// Each distinct file is stored once at `assets/<2 hex>/<md5>.<ext>`, whatever URL or page it
+/// came from. Stored files are recorded in the asset index, so later runs reuse them without
+/// downloading them again.
+#[derive(Debug)]
+pub struct AssetStore {
+    outputs_dir: PathBuf,
+    index: Arc<AssetIndex>,
+    /// One download per normalized URL in this run; `None` once it failed
+    in_flight: Mutex<HashMap<String, Arc<OnceCell<Option<PathBuf>>>>>,
+}
+
+impl AssetStore {
+    pub fn new(outputs_dir: &Path, index: Arc<AssetIndex>) -> Self {
+        Self {
+            outputs_dir: outputs_dir.to_path_buf(),
+            index,
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The stored copy of `url`, downloaded unless already stored.
+    ///
+    /// Concurrent calls for the same URL wait for a single download. A failed download is
+    /// reported once and not retried for the rest of the run.
+    pub async fn fetch(&self, http: &HttpSession, page_url: &str, url: &str) -> Result<LocalAsset> {
+        let cell = self.in_flight.lock().unwrap().entry(normalize_url(url)).or_default().clone();
+        let mut downloaded = None;
+        let stored = cell
+            .get_or_try_init(|| async {
+                let known = self.index.get(url).and_then(|record| record.stored);
+                if let Some(path) = known.map(|rel| self.outputs_dir.join(rel)).filter(|path| path.exists()) {
+                    return Ok(Some(path));
+                }
+                let store_dir = self.outputs_dir.join(STORE_DIR);
+                let Some(download) = download_to_temp(http, page_url, url, &store_dir).await? else {
+                    return Ok(None);
+                };
+                let extension = asset_extension(download.content_type.as_deref(), &download.head, url);
+                let rel_path = format!("{}/{}/{}.{}", STORE_DIR, &download.md5[..2], download.md5, extension);
+                let path = self.outputs_dir.join(&rel_path);
+                // Identical content from another URL is already stored; the temporary file is dropped
+                if !path.exists() {
+                    download.file.commit_to(&path).await?;
+                }
+                self.index.update(url, |record| record.stored = Some(rel_path));
+                downloaded = Some(download.bytes);
+                Ok::<_, anyhow::Error>(Some(path))
+            })
+            .await?
+            .clone();
+        Ok(match stored {
+            Some(path) => {
+                let status = downloaded.map_or(AssetStatus::Existing, AssetStatus::Downloaded);
+                LocalAsset { path, status }
+            }
+            None => LocalAsset::failed(),
+        })
+    }
+
+    /// The stored copy of `url` from this or an earlier run, without downloading it
+    pub fn stored(&self, url: &str) -> Option<PathBuf> {
+        let rel = self.index.get(url)?.stored?;
+        Some(self.outputs_dir.join(rel)).filter(|path| path.exists())
+    }
+
+    /// Store content produced for `url`, e.g. a stylesheet with its references rewritten
+    pub async fn save(&self, url: &str, content: &[u8], extension: &str) -> Result<PathBuf> {
+        let md5 = format!("{:x}", md5::compute(content));
+        let rel_path = format!("{}/{}/{}.{}", STORE_DIR, &md5[..2], md5, extension);
+        let path = self.outputs_dir.join(&rel_path);
+        if !path.exists() {
+            PartialFile::write(&path, content).await?;
+        }
+        self.index.update(url, |record| record.stored = Some(rel_path));
+        Ok(path)
+    }
+}
+
+/// Link from one stored file to another; every stored file sits one directory below the store
+pub fn stored_href(to: &Path) -> String {
+    let shard = to.parent().and_then(Path::file_name).unwrap_or_default();
+    let name = to.file_name().unwrap_or_default();
+    format!("../{}/{}", shard.to_string_lossy(), name.to_string_lossy())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::archive::Archive;
+    use crate::config::Settings;
+    use crate::testing::{filler, post, Pages, Route, TestServer};
+
+    #[tokio::test]
+    async fn pages_share_one_stored_copy_per_content() {
+        let png = b"\x89PNG\r\n\x1a\nshared".to_vec();
+        let server = TestServer::start(vec![
+            Route::ok("/shared.png", "image/png", png.clone()).delayed(100),
+            Route::ok("/copy.png", "image/png", png.clone()),
+        ])
+        .await;
+        let page = |img: &str| format!(r#"<html><body><p>{}</p><img src="{}"></body></html>"#, filler(), img);
+        let (one, two) = (server.url("/one.html"), server.url("/two.html"));
+        let pages = || {
+            Pages::default()
+                .with(&one, &page("/shared.png"))
+                .with(&two, &(page("/shared.png").replace("</body>", r#"<img src="/copy.png"></body>"#)))
+        };
+        let outputs = tempfile::tempdir().unwrap();
+        let run = || async {
+            Archive::builder()
+                .renderer(pages())
+                .settings(Settings {
+                    renderer: Some("pages".to_string()),
+                    asset_store: Some(true),
+                    force: Some(true),
+                    ..Default::default()
+                })
+                .pipeline(["images"])
+                .posts([post(&one, "One"), post(&two, "Two")])
+                .outputs_dir(outputs.path())
+                .build()
+                .unwrap()
+                .run()
+                .await
+                .unwrap()
+        };
+        run().await;
+        // Both pages asked for the same URL at once; identical content from another URL is stored once
+        assert_eq!((server.hits("/shared.png"), server.hits("/copy.png")), (1, 1));
+        let md5 = format!("{:x}", md5::compute(&png));
+        let rel_path = format!("assets/{}/{}.png", &md5[..2], md5);
+        assert_eq!(fs::read(outputs.path().join(&rel_path)).unwrap(), png);
+        assert_eq!(fs::read_dir(outputs.path().join(STORE_DIR)).unwrap().count(), 1);
+        let saved = fs::read_to_string(outputs.path().join("notes/Two.html")).unwrap();
+        assert_eq!(saved.matches(&format!(r#"src="../{}""#, rel_path)).count(), 2);
+
+        // A later run finds the stored files in the asset index
+        let report = run().await;
+        assert_eq!((server.hits("/shared.png"), server.hits("/copy.png")), (1, 1));
+        assert_eq!(report.posts[1].assets.images, 2);
+    }
+}
@@ -0,0 +1,438 @@
+use crate::fetcher::assets::{download_assets, downloaded_bytes, resolve_asset_url, AssetJob};
+use crate::fetcher::downloader::AssetCounts;
+use crate::fetcher::dom;
+use crate::fetcher::events::ProgressEvent;
+use crate::fetcher::naming::asset_file_name;
+use crate::fetcher::partial::PartialFile;
+use crate::fetcher::processor::{PageContext, Processor};
+use crate::fetcher::store::stored_href;
+use crate::utils::relative_url;
+use anyhow::Result;
+use ego_tree::NodeId;
+use futures::future::BoxFuture;
+use scraper::{Html, Selector};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// `@import` chains deeper than this keep their remote links
+const MAX_IMPORT_DEPTH: usize = 8;
+
+/// Download the stylesheets of the page and the fonts and images they use, so it renders offline.
+///
+/// Covers `<link rel="stylesheet">`, `@import`ed sheets, and `url(...)` references in sheets,
+/// `<style>` blocks and `style` attributes. Saved sheets refer to their files with relative links.
+#[derive(Debug, Default)]
+pub struct Styles;
+
+impl Processor for Styles {
+    fn name(&self) -> &'static str {
+        "styles"
+    }
+
+    fn process<'a>(&'a self, document: &'a mut Html, page: &'a mut PageContext) -> BoxFuture<'a, Result<()>> {
+        Box::pin(process_styles(document, page))
+    }
+}
+
+/// How CSS refers to another file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CssRef {
+    /// A stylesheet in an `@import` rule
+    Import,
+    /// A `url(...)`, e.g. a font or a background image
+    Url,
+}
+
+/// Rewrite the references of a stylesheet; `rewrite` returns the new URL, or `None` to keep a reference.
+///
+/// Comments are left alone and rewritten references are double-quoted:
+///
+/// ```
+/// use hyfetcher::fetcher::styles::{rewrite_css, CssRef};
+///
+/// let css = r#"@import "base.css" screen; /* url(old.png) */
+/// @font-face { src: url( 'fonts/a.woff2' ) format("woff2"), url(data:font/woff;base64,AA==) }
+/// .logo { background: URL(logo.png) no-repeat }"#;
+/// let rewritten = rewrite_css(css, |url, kind| match (url, kind) {
+///     ("base.css", CssRef::Import) => Some("styles/base-1.css".to_string()),
+///     (url, CssRef::Url) if !url.starts_with("data:") => Some(format!("styles/{}", url.replace('/', "-"))),
+///     _ => None,
+/// });
+/// assert_eq!(rewritten, r#"@import "styles/base-1.css" screen; /* url(old.png) */
+/// @font-face { src: url("styles/fonts-a.woff2") format("woff2"), url(data:font/woff;base64,AA==) }
+/// .logo { background: url("styles/logo.png") no-repeat }"#);
+/// ```
+pub fn rewrite_css(css: &str, mut rewrite: impl FnMut(&str, CssRef) -> Option<String>) -> String {
+    let mut out = String::with_capacity(css.len());
+    let mut rest = css;
+    // Whether the next url(...) is the target of an @import
+    let mut importing = false;
+    while let Some(c) = rest.chars().next() {
+        if rest.starts_with("/*") {
+            let end = rest[2..].find("*/").map_or(rest.len(), |i| i + 4);
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else if starts_with_ignore_case(rest, "@import") {
+            out.push_str(&rest[..7]);
+            rest = &rest[7..];
+            let spaces = rest.len() - rest.trim_start().len();
+            out.push_str(&rest[..spaces]);
+            rest = &rest[spaces..];
+            match rest.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let (url, len) = quoted(rest, quote);
+                    match rewrite(url, CssRef::Import) {
+                        Some(new) => out.push_str(&quote_string(&new)),
+                        None => out.push_str(&rest[..len]),
+                    }
+                    rest = &rest[len..];
+                }
+                _ => importing = true,
+            }
+        } else if starts_with_ignore_case(rest, "url(") && !ends_with_ident(&out) {
+            let kind = if importing { CssRef::Import } else { CssRef::Url };
+            importing = false;
+            let inner = &rest[4..];
+            let spaces = inner.len() - inner.trim_start().len();
+            let (url, inner_len) = match inner[spaces..].chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let (url, len) = quoted(&inner[spaces..], quote);
+                    (url, spaces + len)
+                }
+                _ => {
+                    let len = inner[spaces..].find(')').unwrap_or(inner.len() - spaces);
+                    (inner[spaces..spaces + len].trim_end(), spaces + len)
+                }
+            };
+            let close = inner[inner_len..].find(')').map_or(inner.len(), |i| inner_len + i + 1);
+            let len = 4 + close;
+            match rewrite(url, kind) {
+                Some(new) => {
+                    out.push_str("url(");
+                    out.push_str(&quote_string(&new));
+                    out.push(')');
+                }
+                None => out.push_str(&rest[..len]),
+            }
+            rest = &rest[len..];
+        } else {
+            if c == ';' || c == '{' {
+                importing = false;
+            }
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+/// Contents and length of the CSS string at the start of `s`
+fn quoted(s: &str, quote: char) -> (&str, usize) {
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            _ if c == quote => return (&s[1..i], i + 1),
+            _ => {}
+        }
+    }
+    (&s[1..], s.len())
+}
+
+fn quote_string(url: &str) -> String {
+    format!("\"{}\"", url.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn starts_with_ignore_case(s: &str, prefix: &str) -> bool {
+    s.get(..prefix.len()).is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+}
+
+/// Whether `url(` would continue an identifier, as in `myurl(`
+fn ends_with_ident(s: &str) -> bool {
+    s.chars().next_back().is_some_and(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Where rewritten CSS is saved, which decides how it links to local files
+#[derive(Debug, Clone, Copy)]
+enum CssLocation<'a> {
+    /// The page itself or a directory next to it
+    Dir(&'a Path),
+    /// The shared asset store
+    Store,
+}
+
+impl CssLocation<'_> {
+    fn href(self, path: &Path) -> String {
+        match self {
+            CssLocation::Dir(dir) => relative_url(dir, path),
+            CssLocation::Store => stored_href(path),
+        }
+    }
+}
+
+/// Stylesheets of one page, each saved once however often it is linked or imported
+struct Sheets<'a> {
+    page: &'a PageContext,
+    /// Directory next to the page for sheets and their files, without a shared store
+    dir: PathBuf,
+    /// Local copy of each sheet URL, `None` if it failed or is still being saved
+    saved: HashMap<String, Option<PathBuf>>,
+    counts: AssetCounts,
+}
+
+impl<'a> Sheets<'a> {
+    fn new(page: &'a PageContext) -> Self {
+        Self {
+            page,
+            dir: page.page_dir.join(&page.options.media.styles_dir),
+            saved: HashMap::new(),
+            counts: AssetCounts::default(),
+        }
+    }
+
+    /// Local copy of the sheet at `url`, with its own references localized
+    fn localize(&mut self, url: String, depth: usize) -> BoxFuture<'_, Result<Option<PathBuf>>> {
+        Box::pin(async move {
+            // Also stops import cycles, since the sheet is marked before its imports are followed
+            if let Some(saved) = self.saved.get(&url) {
+                return Ok(saved.clone());
+            }
+            self.saved.insert(url.clone(), None);
+            let page = self.page;
+            let existing = match &page.options.store {
+                Some(store) => store.stored(&url),
+                None => page
+                    .options
+                    .asset_index
+                    .get(&url)
+                    .and_then(|record| record.file_name)
+                    .map(|name| self.dir.join(name))
+                    .filter(|path| path.exists()),
+            };
+            if let Some(path) = existing {
+                self.counts.styles += 1;
+                self.saved.insert(url, Some(path.clone()));
+                return Ok(Some(path));
+            }
+
+            let css = match page.http.get(&url).await {
+                Ok(response) => response.text().await,
+                Err(e) => Err(e),
+            };
+            let css = match css {
+                Ok(css) => css,
+                Err(e) => {
+                    page.http.events().emit(ProgressEvent::AssetFailed {
+                        page_url: page.post.url.clone(),
+                        url: url.clone(),
+                        error: e.to_string(),
+                    });
+                    self.counts.failed += 1;
+                    return Ok(None);
+                }
+            };
+            page.http.events().emit(ProgressEvent::AssetDownloaded {
+                page_url: page.post.url.clone(),
+                url: url.clone(),
+                bytes: css.len() as u64,
+            });
+            self.counts.styles += 1;
+            self.counts.bytes += css.len() as u64;
+
+            let path = match &page.options.store {
+                Some(store) => {
+                    let css = self.rewrite(&css, &url, CssLocation::Store, depth).await?;
+                    store.save(&url, css.as_bytes(), "css").await?
+                }
+                None => {
+                    let dir = self.dir.clone();
+                    let css = self.rewrite(&css, &url, CssLocation::Dir(&dir), depth).await?;
+                    let name = asset_file_name(&url, Some("text/css"), &[]);
+                    let path = dir.join(&name);
+                    PartialFile::write(&path, css).await?;
+                    page.options.asset_index.update(&url, |record| record.file_name = Some(name));
+                    path
+                }
+            };
+            self.saved.insert(url, Some(path.clone()));
+            Ok(Some(path))
+        })
+    }
+
+    /// Localize the references of CSS found at `base_url` and saved at `location`
+    async fn rewrite(&mut self, css: &str, base_url: &str, location: CssLocation<'_>, depth: usize) -> Result<String> {
+        let base = Url::parse(base_url).ok();
+        let mut imports = Vec::new();
+        let mut jobs = Vec::new();
+        rewrite_css(css, |raw, kind| {
+            if let Some(url) = resolve_asset_url(base.as_ref(), raw) {
+                match kind {
+                    CssRef::Import => imports.push(url),
+                    CssRef::Url => jobs.push(AssetJob { url, dir: self.dir.clone() }),
+                }
+            }
+            None
+        });
+
+        let mut sheets = HashMap::new();
+        if depth < MAX_IMPORT_DEPTH {
+            for url in imports {
+                if let Some(path) = self.localize(url.clone(), depth + 1).await? {
+                    sheets.insert(url, path);
+                }
+            }
+        }
+        let local = download_assets(self.page, &jobs).await?;
+        self.counts.bytes += downloaded_bytes(&local);
+        for asset in local.values() {
+            if asset.status.is_available() {
+                self.counts.styles += 1;
+            } else {
+                self.counts.failed += 1;
+            }
+        }
+
+        // References not saved locally point at the live site, since relative ones would break once moved
+        Ok(rewrite_css(css, |raw, kind| {
+            let url = resolve_asset_url(base.as_ref(), raw)?;
+            let path = match kind {
+                CssRef::Import => sheets.get(&url),
+                CssRef::Url => local.get(&url).filter(|asset| asset.status.is_available()).map(|asset| &asset.path),
+            };
+            Some(path.map_or(url, |path| location.href(path)))
+        }))
+    }
+}
+
+/// Change to the page once its styles are localized
+enum Edit {
+    Link(String),
+    Text(String),
+    Style(String),
+}
+
+async fn process_styles(document: &mut Html, page: &mut PageContext) -> Result<()> {
+    let base_url = Url::parse(&page.page_url).ok();
+    let link_selector = Selector::parse("link[rel][href]").unwrap();
+    let links: Vec<(NodeId, String)> = dom::select_ids(document, &link_selector)
+        .into_iter()
+        .filter(|&id| {
+            dom::attr(document, id, "rel")
+                .is_some_and(|rel| rel.split_whitespace().any(|token| token.eq_ignore_ascii_case("stylesheet")))
+        })
+        .filter_map(|id| {
+            let href = dom::attr(document, id, "href")?;
+            Some((id, resolve_asset_url(base_url.as_ref(), &href)?))
+        })
+        .collect();
+    let blocks = dom::select_ids(document, &Selector::parse("style").unwrap());
+    let styled = dom::select_ids(document, &Selector::parse("[style]").unwrap());
+
+    let mut sheets = Sheets::new(page);
+    let mut edits = Vec::new();
+    for (id, url) in links {
+        if let Some(path) = sheets.localize(url, 0).await? {
+            edits.push((id, Edit::Link(page.href(&path))));
+        }
+    }
+    let page_dir = page.page_dir.clone();
+    for id in blocks {
+        let css = dom::text(document, id);
+        let rewritten = sheets.rewrite(&css, &page.page_url, CssLocation::Dir(&page_dir), 0).await?;
+        if rewritten != css {
+            edits.push((id, Edit::Text(rewritten)));
+        }
+    }
+    for id in styled {
+        let css = dom::attr(document, id, "style").unwrap_or_default();
+        let rewritten = sheets.rewrite(&css, &page.page_url, CssLocation::Dir(&page_dir), 0).await?;
+        if rewritten != css {
+            edits.push((id, Edit::Style(rewritten)));
+        }
+    }
+    let counts = sheets.counts;
+    page.assets.add(counts);
+
+    for (id, edit) in edits {
+        match edit {
+            Edit::Link(href) => {
+                dom::set_attr(document, id, "href", &href);
+                // The local sheet differs from the one the hash was computed for
+                dom::remove_attr(document, id, "integrity");
+            }
+            Edit::Text(css) => dom::set_text(document, id, &css),
+            Edit::Style(css) => dom::set_attr(document, id, "style", &css),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Archive;
+    use crate::config::Settings;
+    use crate::testing::{filler, post, Pages, Route, TestServer};
+
+    const SITE_CSS: &str =
+        r#"@import "parts/theme.css"; .a { background: url(../img/bg.png) } @font-face { src: url('fonts/a.woff2') }"#;
+
+    #[tokio::test]
+    async fn sheets_imports_and_urls_are_localized_relative_to_their_sheet() {
+        let png = b"\x89PNG\r\n\x1a\nimage".to_vec();
+        let woff2 = b"wOF2font".to_vec();
+        let server = TestServer::start(vec![
+            Route::ok("/css/site.css", "text/css", SITE_CSS),
+            Route::ok("/css/parts/theme.css", "text/css", ".b { background: url(dot.png) }"),
+            Route::ok("/css/parts/dot.png", "image/png", png.clone()),
+            Route::ok("/img/bg.png", "image/png", png.clone()),
+            Route::ok("/img/inline.png", "image/png", png.clone()),
+            Route::ok("/css/fonts/a.woff2", "font/woff2", woff2.clone()),
+        ])
+        .await;
+        let html = format!(
+            r#"<html><head><link rel="stylesheet" href="css/site.css">
+            <style>.c {{ background: url(img/inline.png) }} .d {{ background: url(/img/missing.png) }}</style>
+            </head><body><p>{}</p></body></html>"#,
+            filler()
+        );
+        let page_url = server.url("/post.html");
+        let outputs = tempfile::tempdir().unwrap();
+        let archive = Archive::builder()
+            .renderer(Pages::default().with(&page_url, &html))
+            .settings(Settings { renderer: Some("pages".to_string()), ..Default::default() })
+            .pipeline(["styles"])
+            .posts([post(&page_url, "Post")])
+            .outputs_dir(outputs.path())
+            .build()
+            .unwrap();
+        let report = archive.run().await.unwrap();
+        // Two sheets, dot, bg, font and inline; the missing image failed
+        assert_eq!((report.posts[0].assets.styles, report.posts[0].assets.failed), (6, 1));
+
+        let name = |path: &str, content_type: &str, head: &[u8]| {
+            asset_file_name(&server.url(path), Some(content_type), head)
+        };
+        let styles = outputs.path().join("notes/styles");
+        let site = std::fs::read_to_string(styles.join(name("/css/site.css", "text/css", b""))).unwrap();
+        assert_eq!(
+            site,
+            format!(
+                r#"@import "{}"; .a {{ background: url("{}") }} @font-face {{ src: url("{}") }}"#,
+                name("/css/parts/theme.css", "text/css", b""),
+                name("/img/bg.png", "image/png", &png),
+                name("/css/fonts/a.woff2", "font/woff2", &woff2)
+            )
+        );
+        let theme = std::fs::read_to_string(styles.join(name("/css/parts/theme.css", "text/css", b""))).unwrap();
+        assert_eq!(theme, format!(r#".b {{ background: url("{}") }}"#, name("/css/parts/dot.png", "image/png", &png)));
+
+        let saved = std::fs::read_to_string(outputs.path().join("notes/Post.html")).unwrap();
+        assert!(saved.contains(&format!(r#"href="styles/{}""#, name("/css/site.css", "text/css", b""))));
+        assert!(saved.contains(&format!(r#"url("styles/{}")"#, name("/img/inline.png", "image/png", &png))));
+        // Not downloaded: points at the live site rather than a broken relative path
+        assert!(saved.contains(&format!(r#"url("{}")"#, server.url("/img/missing.png"))));
+    }
+}
@@ -1,194 +1,200 @@
-use reqwest::Client;
+use crate::fetcher::assets::{download_assets, downloaded_bytes, resolve_asset_url, AssetJob};
+use crate::fetcher::dom;
+use crate::fetcher::events::ProgressEvent;
+use crate::fetcher::naming::asset_file_name;
+use crate::fetcher::processor::{escape_html, PageContext, Processor};
+use crate::fetcher::scheduler::host_of;
+use futures::future::BoxFuture;
 use scraper::{Html, Selector};
 use url::Url;
-use std::path::Path;
-use tokio::io::AsyncWriteExt;
-use std::process::Command;
+use std::process::Stdio;
+use tokio::process::Command;
 use std::fs;
 use crate::utils::get_tool_path;
+use anyhow::Context;
 
-/// Process all videos in HTML, download them locally, and replace HTML src to point to local files
-pub async fn process_videos(
-    html: &str,
-    page_url: &str,
-    html_file_dir: &Path,
-    client: &Client,
-) -> anyhow::Result<String> {
-    let base_url = Url::parse(page_url).ok();
-    let document = Html::parse_document(html);
-    let video_selector = Selector::parse("video").unwrap();
-    let source_selector = Selector::parse("source").unwrap();
-    let mut replacements = vec![];
+/// Download all videos of the page locally and point their src to the local files
+#[derive(Debug, Default)]
+pub struct Videos;
 
+impl Processor for Videos {
+    fn name(&self) -> &'static str {
+        "videos"
+    }
+
+    fn process<'a>(&'a self, document: &'a mut Html, page: &'a mut PageContext) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(process_videos(document, page))
+    }
+}
+
+async fn process_videos(document: &mut Html, page: &mut PageContext) -> anyhow::Result<()> {
     // Special handling for Bilibili - use yt-dlp
-    if page_url.contains("bilibili.com") {
-        println!("Detected Bilibili video, using yt-dlp to download...");
-        let local_video_dir = html_file_dir.join("videos");
-        fs::create_dir_all(&local_video_dir)?;
-        let output_path = local_video_dir.join("bilibili_video.mp4");
-        
-        if !output_path.exists() {
-            println!("Using yt-dlp to download Bilibili video: {}", page_url);
-            
-            // Get yt-dlp path
-            let yt_dlp_path = match get_tool_path("yt-dlp") {
-                Ok(path) => path,
-                Err(e) => {
-                    eprintln!("Unable to find yt-dlp: {}", e);
-                    eprintln!("Please ensure yt-dlp is installed or re-run the program for auto-installation");
-                    return Err(e);
-                }
-            };
-            
-            let status = Command::new(&yt_dlp_path)
-                .args(&[
-                    "--output", output_path.to_str().unwrap(),
-                    "--format", "bv*[height=720][ext=mp4]+ba[ext=m4a]/bv*[height=720]+ba/best[height=720]/best",
-                    page_url
-                ])
-                .status();
-            
-            match status {
-                Ok(exit_status) => {
-                    if exit_status.success() {
-                        println!("yt-dlp download successful: {}", output_path.display());
-                    } else {
-                        eprintln!("yt-dlp download failed, exit code: {}", exit_status);
-                    }
-                }
-                Err(e) => {
-                    eprintln!("yt-dlp command execution failed: {}", e);
-                    eprintln!("Please ensure yt-dlp is installed: pip install yt-dlp");
-                }
-            }
-        }
-        
-        // Insert local video tag in HTML
-        if output_path.exists() {
-            let rel_path = format!("videos/{}", output_path.file_name().unwrap().to_string_lossy());
-            // Use more compatible HTML5 video tag format with additional browser compatibility attributes
-            replacements.push((
-                "</body>".to_string(),
-                format!(
-                    "<div style=\"text-align:center; margin:20px 0;\">\
-                    <video id=\"localVideo\" controls preload=\"metadata\" style=\"max-width:100%; height:auto; border-radius:8px; box-shadow:0 4px 8px rgba(0,0,0,0.1);\">\
-                    <source src=\"{}\" type=\"video/mp4\" />\
-                    <p style=\"color:#666; margin:10px 0;\">Your browser does not support HTML5 video playback.<br>\
-                    <a href=\"{}\" style=\"color:#007AFF; text-decoration:none;\" download>Click here to download video</a></p>\
-                    </video>\
-                    <script>\
-                    (function() {{\
-                        var video = document.getElementById('localVideo');\
-                        if (video) {{\
-                            video.addEventListener('error', function(e) {{\
-                                console.log('Video loading error:', e);\
-                                var fallback = document.createElement('div');\
-                                fallback.innerHTML = '<p style=\"color:#FF3B30; margin:10px 0;\">Video loading failed, please <a href=\"{}\" style=\"color:#007AFF;\" download>click download</a> to watch</p>';\
-                                video.parentNode.replaceChild(fallback, video);\
-                            }});\
-                            video.addEventListener('loadedmetadata', function() {{\
-                                console.log('Video loaded successfully');\
-                            }});\
-                        }}\
-                    }})();\
-                    </script>\
-                    </div></body>", 
-                    rel_path, rel_path, rel_path
-                )
-            ));
-        }
+    if page.post.url.contains("bilibili.com") {
+        download_bilibili(document, page).await?;
     }
 
-    // Process regular video tags
-    for video in document.select(&video_selector) {
-        if let Some(src) = video.value().attr("src") {
-            if src.starts_with("http://") || src.starts_with("https://") || base_url.is_some() {
-                let video_url = if src.starts_with("http") {
-                    src.to_string()
-                } else if let Some(base) = &base_url {
-                    base.join(src).map(|u| u.to_string()).unwrap_or(src.to_string())
-                } else {
-                    src.to_string()
-                };
-                let filename = video_url.split('/').last().and_then(|f| {
-                    if f.is_empty() { None } else { Some(f) }
-                }).unwrap_or("video.mp4");
-                let local_video_dir = html_file_dir.join("videos");
-                let local_path = local_video_dir.join(filename);
-                if !local_path.exists() {
-                    if let Some(parent) = local_path.parent() {
-                        std::fs::create_dir_all(parent)?;
-                    }
-                    match client.get(&video_url).send().await {
-                        Ok(resp) => {
-                            if resp.status().is_success() {
-                                let mut stream = resp.bytes_stream();
-                                let mut file = tokio::fs::File::create(&local_path).await?;
-                                use futures::StreamExt;
-                                while let Some(chunk) = stream.next().await {
-                                    let chunk = chunk?;
-                                    file.write_all(&chunk).await?;
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to download video {}: {}", video_url, e);
-                        }
-                    }
-                }
-                let rel_path = format!("videos/{}", filename);
-                replacements.push((format!("src=\"{}\"", src), format!("src=\"{}\"", rel_path)));
-            }
+    // Process regular video tags, then source tags
+    let base_url = Url::parse(&page.page_url).ok();
+    let video_selector = Selector::parse("video[src]").unwrap();
+    let source_selector = Selector::parse("source[src]").unwrap();
+    let mut videos = dom::select_ids(document, &video_selector);
+    videos.extend(dom::select_ids(document, &source_selector));
+    let dir = page.page_dir.join(&page.options.media.videos_dir);
+
+    // data: and blob: sources and other schemes that cannot be downloaded stay as they are
+    let targets: Vec<_> = videos
+        .into_iter()
+        .filter_map(|id| {
+            let url = resolve_asset_url(base_url.as_ref(), &dom::attr(document, id, "src")?)?;
+            Some((id, AssetJob { url, dir: dir.clone() }))
+        })
+        .collect();
+
+    let jobs: Vec<AssetJob> = targets.iter().map(|(_, job)| job.clone()).collect();
+    let local = download_assets(page, &jobs).await?;
+    page.assets.bytes += downloaded_bytes(&local);
+
+    // Rewrite in document order, whatever order the downloads finished in
+    for (id, job) in targets {
+        let asset = &local[&job.url];
+        if asset.status.is_available() {
+            page.assets.videos += 1;
+            dom::set_attr(document, id, "src", &page.href(&asset.path));
+        } else {
+            page.assets.failed += 1;
         }
     }
-    
-    // Process source tags
-    for source in document.select(&source_selector) {
-        if let Some(src) = source.value().attr("src") {
-            if src.starts_with("http://") || src.starts_with("https://") || base_url.is_some() {
-                let video_url = if src.starts_with("http") {
-                    src.to_string()
-                } else if let Some(base) = &base_url {
-                    base.join(src).map(|u| u.to_string()).unwrap_or(src.to_string())
-                } else {
-                    src.to_string()
-                };
-                let filename = video_url.split('/').last().and_then(|f| {
-                    if f.is_empty() { None } else { Some(f) }
-                }).unwrap_or("video.mp4");
-                let local_video_dir = html_file_dir.join("videos");
-                let local_path = local_video_dir.join(filename);
-                if !local_path.exists() {
-                    if let Some(parent) = local_path.parent() {
-                        std::fs::create_dir_all(parent)?;
-                    }
-                    match client.get(&video_url).send().await {
-                        Ok(resp) => {
-                            if resp.status().is_success() {
-                                let mut stream = resp.bytes_stream();
-                                let mut file = tokio::fs::File::create(&local_path).await?;
-                                use futures::StreamExt;
-                                while let Some(chunk) = stream.next().await {
-                                    let chunk = chunk?;
-                                    file.write_all(&chunk).await?;
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to download video {}: {}", video_url, e);
-                        }
-                    }
-                }
-                let rel_path = format!("videos/{}", filename);
-                replacements.push((format!("src=\"{}\"", src), format!("src=\"{}\"", rel_path)));
+    Ok(())
+}
+
+/// Download a Bilibili video with yt-dlp and add a player for it at the end of the page
+async fn download_bilibili(document: &mut Html, page: &mut PageContext) -> anyhow::Result<()> {
+    let page_url = &page.post.url;
+    let media = &page.options.media;
+    let http = &page.http;
+    let local_video_dir = page.page_dir.join(&media.videos_dir);
+    fs::create_dir_all(&local_video_dir)?;
+    // Named after the post like any other asset, so posts sharing a videos directory keep their own file
+    let index = &page.options.asset_index;
+    let file_name = index
+        .get(page_url)
+        .and_then(|record| record.file_name)
+        .unwrap_or_else(|| asset_file_name(page_url, Some("video/mp4"), b""));
+    let output_path = local_video_dir.join(&file_name);
+
+    if !output_path.exists() {
+        // Get yt-dlp path
+        let yt_dlp_path = get_tool_path("yt-dlp").context(
+            "Unable to find yt-dlp; install it or re-run the program for auto-installation",
+        )?;
+
+        let mut command = Command::new(&yt_dlp_path);
+        command.arg("--output").arg(&output_path);
+        command.arg("--format").arg(&media.yt_dlp_format);
+        // Same network settings as the HTTP client
+        let client = http.client();
+        if let Some(rules) = client.proxies() {
+            // An empty proxy makes yt-dlp connect directly
+            let proxy = rules.for_host(&host_of(page_url)).map(|proxy| proxy.to_string()).unwrap_or_default();
+            command.arg("--proxy").arg(proxy);
+        }
+        if let Some(timeout) = client.read_timeout() {
+            command.arg("--socket-timeout").arg(timeout.as_secs().max(1).to_string());
+        }
+        if client.is_insecure(page_url) {
+            command.arg("--no-check-certificates");
+        }
+        // Reuse the session cookies for member-only videos
+        if let Some(cookies_file) = http.cookies_file() {
+            command.arg("--cookies").arg(cookies_file);
+        }
+        // Keep yt-dlp's own progress output away from the progress display, and stop it if the
+        // download is cancelled
+        let status = command.arg(page_url).stdout(Stdio::null()).kill_on_drop(true).status().await;
+
+        let error = match status {
+            Ok(exit_status) if exit_status.success() => {
+                let bytes = fs::metadata(&output_path).map(|m| m.len()).unwrap_or(0);
+                page.assets.bytes += bytes;
+                index.update(page_url, |record| record.file_name = Some(file_name.clone()));
+                http.events().emit(ProgressEvent::AssetDownloaded {
+                    page_url: page_url.to_string(),
+                    url: page_url.to_string(),
+                    bytes,
+                });
+                None
             }
+            Ok(exit_status) => Some(format!("yt-dlp download failed, exit code: {}", exit_status)),
+            Err(e) => Some(format!("yt-dlp command execution failed (install it with `pip install yt-dlp`): {}", e)),
+        };
+        if let Some(error) = error {
+            http.events().emit(ProgressEvent::AssetFailed {
+                page_url: page_url.to_string(),
+                url: page_url.to_string(),
+                error,
+            });
+            page.assets.failed += 1;
         }
     }
-    
-    // Batch replacement
-    let mut result = html.to_string();
-    for (from, to) in replacements {
-        result = result.replace(&from, &to);
+
+    // Insert local video tag in HTML
+    if output_path.exists()
+        && let Some(body) = dom::find(document, "body")
+    {
+        page.assets.videos += 1;
+        let rel_path = escape_html(&page.href(&output_path));
+        // Use more compatible HTML5 video tag format with additional browser compatibility attributes
+        let player = format!(
+            "<div style=\"text-align:center; margin:20px 0;\">\
+            <video id=\"localVideo\" controls preload=\"metadata\" style=\"max-width:100%; height:auto; border-radius:8px; box-shadow:0 4px 8px rgba(0,0,0,0.1);\">\
+            <source src=\"{}\" type=\"video/mp4\" />\
+            <p style=\"color:#666; margin:10px 0;\">Your browser does not support HTML5 video playback.<br>\
+            <a href=\"{}\" style=\"color:#007AFF; text-decoration:none;\" download>Click here to download video</a></p>\
+            </video>\
+            </div>",
+            rel_path, rel_path
+        );
+        dom::append_html(document, body, &player);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::archive::Archive;
+    use crate::config::Settings;
+    use crate::testing::{filler, post, Pages, Route, TestServer};
+
+    #[tokio::test]
+    async fn only_fetchable_sources_are_downloaded() {
+        let server = TestServer::start(vec![Route::ok("/clip.mp4", "video/mp4", b"\0\0\0\x18ftypmp42".to_vec())]).await;
+        let html = format!(
+            r#"<html><body><p>{}</p>
+            <video src="/clip.mp4"></video>
+            <video src="data:video/mp4;base64,AAAA"></video>
+            <video><source src="blob:https://example.com/1234"></video>
+            <video src="javascript:void(0)"></video>
+            </body></html>"#,
+            filler()
+        );
+        let page_url = server.url("/post.html");
+        let outputs = tempfile::tempdir().unwrap();
+        let archive = Archive::builder()
+            .renderer(Pages::default().with(&page_url, &html))
+            .settings(Settings { renderer: Some("pages".to_string()), ..Default::default() })
+            .pipeline(["videos"])
+            .posts([post(&page_url, "Post")])
+            .outputs_dir(outputs.path())
+            .build()
+            .unwrap();
+        let report = archive.run().await.unwrap();
+        let assets = &report.posts[0].assets;
+        assert_eq!((assets.videos, assets.failed), (1, 0));
+        assert_eq!(server.hits("/clip.mp4"), 1);
+
+        let saved = std::fs::read_to_string(outputs.path().join("notes/Post.html")).unwrap();
+        assert!(saved.contains(r#"src="videos/clip-"#));
+        assert!(saved.contains(r#"src="data:video/mp4;base64,AAAA""#));
+        assert!(saved.contains(r#"src="blob:https://example.com/1234""#));
     }
-    Ok(result)
-}
\ No newline at end of file
+}
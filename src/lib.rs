@@ -1,4 +1,14 @@
+pub mod archive;
+pub mod config;
 pub mod model;
 pub mod parser;
 pub mod fetcher;
-pub mod utils;
\ No newline at end of file
+pub mod manifest;
+pub mod progress;
+pub mod report;
+pub mod shutdown;
+pub mod utils;
+#[cfg(test)]
+mod testing;
+
+pub use archive::{Archive, ArchiveBuilder};
@@ -1,13 +1,9 @@
-mod model;
-mod parser;
-mod fetcher;
-mod utils;
-
-use parser::csv_parser::parse_posts;
-use parser::index_builder::{build_index_tree, write_index_html};
-use fetcher::downloader::download_and_save_post;
+use hyfetcher::config::{Config, Settings};
+use hyfetcher::progress::spawn_console;
+use hyfetcher::report::RunReport;
+use hyfetcher::shutdown::INTERRUPTED_EXIT_CODE;
+use hyfetcher::Archive;
 use std::path::PathBuf;
-use utils::check_and_install_tools;
 
 use clap::Parser;
 
@@ -19,18 +15,14 @@ use clap::Parser;
     about = "Rust offline website downloader and indexer"
 )]
 struct Args {
-    /// Data input directory
-    #[arg(short = 'd', long, default_value = "data")]
-    data_dir: String,
-    /// Output directory
-    #[arg(short = 'o', long, default_value = "outputs")]
-    outputs_dir: String,
-    /// Number of concurrent tasks
-    #[arg(short = 'c', long, default_value_t = 8)]
-    concurrency: usize,
-    /// Skip tool check
-    #[arg(long)]
-    skip_tool_check: bool,
+    /// YAML config file [default: hyfetcher.yaml in the data directory, if present]
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Config profile to apply on top of the config defaults
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+    #[command(flatten)]
+    settings: Settings,
 }
 
 #[tokio::main]
@@ -38,53 +30,50 @@ async fn main() -> anyhow::Result<()> {
     env_logger::init();
     let args = Args::parse();
 
-    // Check and install required tools
-    if !args.skip_tool_check {
-        if let Err(e) = check_and_install_tools().await {
-            eprintln!("Tool check failed: {}", e);
-            eprintln!("You can use --skip-tool-check to skip tool checking");
-            return Err(e);
-        }
+    // Config defaults, then the selected profile, then command-line flags
+    let data_dir = args.settings.data_dir();
+    let mut builder = Archive::builder().settings(args.settings);
+    if let Some((path, config)) = Config::find(args.config.as_deref(), &data_dir)? {
+        println!("Using config {}", path.display());
+        builder = builder.config(config);
     }
+    if let Some(profile) = args.profile {
+        builder = builder.profile(profile);
+    }
+    let archive = builder.build()?;
+    let settings = archive.settings();
+    let outputs_dir = settings.outputs_dir();
 
-    let data_dir = PathBuf::from(&args.data_dir);
-    let outputs_dir = PathBuf::from(&args.outputs_dir);
-
-    println!("Parsing posts from {} ...", data_dir.display());
-    let posts = parse_posts(&data_dir);
-    println!("Found {} posts.", posts.len());
+    // Check and install required tools
+    if !settings.skip_tool_check.unwrap_or(false)
+        && let Err(e) = archive.check_tools().await
+    {
+        eprintln!("Tool check failed: {}", e);
+        eprintln!("You can use --skip-tool-check to skip tool checking");
+        return Err(e);
+    }
 
-    // Multi-threaded concurrent downloading
-    let client = reqwest::Client::builder()
-        .user_agent("Mozilla/5.0 (compatible; RustDownloader/1.0)")
-        .build()?;
+    let console = spawn_console(archive.events(), archive.scheduler().clone());
+    // From here on Ctrl-C stops starting posts instead of killing the process
+    archive
+        .shutdown()
+        .listen_for_signals(archive.events().clone(), settings.grace_period());
+    let result = archive.run().await;
+    console.await?;
+    let report = result?;
 
-    use futures::stream::{FuturesUnordered, StreamExt};
-    let mut futures = FuturesUnordered::new();
-    for post in posts.iter().cloned() {
-        let client = client.clone();
-        let outputs_dir = outputs_dir.clone();
-        while futures.len() >= args.concurrency {
-            if let Some(result) = futures.next().await {
-                if let Err(e) = result {
-                    eprintln!("Error downloading: {}", e);
-                }
-            }
-        }
-        futures.push(async move {
-            download_and_save_post(&post, &outputs_dir, &client).await
-        });
+    println!("{}", report.summary());
+    if report.problems().next().is_some() {
+        print!("{}", RunReport::table(report.problems()));
     }
-    while let Some(result) = futures.next().await {
-        if let Err(e) = result {
-            eprintln!("Error downloading: {}", e);
-        }
+    println!("Run report written to {}", RunReport::json_path(&outputs_dir).display());
+    if report.interrupted {
+        println!(
+            "Interrupted: run again to resume. Index written to {}/index.html",
+            outputs_dir.display()
+        );
+        std::process::exit(INTERRUPTED_EXIT_CODE);
     }
-
-    // Generate index.html
-    let tree = build_index_tree(&posts);
-    write_index_html(&tree, &outputs_dir)?;
-
     println!("All done! Index generated at: {}/index.html", outputs_dir.display());
     Ok(())
-}
\ No newline at end of file
+}
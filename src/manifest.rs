@@ -0,0 +1,280 @@
+use crate::fetcher::downloader::PostOutcome;
+use crate::fetcher::retry::FetchError;
+use crate::model::Post;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Directory under the outputs root holding run state
+pub const STATE_DIR: &str = ".hyfetcher";
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Outcome of the last fetch attempt for a post
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FetchStatus {
+    Completed,
+    Failed,
+    /// Not fetched on purpose, e.g. disallowed by robots.txt
+    Skipped,
+}
+
+/// One manifest record, keyed by the post's relative save path
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub url: String,
+    /// URL after redirects of the last completed fetch
+    #[serde(default)]
+    pub final_url: Option<String>,
+    pub save_path: String,
+    pub status: FetchStatus,
+    /// HTTP status of the last attempt, if the server answered
+    #[serde(default)]
+    pub http_status: Option<u16>,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    pub timestamp: DateTime<Local>,
+    pub content_hash: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Which posts should be fetched again even if already completed
+#[derive(Debug, Clone, Default)]
+pub struct ForcePolicy {
+    pub all: bool,
+    pub urls: Vec<String>,
+    pub categories: Vec<String>,
+}
+
+impl ForcePolicy {
+    pub fn applies_to(&self, post: &Post) -> bool {
+        self.all
+            || self.urls.iter().any(|u| u == &post.url)
+            || self.categories.iter().any(|c| c == &post.category)
+    }
+}
+
+/// Persistent record of fetched posts, stored at `<outputs>/.hyfetcher/manifest.json`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    entries: BTreeMap<String, ManifestEntry>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Manifest {
+    /// Load the manifest of an outputs directory, or start an empty one
+    pub fn load(outputs_dir: &Path) -> Result<Self> {
+        let path = outputs_dir.join(STATE_DIR).join(MANIFEST_FILE);
+        let mut manifest = if path.exists() {
+            let content = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read manifest {}", path.display()))?;
+            serde_json::from_str::<Manifest>(&content)
+                .with_context(|| format!("Corrupted manifest {}", path.display()))?
+        } else {
+            Manifest::default()
+        };
+        manifest.path = path;
+        Ok(manifest)
+    }
+
+    /// Write the manifest atomically (temp file + rename) so a crash never leaves it truncated
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, post: &Post) -> Option<&ManifestEntry> {
+        self.entries.get(&post.get_rel_save_path())
+    }
+
+    /// Whether a post needs fetching: new, previously failed, missing on disk or forced
+    pub fn needs_fetch(&self, post: &Post, outputs_dir: &Path, force: &ForcePolicy) -> bool {
+        if force.applies_to(post) {
+            return true;
+        }
+        match self.get(post) {
+            Some(entry) => {
+                entry.status != FetchStatus::Completed
+                    || !outputs_dir.join(&entry.save_path).exists()
+            }
+            None => true,
+        }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.entries.values()
+    }
+
+    /// Hash a saved file again after it was edited in place
+    pub fn rehash(&mut self, outputs_dir: &Path, save_path: &str) {
+        let content_hash = fs::read(outputs_dir.join(save_path))
+            .ok()
+            .map(|bytes| format!("{:x}", md5::compute(bytes)));
+        for entry in self.entries.values_mut().filter(|entry| entry.save_path == save_path) {
+            entry.content_hash = content_hash.clone();
+        }
+    }
+
+    /// Record a successful fetch, hashing the saved file
+    pub fn record_success(&mut self, post: &Post, outputs_dir: &Path, outcome: &PostOutcome) {
+        let content_hash = fs::read(outputs_dir.join(&outcome.save_path))
+            .ok()
+            .map(|bytes| format!("{:x}", md5::compute(bytes)));
+        self.entries.insert(
+            post.get_rel_save_path(),
+            ManifestEntry {
+                url: post.url.clone(),
+                final_url: Some(outcome.final_url.clone()),
+                save_path: outcome.save_path.clone(),
+                status: FetchStatus::Completed,
+                http_status: Some(outcome.http_status),
+                content_type: outcome.content_type.clone(),
+                timestamp: Local::now(),
+                content_hash,
+                error: None,
+            },
+        );
+    }
+
+    /// Record a failed fetch, keeping the hash of any earlier good copy
+    pub fn record_failure(&mut self, post: &Post, error: &anyhow::Error) {
+        let http_status = error
+            .downcast_ref::<FetchError>()
+            .and_then(|e| e.status())
+            .map(|s| s.as_u16());
+        self.record_unfinished(post, FetchStatus::Failed, http_status, format!("{:#}", error));
+    }
+
+    /// Record a post that was deliberately not fetched, with the reason
+    pub fn record_skipped(&mut self, post: &Post, reason: &str) {
+        self.record_unfinished(post, FetchStatus::Skipped, None, reason.to_string());
+    }
+
+    fn record_unfinished(
+        &mut self,
+        post: &Post,
+        status: FetchStatus,
+        http_status: Option<u16>,
+        reason: String,
+    ) {
+        let key = post.get_rel_save_path();
+        // Keep pointing at any earlier good copy
+        let previous = self.entries.get(&key);
+        let save_path = previous.map(|e| e.save_path.clone()).unwrap_or_else(|| key.clone());
+        let final_url = previous.and_then(|e| e.final_url.clone());
+        let content_type = previous.and_then(|e| e.content_type.clone());
+        let content_hash = previous.and_then(|e| e.content_hash.clone());
+        self.entries.insert(
+            key,
+            ManifestEntry {
+                url: post.url.clone(),
+                final_url,
+                save_path,
+                status,
+                http_status,
+                content_type,
+                timestamp: Local::now(),
+                content_hash,
+                error: Some(reason),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::post;
+
+    fn save(outputs_dir: &Path, manifest: &mut Manifest, post: &Post, html: &str) {
+        let save_path = post.get_rel_save_path();
+        fs::create_dir_all(outputs_dir.join(&save_path).parent().unwrap()).unwrap();
+        fs::write(outputs_dir.join(&save_path), html).unwrap();
+        let outcome = PostOutcome {
+            http_status: 200,
+            final_url: post.url.clone(),
+            save_path,
+            content_type: Some("text/html".to_string()),
+            bytes: html.len() as u64,
+            assets: Default::default(),
+            metadata: Default::default(),
+        };
+        manifest.record_success(post, outputs_dir, &outcome);
+    }
+
+    #[test]
+    fn only_completed_posts_on_disk_are_skipped() {
+        let outputs = tempfile::tempdir().unwrap();
+        let mut manifest = Manifest::load(outputs.path()).unwrap();
+        let done = post("https://a.example/1", "Done");
+        let failed = post("https://a.example/2", "Failed");
+        let new = post("https://a.example/3", "New");
+        save(outputs.path(), &mut manifest, &done, "<html></html>");
+        manifest.record_failure(&failed, &anyhow::anyhow!("timed out"));
+
+        let none = ForcePolicy::default();
+        assert!(!manifest.needs_fetch(&done, outputs.path(), &none));
+        assert!(manifest.needs_fetch(&failed, outputs.path(), &none));
+        assert!(manifest.needs_fetch(&new, outputs.path(), &none));
+
+        for force in [
+            ForcePolicy { all: true, ..Default::default() },
+            ForcePolicy { urls: vec![done.url.clone()], ..Default::default() },
+            ForcePolicy { categories: vec!["notes".to_string()], ..Default::default() },
+        ] {
+            assert!(manifest.needs_fetch(&done, outputs.path(), &force));
+        }
+
+        // A completed post whose file was deleted is fetched again
+        fs::remove_file(outputs.path().join(done.get_rel_save_path())).unwrap();
+        assert!(manifest.needs_fetch(&done, outputs.path(), &none));
+    }
+
+    #[test]
+    fn failures_keep_the_earlier_good_copy() {
+        let outputs = tempfile::tempdir().unwrap();
+        let mut manifest = Manifest::load(outputs.path()).unwrap();
+        let post = post("https://a.example/1", "Post");
+        save(outputs.path(), &mut manifest, &post, "<html>good</html>");
+        let good = manifest.get(&post).unwrap().clone();
+
+        manifest.record_failure(&post, &anyhow::anyhow!("connection reset"));
+        let entry = manifest.get(&post).unwrap();
+        assert_eq!(entry.status, FetchStatus::Failed);
+        assert_eq!(entry.save_path, good.save_path);
+        assert_eq!((&entry.content_hash, &entry.content_type), (&good.content_hash, &good.content_type));
+        assert_eq!(entry.error.as_deref(), Some("connection reset"));
+
+        manifest.record_skipped(&post, "disallowed by robots.txt");
+        assert_eq!(manifest.get(&post).unwrap().status, FetchStatus::Skipped);
+        assert_eq!(manifest.get(&post).unwrap().content_hash, good.content_hash);
+    }
+
+    #[test]
+    fn saved_manifests_load_back_and_corrupt_ones_are_errors() {
+        let outputs = tempfile::tempdir().unwrap();
+        // A missing manifest is an empty one
+        let mut manifest = Manifest::load(outputs.path()).unwrap();
+        assert_eq!(manifest.entries().count(), 0);
+
+        let post = post("https://a.example/1", "Post");
+        save(outputs.path(), &mut manifest, &post, "<html></html>");
+        manifest.save().unwrap();
+        let loaded = Manifest::load(outputs.path()).unwrap();
+        assert_eq!(loaded.get(&post).unwrap().status, FetchStatus::Completed);
+        assert!(!loaded.needs_fetch(&post, outputs.path(), &ForcePolicy::default()));
+
+        fs::write(outputs.path().join(STATE_DIR).join(MANIFEST_FILE), "{\"entries\": {").unwrap();
+        let error = Manifest::load(outputs.path()).unwrap_err();
+        assert!(format!("{:#}", error).contains("Corrupted manifest"));
+    }
+}
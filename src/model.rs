@@ -31,6 +31,11 @@ impl Post {
 
     /// Returns the output html file path (without outputs/ prefix)
     pub fn get_rel_save_path(&self) -> String {
+        self.get_rel_save_path_with_ext("html")
+    }
+
+    /// Returns the output file path for a non-HTML post with the given extension
+    pub fn get_rel_save_path_with_ext(&self, extension: &str) -> String {
         let mut parts = vec![self.category.clone()];
         if !self.csv_subdir.is_empty() {
             parts.extend(self.csv_subdir.split('/').map(|s| s.to_owned()));
@@ -38,7 +43,7 @@ impl Post {
         if !self.csv_filename.is_empty() {
             parts.push(self.csv_filename.clone());
         }
-        parts.push(format!("{}.html", &self.safe_title));
+        parts.push(format!("{}.{}", &self.safe_title, extension));
         parts.join("/")
     }
 }
@@ -29,19 +29,17 @@ pub fn parse_posts(data_dir: &Path) -> Vec<Post> {
 
         // Read csv
         let mut rdr = csv::Reader::from_path(csv_path).unwrap();
-        for result in rdr.records() {
-            if let Ok(record) = result {
-                let url = record.get(0).unwrap_or("").to_string();
-                let title = record.get(1).unwrap_or("").to_string();
-                if !url.is_empty() && !title.is_empty() {
-                    posts.push(Post::new(
-                        url,
-                        title,
-                        category.clone(),
-                        csv_subdir.clone(),
-                        csv_filename.clone(),
-                    ));
-                }
+        for record in rdr.records().flatten() {
+            let url = record.get(0).unwrap_or("").to_string();
+            let title = record.get(1).unwrap_or("").to_string();
+            if !url.is_empty() && !title.is_empty() {
+                posts.push(Post::new(
+                    url,
+                    title,
+                    category.clone(),
+                    csv_subdir.clone(),
+                    csv_filename.clone(),
+                ));
             }
         }
     }
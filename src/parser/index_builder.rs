@@ -1,3 +1,5 @@
+use crate::fetcher::content_type::type_label;
+use crate::manifest::{FetchStatus, Manifest};
 use crate::model::Post;
 use std::collections::HashMap;
 
@@ -53,7 +55,34 @@ pub fn build_index_tree(posts: &[Post]) -> TreeNode {
     root
 }
 
-pub fn write_index_html(tree: &TreeNode, outputs_dir: &std::path::Path) -> anyhow::Result<()> {
+/// Status badge for posts whose last fetch did not complete
+fn status_badge(post: &Post, manifest: &Manifest) -> String {
+    let Some(entry) = manifest.get(post) else {
+        return String::new();
+    };
+    let label = match entry.status {
+        FetchStatus::Completed => return String::new(),
+        FetchStatus::Failed => "Failed",
+        FetchStatus::Skipped => "Skipped",
+    };
+    let detail = match entry.http_status {
+        Some(code) => format!(" (HTTP {})", code),
+        None => String::new(),
+    };
+    format!(
+        "<span class=\"badge badge-{}\" title=\"{}\">{}{}</span>\n",
+        label.to_lowercase(),
+        entry.error.as_deref().unwrap_or("").replace('"', "&quot;"),
+        label,
+        detail
+    )
+}
+
+pub fn write_index_html(
+    tree: &TreeNode,
+    outputs_dir: &std::path::Path,
+    manifest: &Manifest,
+) -> anyhow::Result<()> {
     let mut html = String::new();
     html.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n");
     html.push_str("<meta charset=\"UTF-8\">\n");
@@ -76,6 +105,10 @@ pub fn write_index_html(tree: &TreeNode, outputs_dir: &std::path::Path) -> anyho
     html.push_str(".file-item a { color: #333; text-decoration: none; font-weight: 500; display: block; }\n");
     html.push_str(".file-item a:hover { color: #667eea; }\n");
     html.push_str(".file-meta { font-size: 0.9em; color: #666; margin-top: 8px; }\n");
+    html.push_str(".badge { display: inline-block; font-size: 0.8em; padding: 2px 8px; border-radius: 10px; margin-top: 8px; color: white; }\n");
+    html.push_str(".badge-failed { background: #e5534b; }\n");
+    html.push_str(".badge-skipped { background: #999; }\n");
+    html.push_str(".badge-type { background: #667eea; margin-right: 6px; }\n");
     html.push_str(".empty-message { color: #999; font-style: italic; text-align: center; padding: 20px; }\n");
     html.push_str("</style>\n");
     html.push_str("</head>\n<body>\n");
@@ -86,23 +119,35 @@ pub fn write_index_html(tree: &TreeNode, outputs_dir: &std::path::Path) -> anyho
     html.push_str("</div>\n");
     html.push_str("<div class=\"content\">\n");
 
-    fn render_node(node: &TreeNode, level: usize, html: &mut String) {
+    fn render_node(node: &TreeNode, level: usize, html: &mut String, outputs_dir: &std::path::Path, manifest: &Manifest) {
         for (name, child) in &node.children {
             if level == 0 {
-                html.push_str(&format!("<div class=\"category\">\n"));
+                html.push_str("<div class=\"category\">\n");
                 html.push_str(&format!("<h2>{}</h2>\n", name));
             } else {
-                html.push_str(&format!("<div class=\"subcategory\">\n"));
+                html.push_str("<div class=\"subcategory\">\n");
                 html.push_str(&format!("<h3>{}</h3>\n", name));
             }
 
             if !child.files.is_empty() {
                 html.push_str("<div class=\"file-list\">\n");
                 for file in &child.files {
-                    let file_path = file.get_rel_save_path();
+                    // Non-HTML posts are saved under their own extension
+                    let file_path = manifest
+                        .get(file)
+                        .map(|entry| entry.save_path.clone())
+                        .unwrap_or_else(|| file.get_rel_save_path());
+                    let type_badge = match file_path.rsplit_once('.') {
+                        Some((_, ext)) if ext != "html" => {
+                            format!("<span class=\"badge badge-type\">{}</span>\n", type_label(ext))
+                        }
+                        _ => String::new(),
+                    };
+                    // Fall back to the live page when there is no local copy
+                    let href = if outputs_dir.join(&file_path).exists() { file_path } else { file.url.clone() };
                     html.push_str(&format!(
-                        "<div class=\"file-item\">\n<a href=\"{}\">{}</a>\n<div class=\"file-meta\">{}</div>\n</div>\n",
-                        file_path, file.title, file.url
+                        "<div class=\"file-item\">\n<a href=\"{}\">{}</a>\n<div class=\"file-meta\">{}</div>\n{}{}</div>\n",
+                        href, file.title, file.url, type_badge, status_badge(file, manifest)
                     ));
                 }
                 html.push_str("</div>\n");
@@ -110,17 +155,13 @@ pub fn write_index_html(tree: &TreeNode, outputs_dir: &std::path::Path) -> anyho
                 html.push_str("<div class=\"empty-message\">No files in this category</div>\n");
             }
 
-            render_node(child, level + 1, html);
+            render_node(child, level + 1, html, outputs_dir, manifest);
 
-            if level == 0 {
-                html.push_str("</div>\n");
-            } else {
-                html.push_str("</div>\n");
-            }
+            html.push_str("</div>\n");
         }
     }
 
-    render_node(tree, 0, &mut html);
+    render_node(tree, 0, &mut html, outputs_dir, manifest);
 
     html.push_str("</div>\n");
     html.push_str("</div>\n");
@@ -0,0 +1,254 @@
+use crate::fetcher::events::{Events, ProgressEvent};
+use crate::fetcher::scheduler::HostScheduler;
+use crate::report::format_bytes;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::collections::{HashMap, VecDeque};
+use std::io::IsTerminal;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+
+/// Redraw interval of the live display
+const TTY_REFRESH: Duration = Duration::from_millis(250);
+/// Interval between progress lines when stderr is not a terminal
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// Window for the bytes/sec average
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+/// Active posts and hosts listed on the live display
+const MAX_LISTED: usize = 3;
+
+/// Counters and in-flight posts rebuilt from the event stream
+struct RunState {
+    total: usize,
+    completed: usize,
+    failed: usize,
+    skipped: usize,
+    /// Active posts by URL: title and start time
+    active: HashMap<String, (String, Instant)>,
+    /// Recent `(time, total bytes)` samples for the transfer rate
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RunState {
+    fn new() -> Self {
+        Self {
+            total: 0,
+            completed: 0,
+            failed: 0,
+            skipped: 0,
+            active: HashMap::new(),
+            samples: VecDeque::new(),
+        }
+    }
+
+    fn finished(&self) -> usize {
+        self.completed + self.failed + self.skipped
+    }
+
+    /// Record the byte counter and return the average bytes/sec over the rate window
+    fn sample_rate(&mut self, bytes: u64) -> f64 {
+        let now = Instant::now();
+        self.samples.push_back((now, bytes));
+        while self.samples.len() > 2 && now - self.samples[0].0 > RATE_WINDOW {
+            self.samples.pop_front();
+        }
+        let (since, start) = self.samples[0];
+        let secs = (now - since).as_secs_f64();
+        if secs > 0.0 { (bytes - start) as f64 / secs } else { 0.0 }
+    }
+
+    /// Active posts, longest-running first
+    fn active_posts(&self) -> Vec<(&str, Duration)> {
+        let mut posts: Vec<(&str, Duration)> = self
+            .active
+            .values()
+            .map(|(title, started)| (title.as_str(), started.elapsed()))
+            .collect();
+        posts.sort_by_key(|(_, elapsed)| std::cmp::Reverse(*elapsed));
+        posts
+    }
+
+    fn status_line(&self, rate: f64) -> String {
+        let mut line = format!("{} active, {}/s", self.active.len(), format_bytes(rate as u64));
+        if self.failed > 0 {
+            line.push_str(&format!(", {} failed", self.failed));
+        }
+        if self.skipped > 0 {
+            line.push_str(&format!(", {} skipped", self.skipped));
+        }
+        line
+    }
+}
+
+/// Print progress of a run to the console until `RunFinished` is emitted.
+///
+/// On a terminal this draws a live display on stderr: an overall bar with the transfer rate,
+/// the longest-running posts and the busiest hosts. Otherwise finished posts and problems
+/// are logged line by line, with a progress summary every few seconds.
+pub fn spawn_console(events: &Events, scheduler: Arc<HostScheduler>) -> JoinHandle<()> {
+    let rx = events.subscribe();
+    let events = events.clone();
+    if std::io::stderr().is_terminal() {
+        tokio::spawn(run_live(rx, events, scheduler))
+    } else {
+        tokio::spawn(run_log(rx, events))
+    }
+}
+
+/// Apply an event to the counters, returning a line worth printing and whether it is a problem
+fn apply(state: &mut RunState, event: ProgressEvent) -> Option<(String, bool)> {
+    match event {
+        ProgressEvent::RunStarted { total } => {
+            state.total = total;
+            None
+        }
+        ProgressEvent::PostStarted { url, title } => {
+            state.active.insert(url, (title, Instant::now()));
+            None
+        }
+        ProgressEvent::PostCompleted { url, title, .. } => {
+            state.active.remove(&url);
+            state.completed += 1;
+            Some((format!("Downloaded: {}", title), false))
+        }
+        ProgressEvent::PostFailed { url, title, error } => {
+            state.active.remove(&url);
+            state.failed += 1;
+            Some((format!("Error downloading {} <{}>: {}", title, url, error), true))
+        }
+        ProgressEvent::PostSkipped { url, title, reason } => {
+            state.active.remove(&url);
+            state.skipped += 1;
+            Some((format!("Skipped {}: {}", title, reason), false))
+        }
+        ProgressEvent::AssetFailed { url, error, .. } => {
+            Some((format!("Failed to download {}: {}", url, error), true))
+        }
+        ProgressEvent::Retrying { url, attempt, max_retries, delay, error } => Some((
+            format!("Retrying {} in {:?} (attempt {}/{}): {}", url, delay, attempt, max_retries, error),
+            true,
+        )),
+        ProgressEvent::Info { message } => Some((message, false)),
+        ProgressEvent::Warning { message } => Some((message, true)),
+        _ => None,
+    }
+}
+
+async fn run_live(
+    mut rx: tokio::sync::broadcast::Receiver<ProgressEvent>,
+    events: Events,
+    scheduler: Arc<HostScheduler>,
+) {
+    let multi = MultiProgress::with_draw_target(ProgressDrawTarget::stderr());
+    let overall = multi.add(ProgressBar::new(0));
+    overall.set_style(
+        ProgressStyle::with_template("{elapsed_precise} [{bar:40.cyan/blue}] {pos}/{len} posts  {msg}")
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    let active = multi.add(ProgressBar::new_spinner());
+    let hosts = multi.add(ProgressBar::new_spinner());
+    let mut state = RunState::new();
+    let mut refresh = tokio::time::interval(TTY_REFRESH);
+    loop {
+        tokio::select! {
+            event = rx.recv() => match event {
+                Ok(ProgressEvent::RunFinished) | Err(RecvError::Closed) => break,
+                Ok(event) => {
+                    if let Some((line, _)) = apply(&mut state, event) {
+                        let _ = multi.println(line);
+                    }
+                }
+                Err(RecvError::Lagged(_)) => {}
+            },
+            _ = refresh.tick() => {
+                let rate = state.sample_rate(events.bytes_received());
+                overall.set_length(state.total as u64);
+                overall.set_position(state.finished() as u64);
+                overall.set_message(state.status_line(rate));
+                let posts: Vec<String> = state
+                    .active_posts()
+                    .iter()
+                    .take(MAX_LISTED)
+                    .map(|(title, elapsed)| format!("{} ({}s)", title, elapsed.as_secs()))
+                    .collect();
+                active.set_message(format!("active: {}", posts.join(", ")));
+                let busy: Vec<String> = scheduler
+                    .snapshot()
+                    .iter()
+                    .take(MAX_LISTED)
+                    .map(|h| format!("{} {} running/{} queued", h.host, h.in_flight, h.waiting))
+                    .collect();
+                hosts.set_message(format!("hosts: {}", busy.join(", ")));
+            }
+        }
+    }
+    active.finish_and_clear();
+    hosts.finish_and_clear();
+    overall.finish_and_clear();
+}
+
+async fn run_log(mut rx: tokio::sync::broadcast::Receiver<ProgressEvent>, events: Events) {
+    let mut state = RunState::new();
+    let mut log = tokio::time::interval(LOG_INTERVAL);
+    // The first tick completes immediately
+    log.tick().await;
+    loop {
+        tokio::select! {
+            event = rx.recv() => match event {
+                Ok(ProgressEvent::RunFinished) | Err(RecvError::Closed) => break,
+                Ok(event) => match apply(&mut state, event) {
+                    Some((line, true)) => eprintln!("{}", line),
+                    Some((line, false)) => println!("{}", line),
+                    None => {}
+                },
+                Err(RecvError::Lagged(missed)) => eprintln!("Progress output fell behind, {} events dropped", missed),
+            },
+            _ = log.tick() => {
+                let rate = state.sample_rate(events.bytes_received());
+                println!("Progress: {}/{} posts, {}", state.finished(), state.total, state.status_line(rate));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post_event(url: &str) -> ProgressEvent {
+        ProgressEvent::PostStarted { url: url.to_string(), title: url.to_string() }
+    }
+
+    #[test]
+    fn events_update_the_counters_and_problems_stand_out() {
+        let mut state = RunState::new();
+        assert_eq!(apply(&mut state, ProgressEvent::RunStarted { total: 3 }), None);
+        for url in ["a", "b", "c"] {
+            assert_eq!(apply(&mut state, post_event(url)), None);
+        }
+        assert_eq!(state.active.len(), 3);
+
+        let completed = ProgressEvent::PostCompleted {
+            url: "a".to_string(),
+            title: "Post A".to_string(),
+            http_status: 200,
+            save_path: "notes/Post A.html".to_string(),
+            bytes: 10,
+        };
+        assert_eq!(apply(&mut state, completed), Some(("Downloaded: Post A".to_string(), false)));
+        let failed = ProgressEvent::PostFailed {
+            url: "b".to_string(),
+            title: "Post B".to_string(),
+            error: "HTTP 500".to_string(),
+        };
+        assert_eq!(apply(&mut state, failed), Some(("Error downloading Post B <b>: HTTP 500".to_string(), true)));
+        let warning = ProgressEvent::Warning { message: "robots.txt unreachable".to_string() };
+        assert_eq!(apply(&mut state, warning), Some(("robots.txt unreachable".to_string(), true)));
+
+        assert_eq!((state.total, state.finished()), (3, 2));
+        assert_eq!(state.active_posts().iter().map(|(title, _)| *title).collect::<Vec<_>>(), ["c"]);
+        assert_eq!(state.status_line(2048.0), "1 active, 2.0 KiB/s, 1 failed");
+    }
+}
@@ -0,0 +1,352 @@
+use crate::fetcher::downloader::{AssetCounts, PostOutcome};
+use crate::fetcher::retry::FetchError;
+use crate::manifest::{FetchStatus, ManifestEntry, STATE_DIR};
+use crate::model::Post;
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const REPORT_JSON: &str = "report.json";
+const REPORT_TEXT: &str = "report.txt";
+
+/// What happened to a post in a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportStatus {
+    Completed,
+    Failed,
+    Skipped,
+    /// Not fetched because an earlier run already completed it
+    UpToDate,
+    /// Not selected for this run, e.g. a new post left out by `--retry-failed`
+    NotAttempted,
+}
+
+impl From<FetchStatus> for ReportStatus {
+    fn from(status: FetchStatus) -> Self {
+        match status {
+            FetchStatus::Completed => ReportStatus::Completed,
+            FetchStatus::Failed => ReportStatus::Failed,
+            FetchStatus::Skipped => ReportStatus::Skipped,
+        }
+    }
+}
+
+impl ReportStatus {
+    fn label(self) -> &'static str {
+        match self {
+            ReportStatus::Completed => "completed",
+            ReportStatus::Failed => "FAILED",
+            ReportStatus::Skipped => "skipped",
+            ReportStatus::UpToDate => "up-to-date",
+            ReportStatus::NotAttempted => "-",
+        }
+    }
+}
+
+/// Outcome of one post
+#[derive(Debug, Clone, Serialize)]
+pub struct PostReport {
+    pub title: String,
+    pub url: String,
+    pub category: String,
+    pub status: ReportStatus,
+    pub http_status: Option<u16>,
+    /// URL after redirects
+    pub final_url: Option<String>,
+    /// Saved file relative to the outputs directory
+    pub save_path: Option<String>,
+    /// Bytes downloaded in this run for the post and its assets
+    pub bytes: u64,
+    pub assets: AssetCounts,
+    /// Facts collected by the `metadata` step and custom processors
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    pub duration_ms: u64,
+    /// Error followed by its causes, outermost first
+    pub errors: Vec<String>,
+}
+
+impl PostReport {
+    /// Report for a post fetched in this run
+    pub fn new(post: &Post, status: FetchStatus, result: &Result<PostOutcome>, elapsed: Duration) -> Self {
+        let mut report = Self::empty(post, status.into());
+        report.duration_ms = elapsed.as_millis() as u64;
+        match result {
+            Ok(outcome) => {
+                report.http_status = Some(outcome.http_status);
+                report.final_url = Some(outcome.final_url.clone());
+                report.save_path = Some(outcome.save_path.clone());
+                report.bytes = outcome.bytes;
+                report.assets = outcome.assets;
+                report.metadata = outcome.metadata.clone();
+            }
+            Err(e) => {
+                report.http_status = e
+                    .downcast_ref::<FetchError>()
+                    .and_then(|e| e.status())
+                    .map(|s| s.as_u16());
+                report.errors = e.chain().map(|cause| cause.to_string()).collect();
+            }
+        }
+        report
+    }
+
+    /// Report for a post not fetched in this run, from its manifest entry
+    pub fn not_fetched(post: &Post, entry: Option<&ManifestEntry>) -> Self {
+        match entry {
+            Some(entry) if entry.status == FetchStatus::Completed => {
+                let mut report = Self::empty(post, ReportStatus::UpToDate);
+                report.http_status = entry.http_status;
+                report.save_path = Some(entry.save_path.clone());
+                report
+            }
+            _ => Self::empty(post, ReportStatus::NotAttempted),
+        }
+    }
+
+    fn empty(post: &Post, status: ReportStatus) -> Self {
+        Self {
+            title: post.title.clone(),
+            url: post.url.clone(),
+            category: post.category.clone(),
+            status,
+            http_status: None,
+            final_url: None,
+            save_path: None,
+            bytes: 0,
+            assets: AssetCounts::default(),
+            metadata: BTreeMap::new(),
+            duration_ms: 0,
+            errors: Vec::new(),
+        }
+    }
+}
+
+/// Totals per status
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ReportTotals {
+    pub completed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub up_to_date: usize,
+    pub not_attempted: usize,
+    pub bytes: u64,
+}
+
+/// Structured record of a whole run, written to `<outputs>/.hyfetcher/report.json` and `report.txt`
+#[derive(Debug, Clone, Serialize)]
+pub struct RunReport {
+    pub started_at: DateTime<Local>,
+    pub finished_at: DateTime<Local>,
+    pub duration_ms: u64,
+    /// Stopped early by Ctrl-C or `Shutdown::trigger`
+    pub interrupted: bool,
+    pub totals: ReportTotals,
+    pub posts: Vec<PostReport>,
+}
+
+impl RunReport {
+    pub fn new() -> Self {
+        let now = Local::now();
+        Self {
+            started_at: now,
+            finished_at: now,
+            duration_ms: 0,
+            interrupted: false,
+            totals: ReportTotals::default(),
+            posts: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, post: PostReport) {
+        match post.status {
+            ReportStatus::Completed => self.totals.completed += 1,
+            ReportStatus::Failed => self.totals.failed += 1,
+            ReportStatus::Skipped => self.totals.skipped += 1,
+            ReportStatus::UpToDate => self.totals.up_to_date += 1,
+            ReportStatus::NotAttempted => self.totals.not_attempted += 1,
+        }
+        self.totals.bytes += post.bytes;
+        self.posts.push(post);
+    }
+
+    /// Stamp the end time
+    pub fn finish(&mut self) {
+        self.finished_at = Local::now();
+        self.duration_ms = (self.finished_at - self.started_at).num_milliseconds().max(0) as u64;
+    }
+
+    /// Posts that did not complete, for a quick look at the end of a run
+    pub fn problems(&self) -> impl Iterator<Item = &PostReport> {
+        self.posts
+            .iter()
+            .filter(|p| matches!(p.status, ReportStatus::Failed | ReportStatus::Skipped))
+    }
+
+    /// Fixed-width table of the given posts
+    pub fn table<'a>(posts: impl IntoIterator<Item = &'a PostReport>) -> String {
+        let mut out = format!(
+            "{:<10} {:>4} {:>8} {:>9} {:>6} {:>6} {:>6}  {}\n",
+            "STATUS", "HTTP", "TIME", "SIZE", "IMAGES", "VIDEOS", "FAILED", "POST"
+        );
+        for post in posts {
+            let _ = writeln!(
+                out,
+                "{:<10} {:>4} {:>7.1}s {:>9} {:>6} {:>6} {:>6}  {} <{}>",
+                post.status.label(),
+                post.http_status.map(|s| s.to_string()).unwrap_or_else(|| "-".to_string()),
+                post.duration_ms as f64 / 1000.0,
+                format_bytes(post.bytes),
+                post.assets.images,
+                post.assets.videos,
+                post.assets.failed,
+                post.title,
+                post.url,
+            );
+            if let Some(error) = post.errors.first() {
+                let _ = writeln!(out, "{:>10} {}", "", error);
+                for cause in &post.errors[1..] {
+                    let _ = writeln!(out, "{:>10}   caused by: {}", "", cause);
+                }
+            }
+        }
+        out
+    }
+
+    /// One-line totals
+    pub fn summary(&self) -> String {
+        format!(
+            "Completed: {}, failed: {}, skipped: {}, up to date: {}, downloaded {} in {:.1}s",
+            self.totals.completed,
+            self.totals.failed,
+            self.totals.skipped,
+            self.totals.up_to_date,
+            format_bytes(self.totals.bytes),
+            self.duration_ms as f64 / 1000.0
+        )
+    }
+
+    /// Where `save` writes the JSON report
+    pub fn json_path(outputs_dir: &Path) -> PathBuf {
+        outputs_dir.join(STATE_DIR).join(REPORT_JSON)
+    }
+
+    /// Write the JSON report and the full human-readable table, returning the JSON path
+    pub fn save(&self, outputs_dir: &Path) -> Result<PathBuf> {
+        let dir = outputs_dir.join(STATE_DIR);
+        fs::create_dir_all(&dir)?;
+        let json_path = Self::json_path(outputs_dir);
+        fs::write(&json_path, serde_json::to_string_pretty(self)?)?;
+        let text = format!("{}\n\n{}", self.summary(), Self::table(&self.posts));
+        fs::write(dir.join(REPORT_TEXT), text)?;
+        Ok(json_path)
+    }
+}
+
+impl Default for RunReport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Byte count with a binary unit, e.g. `1.5 MiB`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    // Values that would round up to 1024.0 move to the next unit too
+    while value >= 1023.95 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::post;
+
+    fn entry(status: FetchStatus) -> ManifestEntry {
+        ManifestEntry {
+            url: "https://a.example/1".to_string(),
+            final_url: None,
+            save_path: "notes/Post.html".to_string(),
+            status,
+            http_status: Some(200),
+            content_type: Some("text/html".to_string()),
+            timestamp: Local::now(),
+            content_hash: None,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn posts_not_fetched_are_up_to_date_only_if_completed_before() {
+        let post = post("https://a.example/1", "Post");
+        let report = PostReport::not_fetched(&post, Some(&entry(FetchStatus::Completed)));
+        assert_eq!(report.status, ReportStatus::UpToDate);
+        assert_eq!((report.http_status, report.save_path.as_deref()), (Some(200), Some("notes/Post.html")));
+        for earlier in [Some(entry(FetchStatus::Failed)), Some(entry(FetchStatus::Skipped)), None] {
+            let report = PostReport::not_fetched(&post, earlier.as_ref());
+            assert_eq!((report.status, report.save_path), (ReportStatus::NotAttempted, None));
+        }
+    }
+
+    #[test]
+    fn totals_count_every_status_and_the_bytes() {
+        let mut report = RunReport::new();
+        let statuses = [
+            ReportStatus::Completed,
+            ReportStatus::Completed,
+            ReportStatus::Failed,
+            ReportStatus::Skipped,
+            ReportStatus::UpToDate,
+            ReportStatus::NotAttempted,
+        ];
+        for status in statuses {
+            let mut post = PostReport::empty(&post("https://a.example/1", "Post"), status);
+            post.bytes = 1000;
+            report.push(post);
+        }
+        let totals = report.totals;
+        assert_eq!((totals.completed, totals.failed, totals.skipped), (2, 1, 1));
+        assert_eq!((totals.up_to_date, totals.not_attempted, totals.bytes), (1, 1, 6000));
+        assert_eq!(report.problems().count(), 2);
+    }
+
+    #[test]
+    fn failures_are_listed_with_their_causes() {
+        let error = anyhow::anyhow!("connection reset").context("Failed to fetch https://a.example/1");
+        let post = post("https://a.example/1", "Post");
+        let failed = PostReport::new(&post, FetchStatus::Failed, &Err(error), Duration::ZERO);
+        let table = RunReport::table([&failed]);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].starts_with("FAILED") && lines[1].ends_with("Post <https://a.example/1>"));
+        assert_eq!(lines[2].trim(), "Failed to fetch https://a.example/1");
+        assert_eq!(lines[3].trim(), "caused by: connection reset");
+    }
+
+    #[test]
+    fn bytes_use_binary_units() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.0 KiB");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(1023 * 1024), "1023.0 KiB");
+        assert_eq!(format_bytes(1024 * 1024 - 1), "1.0 MiB");
+        assert_eq!(format_bytes(1024 * 1024), "1.0 MiB");
+        assert_eq!(format_bytes(5 << 30), "5.0 GiB");
+        assert_eq!(format_bytes(2048 << 40), "2048.0 TiB");
+    }
+}
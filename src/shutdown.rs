@@ -0,0 +1,124 @@
+use crate::fetcher::events::Events;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Exit code of a run that was forced to stop (128 + SIGINT)
+pub const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// Request to stop starting new work, shared by everything taking part in a run
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(false);
+        Self { sender: Arc::new(sender) }
+    }
+
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Resolves once a shutdown has been requested
+    pub async fn triggered(&self) {
+        let mut receiver = self.sender.subscribe();
+        // The sender lives as long as `self`, so this cannot fail
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+
+    /// Trigger on the first Ctrl-C or SIGTERM and exit the process on the second
+    pub fn listen_for_signals(&self, events: Events, grace_period: Duration) {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            if wait_for_signal().await.is_err() {
+                return;
+            }
+            events.warn(format!(
+                "Interrupted: finishing downloads in progress (up to {}s), press Ctrl-C again to exit immediately",
+                grace_period.as_secs()
+            ));
+            shutdown.trigger();
+            if wait_for_signal().await.is_ok() {
+                eprintln!("Interrupted again, exiting immediately");
+                std::process::exit(INTERRUPTED_EXIT_CODE);
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::Archive;
+    use crate::config::Settings;
+    use crate::testing::{filler, post, Route, TestServer};
+
+    #[tokio::test]
+    async fn triggered_resolves_for_every_clone() {
+        let shutdown = Shutdown::new();
+        let waiting = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.triggered().await }
+        });
+        assert!(!shutdown.is_triggered());
+        shutdown.trigger();
+        shutdown.trigger();
+        tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap();
+        // Already triggered: resolves at once
+        tokio::time::timeout(Duration::from_secs(1), shutdown.triggered()).await.unwrap();
+        assert!(shutdown.clone().is_triggered());
+    }
+
+    #[tokio::test]
+    async fn downloads_past_the_grace_period_are_abandoned() {
+        let page = format!("<html><body><p>{}</p></body></html>", filler());
+        let server = TestServer::start(vec![Route::ok("/slow.html", "text/html", page).delayed(5000)]).await;
+        let outputs = tempfile::tempdir().unwrap();
+        let archive = Archive::builder()
+            .settings(Settings { grace_period: Some(0), ..Default::default() })
+            .concurrency(1)
+            .posts([post(&server.url("/slow.html"), "Slow"), post(&server.url("/next.html"), "Next")])
+            .outputs_dir(outputs.path())
+            .build()
+            .unwrap();
+        let shutdown = archive.shutdown().clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            shutdown.trigger();
+        });
+        let report = tokio::time::timeout(Duration::from_secs(3), archive.run()).await.unwrap().unwrap();
+        assert!(report.interrupted);
+        assert_eq!(report.posts.len(), 2);
+        assert!(!outputs.path().join("notes/Slow.html").exists());
+        // The second post was never started
+        assert_eq!(server.hits("/next.html"), 0);
+    }
+}
@@ -0,0 +1,133 @@
+//! Helpers shared by the unit tests: a local HTTP server and a renderer serving fixed pages
+
+use crate::fetcher::render::{RenderRequest, RenderedPage, Renderer};
+use crate::model::Post;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// A response of the test server
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub path: String,
+    pub status: u16,
+    pub content_type: String,
+    pub body: Vec<u8>,
+    /// Wait before answering, e.g. to make downloads finish out of order
+    pub delay: Duration,
+}
+
+impl Route {
+    pub fn ok(path: &str, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            path: path.to_string(),
+            status: 200,
+            content_type: content_type.to_string(),
+            body: body.into(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn delayed(mut self, millis: u64) -> Self {
+        self.delay = Duration::from_millis(millis);
+        self
+    }
+}
+
+/// HTTP server on a local port answering from fixed routes (404 for the rest) and counting requests
+#[derive(Debug, Clone)]
+pub struct TestServer {
+    base: String,
+    hits: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl TestServer {
+    pub async fn start(routes: Vec<Route>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = Self {
+            base: format!("http://{}", listener.local_addr().unwrap()),
+            hits: Arc::default(),
+        };
+        let routes = Arc::new(routes);
+        let hits = server.hits.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let routes = routes.clone();
+                let hits = hits.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request);
+                    let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+                    *hits.lock().unwrap().entry(path.clone()).or_default() += 1;
+                    let route = routes.iter().find(|route| route.path == path).cloned().unwrap_or(Route {
+                        status: 404,
+                        ..Route::ok(&path, "text/html", "<html><body>Not found</body></html>")
+                    });
+                    tokio::time::sleep(route.delay).await;
+                    let head = format!(
+                        "HTTP/1.1 {} X\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        route.status,
+                        route.content_type,
+                        route.body.len()
+                    );
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(&route.body).await;
+                });
+            }
+        });
+        server
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base, path)
+    }
+
+    /// Requests received for `path`
+    pub fn hits(&self, path: &str) -> usize {
+        self.hits.lock().unwrap().get(path).copied().unwrap_or(0)
+    }
+}
+
+/// Renders fixed HTML by URL without going over the network; other URLs fail
+#[derive(Debug, Default)]
+pub struct Pages(pub HashMap<String, String>);
+
+impl Pages {
+    pub fn with(mut self, url: &str, html: &str) -> Self {
+        self.0.insert(url.to_string(), html.to_string());
+        self
+    }
+}
+
+impl Renderer for Pages {
+    fn name(&self) -> &'static str {
+        "pages"
+    }
+
+    fn render<'a>(&'a self, request: RenderRequest<'a>) -> BoxFuture<'a, anyhow::Result<RenderedPage>> {
+        Box::pin(async move {
+            let html = self.0.get(request.url).ok_or_else(|| anyhow::anyhow!("no page {}", request.url))?;
+            Ok(RenderedPage { status: 200, final_url: request.url.to_string(), html: html.clone() })
+        })
+    }
+}
+
+/// A post in the `notes` category, saved as `notes/<title>.html`
+pub fn post(url: &str, title: &str) -> Post {
+    Post::new(url.to_string(), title.to_string(), "notes".to_string(), String::new(), String::new())
+}
+
+/// Enough text for a page not to count as an empty body
+pub fn filler() -> String {
+    "Some article text. ".repeat(40)
+}
@@ -3,9 +3,8 @@ use std::path::Path;
 use std::fs;
 use std::env;
 use anyhow::Result;
-use reqwest::Client;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use crate::fetcher::http::HttpSession;
+use crate::fetcher::partial::PartialFile;
 
 #[derive(Debug)]
 pub enum Platform {
@@ -71,24 +70,39 @@ pub fn get_tools_dir() -> Result<std::path::PathBuf> {
     Ok(tools_dir)
 }
 
-/// Download a file
-pub async fn download_file(url: &str, path: &Path) -> Result<()> {
-    let client = Client::new();
-    let response = client.get(url).send().await?;
-    
-    if !response.status().is_success() {
-        return Err(anyhow::anyhow!("Download failed: {}", response.status()));
+/// Relative URL from a directory to a file, both given relative to the same root
+///
+/// ```
+/// use hyfetcher::utils::relative_url;
+/// use std::path::Path;
+///
+/// let page_dir = Path::new("outputs/Notes/2024");
+/// assert_eq!(relative_url(page_dir, Path::new("outputs/Notes/2024/images/a.png")), "images/a.png");
+/// assert_eq!(relative_url(page_dir, Path::new("outputs/assets/3f/3f2a.png")), "../../assets/3f/3f2a.png");
+/// ```
+pub fn relative_url(from_dir: &Path, to: &Path) -> String {
+    let from: Vec<_> = from_dir.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut parts = vec!["..".to_string(); from.len() - common];
+    parts.extend(to[common..].iter().map(|c| c.as_os_str().to_string_lossy().into_owned()));
+    parts.join("/")
+}
+
+/// Download a file through the shared HTTP session
+pub async fn download_file(http: &HttpSession, url: &str, path: &Path) -> Result<()> {
+    let mut response = http.fetch(url).await?;
+    let mut file = PartialFile::create(path).await?;
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk).await?;
     }
-    
-    let mut file = File::create(path).await?;
-    let bytes = response.bytes().await?;
-    file.write_all(&bytes).await?;
-    
+    file.commit().await?;
+
     Ok(())
 }
 
 /// Install yt-dlp
-pub async fn install_yt_dlp() -> Result<()> {
+pub async fn install_yt_dlp(http: &HttpSession) -> Result<()> {
     let platform = Platform::current();
     let tools_dir = get_tools_dir()?;
     
@@ -99,7 +113,7 @@ pub async fn install_yt_dlp() -> Result<()> {
             let url = "https://github.com/yt-dlp/yt-dlp/releases/latest/download/yt-dlp.exe";
             
             println!("Downloading yt-dlp...");
-            download_file(url, &yt_dlp_path).await?;
+            download_file(http, url, &yt_dlp_path).await?;
             
             println!("yt-dlp installation completed: {}", yt_dlp_path.display());
         },
@@ -107,7 +121,7 @@ pub async fn install_yt_dlp() -> Result<()> {
             // macOS: Install using pip
             println!("Installing yt-dlp using pip...");
             let status = Command::new("pip3")
-                .args(&["install", "--user", "yt-dlp"])
+                .args(["install", "--user", "yt-dlp"])
                 .status()?;
             
             if !status.success() {
@@ -122,7 +136,7 @@ pub async fn install_yt_dlp() -> Result<()> {
             let url = "https://github.com/yt-dlp/yt-dlp/releases/latest/download/yt-dlp";
             
             println!("Downloading yt-dlp...");
-            download_file(url, &yt_dlp_path).await?;
+            download_file(http, url, &yt_dlp_path).await?;
             
             // Set execution permissions
             use std::os::unix::fs::PermissionsExt;
@@ -138,7 +152,7 @@ pub async fn install_yt_dlp() -> Result<()> {
 }
 
 /// Check and install all required tools
-pub async fn check_and_install_tools() -> Result<()> {
+pub async fn check_and_install_tools(http: &HttpSession) -> Result<()> {
     println!("Checking required external tools...");
     
     for tool in REQUIRED_TOOLS {
@@ -146,7 +160,7 @@ pub async fn check_and_install_tools() -> Result<()> {
             println!("{} not found, installing...", tool.name);
             
             match tool.name {
-                "yt-dlp" => install_yt_dlp().await?,
+                "yt-dlp" => install_yt_dlp(http).await?,
                 _ => {
                     println!("Please manually install {}: {}", tool.name, tool.install_instructions);
                     return Err(anyhow::anyhow!("Manual installation required: {}", tool.name));